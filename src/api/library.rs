@@ -0,0 +1,460 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+
+use super::IBroadcastError;
+
+/// A user's library decoded from the `getlibrary` payload.
+///
+/// iBroadcast sends every collection in a compact columnar form: each table is
+/// an object whose `map` entry gives the column index of every field, and
+/// whose remaining entries are rows keyed by the item id.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Library {
+    pub tracks: BTreeMap<u64, Track>,
+    pub albums: BTreeMap<u64, Album>,
+    pub artists: BTreeMap<u64, Artist>,
+    pub playlists: BTreeMap<u64, Playlist>,
+    pub tags: BTreeMap<u64, Tag>,
+    pub trash: Trash,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Track {
+    pub id: u64,
+    pub title: String,
+    pub track_number: Option<u32>,
+    pub year: Option<u32>,
+    pub genre: Option<String>,
+    pub length: Option<u32>,
+    pub album_id: Option<u64>,
+    pub artist_id: Option<u64>,
+    pub artwork_id: Option<u64>,
+    pub plays: u32,
+    pub rating: u32,
+    pub size: Option<u64>,
+    pub file: Option<String>,
+    pub path: Option<String>,
+    pub uploaded_on: Option<String>,
+    pub trashed: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Album {
+    pub id: u64,
+    pub name: String,
+    pub artist_id: Option<u64>,
+    pub tracks: Vec<u64>,
+    pub disc: Option<u32>,
+    pub year: Option<u32>,
+    pub rating: u32,
+    pub trashed: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Artist {
+    pub id: u64,
+    pub name: String,
+    pub tracks: Vec<u64>,
+    pub artwork_id: Option<u64>,
+    pub rating: u32,
+    pub trashed: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Playlist {
+    pub id: u64,
+    pub name: String,
+    pub description: Option<String>,
+    pub tracks: Vec<u64>,
+    pub artwork_id: Option<u64>,
+    pub public_id: Option<String>,
+    pub kind: Option<String>,
+    pub system_created: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Tag {
+    pub id: u64,
+    pub name: String,
+    pub tracks: Vec<u64>,
+    pub archived: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Trash {
+    pub tracks: Vec<u64>,
+}
+
+impl Library {
+    /// Decodes the `library` object of a `getlibrary` response.
+    ///
+    /// Older payloads carry playlists next to the library instead of inside
+    /// it, so `playlists` is used when the library has no table of its own.
+    pub fn from_value(library: &Value, playlists: &Value) -> Result<Self, IBroadcastError> {
+        let library = library
+            .as_object()
+            .ok_or_else(|| IBroadcastError::InvalidResponse("library is not an object".to_string()))?;
+
+        let playlists = match library.get("playlists") {
+            Some(table) => Some(table),
+            None if !playlists.is_null() => Some(playlists),
+            None => None,
+        };
+
+        Ok(Self {
+            tracks: decode_table(library.get("tracks"), "tracks", decode_track)?,
+            albums: decode_table(library.get("albums"), "albums", decode_album)?,
+            artists: decode_table(library.get("artists"), "artists", decode_artist)?,
+            playlists: decode_table(playlists, "playlists", decode_playlist)?,
+            tags: decode_tags(library.get("tags"))?,
+            trash: decode_trash(library.get("trash"))?,
+        })
+    }
+
+    /// Returns the tracks of an album in disc order.
+    pub fn album_tracks(&self, album_id: u64) -> Vec<&Track> {
+        let mut tracks: Vec<&Track> = self
+            .tracks
+            .values()
+            .filter(|track| track.album_id == Some(album_id) && !track.trashed)
+            .collect();
+        tracks.sort_by_key(|track| track.track_number.unwrap_or(u32::MAX));
+        tracks
+    }
+
+    /// Returns the tracks of a playlist in playlist order, skipping unknown ids.
+    pub fn playlist_tracks(&self, playlist_id: u64) -> Vec<&Track> {
+        self.playlists
+            .get(&playlist_id)
+            .map(|playlist| playlist.tracks.iter().filter_map(|id| self.tracks.get(id)).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn artist_name(&self, artist_id: Option<u64>) -> Option<&str> {
+        artist_id
+            .and_then(|id| self.artists.get(&id))
+            .map(|artist| artist.name.as_str())
+    }
+
+    pub fn album_name(&self, album_id: Option<u64>) -> Option<&str> {
+        album_id
+            .and_then(|id| self.albums.get(&id))
+            .map(|album| album.name.as_str())
+    }
+}
+
+/// Column lookup for one columnar table, built from its `map` entry.
+struct Columns {
+    index: HashMap<String, usize>,
+}
+
+impl Columns {
+    fn from_table(name: &str, table: &Map<String, Value>) -> Result<Self, IBroadcastError> {
+        let map = table
+            .get("map")
+            .and_then(Value::as_object)
+            .ok_or_else(|| IBroadcastError::InvalidResponse(format!("{} table has no column map", name)))?;
+
+        let index = map
+            .iter()
+            .filter_map(|(column, position)| Some((column.clone(), position.as_u64()? as usize)))
+            .collect();
+
+        Ok(Self { index })
+    }
+
+    fn value<'a>(&self, row: &'a [Value], column: &str) -> Option<&'a Value> {
+        self.index
+            .get(column)
+            .and_then(|&position| row.get(position))
+            .filter(|value| !value.is_null())
+    }
+
+    fn string(&self, row: &[Value], column: &str) -> Option<String> {
+        match self.value(row, column)? {
+            Value::String(s) if s.is_empty() => None,
+            Value::String(s) => Some(s.clone()),
+            Value::Number(n) => Some(n.to_string()),
+            _ => None,
+        }
+    }
+
+    fn u64(&self, row: &[Value], column: &str) -> Option<u64> {
+        value_to_u64(self.value(row, column)?)
+    }
+
+    /// Ids of zero mean "none" in iBroadcast payloads.
+    fn id(&self, row: &[Value], column: &str) -> Option<u64> {
+        self.u64(row, column).filter(|&id| id != 0)
+    }
+
+    fn u32(&self, row: &[Value], column: &str) -> Option<u32> {
+        self.u64(row, column).and_then(|n| u32::try_from(n).ok())
+    }
+
+    fn bool(&self, row: &[Value], column: &str) -> bool {
+        self.value(row, column).is_some_and(value_to_bool)
+    }
+
+    fn ids(&self, row: &[Value], column: &str) -> Vec<u64> {
+        self.value(row, column).map(value_to_ids).unwrap_or_default()
+    }
+}
+
+fn value_to_u64(value: &Value) -> Option<u64> {
+    match value {
+        Value::Number(n) => n.as_u64().or_else(|| n.as_f64().filter(|f| *f >= 0.0).map(|f| f as u64)),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn value_to_bool(value: &Value) -> bool {
+    match value {
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_i64().is_some_and(|n| n != 0),
+        Value::String(s) => matches!(s.as_str(), "1" | "true" | "t"),
+        _ => false,
+    }
+}
+
+fn value_to_ids(value: &Value) -> Vec<u64> {
+    value
+        .as_array()
+        .map(|ids| ids.iter().filter_map(value_to_u64).collect())
+        .unwrap_or_default()
+}
+
+fn decode_table<T>(
+    table: Option<&Value>,
+    name: &str,
+    decode: fn(u64, &Columns, &[Value]) -> T,
+) -> Result<BTreeMap<u64, T>, IBroadcastError> {
+    let table = match table {
+        Some(Value::Object(table)) => table,
+        Some(Value::Null) | None => return Ok(BTreeMap::new()),
+        Some(_) => {
+            return Err(IBroadcastError::InvalidResponse(format!("{} table is not an object", name)));
+        }
+    };
+
+    let columns = Columns::from_table(name, table)?;
+    let mut items = BTreeMap::new();
+    for (key, row) in table.iter().filter(|(key, _)| key.as_str() != "map") {
+        let (Ok(id), Some(row)) = (key.parse::<u64>(), row.as_array()) else {
+            log::warn!("Skipping malformed {} row {:?}", name, key);
+            continue;
+        };
+        items.insert(id, decode(id, &columns, row));
+    }
+    Ok(items)
+}
+
+fn decode_track(id: u64, columns: &Columns, row: &[Value]) -> Track {
+    Track {
+        id,
+        title: columns.string(row, "title").unwrap_or_default(),
+        track_number: columns.u32(row, "track"),
+        year: columns.u32(row, "year").filter(|&year| year != 0),
+        genre: columns.string(row, "genre"),
+        length: columns.u32(row, "length"),
+        album_id: columns.id(row, "album_id"),
+        artist_id: columns.id(row, "artist_id"),
+        artwork_id: columns.id(row, "artwork_id"),
+        plays: columns.u32(row, "plays").unwrap_or(0),
+        rating: columns.u32(row, "rating").unwrap_or(0),
+        size: columns.u64(row, "size"),
+        file: columns.string(row, "file"),
+        path: columns.string(row, "path"),
+        uploaded_on: columns.string(row, "uploaded_on"),
+        trashed: columns.bool(row, "trashed"),
+    }
+}
+
+fn decode_album(id: u64, columns: &Columns, row: &[Value]) -> Album {
+    Album {
+        id,
+        name: columns.string(row, "name").unwrap_or_default(),
+        artist_id: columns.id(row, "artist_id"),
+        tracks: columns.ids(row, "tracks"),
+        disc: columns.u32(row, "disc"),
+        year: columns.u32(row, "year").filter(|&year| year != 0),
+        rating: columns.u32(row, "rating").unwrap_or(0),
+        trashed: columns.bool(row, "trashed"),
+    }
+}
+
+fn decode_artist(id: u64, columns: &Columns, row: &[Value]) -> Artist {
+    Artist {
+        id,
+        name: columns.string(row, "name").unwrap_or_default(),
+        tracks: columns.ids(row, "tracks"),
+        artwork_id: columns.id(row, "artwork_id"),
+        rating: columns.u32(row, "rating").unwrap_or(0),
+        trashed: columns.bool(row, "trashed"),
+    }
+}
+
+fn decode_playlist(id: u64, columns: &Columns, row: &[Value]) -> Playlist {
+    Playlist {
+        id,
+        name: columns.string(row, "name").unwrap_or_default(),
+        description: columns.string(row, "description"),
+        tracks: columns.ids(row, "tracks"),
+        artwork_id: columns.id(row, "artwork_id"),
+        public_id: columns.string(row, "public_id"),
+        kind: columns.string(row, "type"),
+        system_created: columns.bool(row, "system_created"),
+    }
+}
+
+/// Tags are sent either as a columnar table or as plain objects keyed by id.
+fn decode_tags(table: Option<&Value>) -> Result<BTreeMap<u64, Tag>, IBroadcastError> {
+    let Some(Value::Object(object)) = table else {
+        return Ok(BTreeMap::new());
+    };
+
+    if object.contains_key("map") {
+        return decode_table(table, "tags", |id, columns, row| Tag {
+            id,
+            name: columns.string(row, "name").unwrap_or_default(),
+            tracks: columns.ids(row, "tracks"),
+            archived: columns.bool(row, "archived"),
+        });
+    }
+
+    let mut tags = BTreeMap::new();
+    for (key, tag) in object {
+        let Ok(id) = key.parse::<u64>() else {
+            log::warn!("Skipping malformed tag {:?}", key);
+            continue;
+        };
+        tags.insert(
+            id,
+            Tag {
+                id,
+                name: tag.get("name").and_then(Value::as_str).unwrap_or_default().to_string(),
+                tracks: tag.get("tracks").map(value_to_ids).unwrap_or_default(),
+                archived: tag.get("archived").is_some_and(value_to_bool),
+            },
+        );
+    }
+    Ok(tags)
+}
+
+/// Trash is either a columnar table of track rows or an object with a
+/// `tracks` id list.
+fn decode_trash(table: Option<&Value>) -> Result<Trash, IBroadcastError> {
+    let Some(Value::Object(object)) = table else {
+        return Ok(Trash::default());
+    };
+
+    if object.contains_key("map") {
+        let rows = decode_table(table, "trash", |id, _, _| id)?;
+        return Ok(Trash { tracks: rows.into_keys().collect() });
+    }
+
+    Ok(Trash {
+        tracks: object.get("tracks").map(value_to_ids).unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn decodes_columns_in_map_order() {
+        let library = json!({
+            "tracks": {
+                "map": { "album_id": 0, "title": 3, "track": 1, "trashed": 2 },
+                "10": [5, 2, false, "Second"],
+                "11": [5, "1", 1, "First"]
+            },
+            "albums": {
+                "map": { "tracks": 1, "name": 0 },
+                "5": ["An Album", [11, 10]]
+            }
+        });
+
+        let library = Library::from_value(&library, &Value::Null).unwrap();
+        let second = &library.tracks[&10];
+        assert_eq!(second.title, "Second");
+        assert_eq!(second.track_number, Some(2));
+        assert_eq!(second.album_id, Some(5));
+        assert!(!second.trashed);
+        let first = &library.tracks[&11];
+        assert_eq!(first.track_number, Some(1));
+        assert!(first.trashed);
+        assert_eq!(library.albums[&5].name, "An Album");
+        assert_eq!(library.albums[&5].tracks, vec![11, 10]);
+        // Trashed tracks are left out
+        let titles: Vec<&str> = library.album_tracks(5).iter().map(|track| track.title.as_str()).collect();
+        assert_eq!(titles, vec!["Second"]);
+    }
+
+    #[test]
+    fn tolerates_missing_and_extra_columns() {
+        let library = json!({
+            "tracks": {
+                // "genre" is mapped past the end of the row, "year" isn't mapped at all
+                "map": { "title": 0, "genre": 5, "unknown": 1, "artist_id": 2 },
+                "1": ["Song", "extra", 0],
+                "oops": ["Not an id"],
+                "2": "not a row"
+            },
+            "artists": { "map": { "name": 0 }, "3": ["Someone", "ignored"] }
+        });
+
+        let library = Library::from_value(&library, &Value::Null).unwrap();
+        assert_eq!(library.tracks.len(), 1);
+        let track = &library.tracks[&1];
+        assert_eq!(track.title, "Song");
+        assert_eq!(track.genre, None);
+        assert_eq!(track.year, None);
+        // An id of zero means none
+        assert_eq!(track.artist_id, None);
+        assert_eq!(library.artists[&3].name, "Someone");
+        assert!(library.albums.is_empty());
+    }
+
+    #[test]
+    fn rejects_a_table_without_a_map() {
+        let library = json!({ "tracks": { "1": ["Song"] } });
+        assert!(matches!(
+            Library::from_value(&library, &Value::Null),
+            Err(IBroadcastError::InvalidResponse(_))
+        ));
+    }
+
+    #[test]
+    fn decodes_trash_and_tags_in_either_form() {
+        let columnar = json!({
+            "trash": { "map": { "title": 0 }, "7": ["Gone"], "8": ["Also gone"] },
+            "tags": { "map": { "name": 1, "tracks": 0 }, "4": [[1, 2], "Loud"] }
+        });
+        let library = Library::from_value(&columnar, &Value::Null).unwrap();
+        assert_eq!(library.trash.tracks, vec![7, 8]);
+        assert_eq!(library.tags[&4].name, "Loud");
+        assert_eq!(library.tags[&4].tracks, vec![1, 2]);
+
+        let plain = json!({
+            "trash": { "tracks": [9, "10"] },
+            "tags": { "4": { "name": "Quiet", "tracks": [3], "archived": 1 } }
+        });
+        let library = Library::from_value(&plain, &Value::Null).unwrap();
+        assert_eq!(library.trash.tracks, vec![9, 10]);
+        assert_eq!(library.tags[&4].name, "Quiet");
+        assert!(library.tags[&4].archived);
+    }
+
+    #[test]
+    fn falls_back_to_playlists_outside_the_library() {
+        let playlists = json!({ "map": { "name": 0, "tracks": 1 }, "20": ["Mix", [1]] });
+        let library = Library::from_value(&json!({}), &playlists).unwrap();
+        assert_eq!(library.playlists[&20].name, "Mix");
+        assert_eq!(library.playlists[&20].tracks, vec![1]);
+    }
+}
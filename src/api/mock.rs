@@ -0,0 +1,673 @@
+//! In-process mock of the iBroadcast JSON API.
+//!
+//! The server listens on a random localhost port and speaks just enough
+//! HTTP/1.1 for `reqwest`: every API call is a form-encoded POST to `/`, and
+//! stream URLs handed out by the `stream` mode point back at `/stream/<id>`.
+//! Point a client at it with `IBroadcastClient::with_base_url(server.url())`.
+
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use super::{Library, Playlist, Track};
+
+const TOKEN_LIFETIME_SECS: i64 = 3600;
+const DEVICE_CODE_LIFETIME_SECS: i64 = 600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeviceCodeState {
+    Pending,
+    Approved,
+    Expired,
+}
+
+/// A canned failure for an upcoming API call
+struct Failure {
+    status: u16,
+    retry_after: Option<String>,
+    /// Whether the call still takes effect before failing
+    handled: bool,
+}
+
+impl Failure {
+    fn response(&self) -> HttpResponse {
+        let mut response = HttpResponse::error(self.status, "Injected failure");
+        if let Some(retry_after) = &self.retry_after {
+            response.headers.push(("Retry-After".to_string(), retry_after.clone()));
+        }
+        response
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    accounts: HashMap<String, String>,
+    tokens: HashMap<String, String>,
+    device_codes: HashMap<String, DeviceCodeState>,
+    library: Library,
+    streams: HashMap<u64, Vec<u8>>,
+    requests: Vec<HashMap<String, String>>,
+    failures: VecDeque<Failure>,
+    /// The next refresh succeeds without handing out a token
+    withhold_refreshed_token: bool,
+    next_id: u64,
+}
+
+impl MockState {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn issue_token(&mut self, user_id: &str) -> String {
+        let token = format!("mock-token-{}", self.next_id());
+        self.tokens.insert(token.clone(), user_id.to_string());
+        token
+    }
+}
+
+/// A running mock server. The listener task is aborted on drop.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    task: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Binds to a random localhost port and starts serving requests
+    pub async fn start() -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(MockState {
+            next_id: 1000,
+            ..Default::default()
+        }));
+
+        let task_state = state.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let state = task_state.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, addr, state).await {
+                        log::debug!("Mock server connection error: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(Self { addr, state, task })
+    }
+
+    /// The API endpoint to pass to `IBroadcastClient::with_base_url`
+    pub fn url(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+
+    /// Registers an account accepted by the `login` mode
+    pub fn add_account(&self, email: &str, password: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.accounts.insert(email.to_string(), password.to_string());
+    }
+
+    /// Marks a device code as approved so the next poll authenticates
+    pub fn approve_device_code(&self, device_code: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.device_codes.insert(device_code.to_string(), DeviceCodeState::Approved);
+    }
+
+    /// Marks a device code as expired
+    pub fn expire_device_code(&self, device_code: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.device_codes.insert(device_code.to_string(), DeviceCodeState::Expired);
+    }
+
+    /// Invalidates every issued token, as if the sessions were revoked
+    pub fn revoke_tokens(&self) {
+        self.state.lock().unwrap().tokens.clear();
+    }
+
+    /// Replaces the library served by `getlibrary`
+    pub fn set_library(&self, library: Library) {
+        self.state.lock().unwrap().library = library;
+    }
+
+    /// Returns the library as modified by playlist calls
+    pub fn library(&self) -> Library {
+        self.state.lock().unwrap().library.clone()
+    }
+
+    /// Adds a track to the library, with the bytes served for its stream
+    pub fn add_track(&self, track: Track, audio: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        state.streams.insert(track.id, audio);
+        state.library.tracks.insert(track.id, track);
+    }
+
+    /// Makes the next API call fail with `status` without taking effect,
+    /// optionally with a Retry-After header
+    pub fn reject_next(&self, status: u16, retry_after: Option<&str>) {
+        self.state.lock().unwrap().failures.push_back(Failure {
+            status,
+            retry_after: retry_after.map(str::to_string),
+            handled: false,
+        });
+    }
+
+    /// Makes the next API call take effect but still fail with `status`, as
+    /// when a response is lost after the server acted on the request
+    pub fn fail_next_after_handling(&self, status: u16) {
+        self.state.lock().unwrap().failures.push_back(Failure {
+            status,
+            retry_after: None,
+            handled: true,
+        });
+    }
+
+    /// Makes the next refresh report success but leave out the new token
+    pub fn withhold_refreshed_token(&self) {
+        self.state.lock().unwrap().withhold_refreshed_token = true;
+    }
+
+    /// Returns the form parameters of every API call received so far
+    pub fn requests(&self) -> Vec<HashMap<String, String>> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Returns the modes of every API call received so far
+    pub fn modes(&self) -> Vec<String> {
+        self.requests()
+            .into_iter()
+            .filter_map(|mut params| params.remove("mode"))
+            .collect()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+struct HttpResponse {
+    status: u16,
+    content_type: &'static str,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn json(status: u16, value: Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self::json(status, json!({ "status": "error", "message": message }))
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+) -> std::io::Result<()> {
+    let Some(request) = read_request(&mut stream).await? else {
+        return Ok(());
+    };
+
+    let response = if request.method == "POST" && request.path == "/" {
+        let params = parse_form(&String::from_utf8_lossy(&request.body));
+        let mut state = state.lock().unwrap();
+        match state.failures.pop_front() {
+            Some(failure) if !failure.handled => {
+                state.requests.push(params);
+                failure.response()
+            }
+            Some(failure) => {
+                handle_api(&mut state, addr, params);
+                failure.response()
+            }
+            None => handle_api(&mut state, addr, params),
+        }
+    } else if let Some(id) = request.path.strip_prefix("/stream/") {
+        handle_stream(&state.lock().unwrap(), id, &request.headers)
+    } else {
+        HttpResponse::error(404, "Not found")
+    };
+
+    write_response(&mut stream, response).await
+}
+
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<Option<HttpRequest>> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    let header_end = loop {
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let content_length = headers
+        .get("content-length")
+        .and_then(|length| length.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    let mut body = buffer[header_end..].to_vec();
+    while body.len() < content_length {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+
+    Ok(Some(HttpRequest {
+        method,
+        path,
+        headers,
+        body,
+    }))
+}
+
+async fn write_response(stream: &mut TcpStream, response: HttpResponse) -> std::io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        206 => "Partial Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        416 => "Range Not Satisfiable",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Error",
+    };
+
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len()
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await
+}
+
+fn parse_form(body: &str) -> HashMap<String, String> {
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn handle_api(
+    state: &mut MockState,
+    addr: SocketAddr,
+    params: HashMap<String, String>,
+) -> HttpResponse {
+    state.requests.push(params.clone());
+    let mode = params.get("mode").map(String::as_str).unwrap_or_default();
+    let param = |name: &str| params.get(name).map(String::as_str).unwrap_or_default();
+
+    match mode {
+        "login" => {
+            let email = param("email");
+            if state.accounts.get(email).map(String::as_str) != Some(param("password")) {
+                return HttpResponse::json(
+                    200,
+                    json!({ "message": "Invalid email or password", "authenticated": false, "result": false }),
+                );
+            }
+            let token = state.issue_token(email);
+            HttpResponse::json(
+                200,
+                json!({
+                    "message": "ok",
+                    "authenticated": true,
+                    "result": true,
+                    "token": token,
+                    "user": { "id": email, "email": email },
+                    "expires": TOKEN_LIFETIME_SECS,
+                }),
+            )
+        }
+        "getdevicecode" => {
+            let code = format!("{:06}", state.next_id());
+            state.device_codes.insert(code.clone(), DeviceCodeState::Pending);
+            HttpResponse::json(
+                200,
+                json!({
+                    "message": "ok",
+                    "result": true,
+                    "device_code": code,
+                    "expires_in": DEVICE_CODE_LIFETIME_SECS,
+                }),
+            )
+        }
+        "polldevicecode" => match state.device_codes.get(param("device_code")).copied() {
+            Some(DeviceCodeState::Pending) => HttpResponse::json(
+                200,
+                json!({ "message": "Waiting for approval", "result": true, "authenticated": false }),
+            ),
+            Some(DeviceCodeState::Approved) => {
+                let user_id = format!("device-user-{}", param("device_code"));
+                let token = state.issue_token(&user_id);
+                state.device_codes.remove(param("device_code"));
+                HttpResponse::json(
+                    200,
+                    json!({
+                        "message": "ok",
+                        "result": true,
+                        "authenticated": true,
+                        "token": token,
+                        "user": { "id": user_id },
+                    }),
+                )
+            }
+            Some(DeviceCodeState::Expired) | None => HttpResponse::json(
+                200,
+                json!({ "message": "Device code expired", "result": false, "authenticated": false }),
+            ),
+        },
+        _ => {
+            let Some(user_id) = state.tokens.get(param("token")).cloned() else {
+                return HttpResponse::error(401, "Invalid token");
+            };
+            handle_authenticated(state, addr, mode, &user_id, &params)
+        }
+    }
+}
+
+fn handle_authenticated(
+    state: &mut MockState,
+    addr: SocketAddr,
+    mode: &str,
+    user_id: &str,
+    params: &HashMap<String, String>,
+) -> HttpResponse {
+    let id_param = |name: &str| params.get(name).and_then(|value| value.parse::<u64>().ok());
+
+    match mode {
+        "refresh" if std::mem::take(&mut state.withhold_refreshed_token) => HttpResponse::json(
+            200,
+            json!({ "message": "ok", "authenticated": true, "result": true }),
+        ),
+        "refresh" => {
+            let old_token = params.get("token").cloned().unwrap_or_default();
+            state.tokens.remove(&old_token);
+            let token = state.issue_token(user_id);
+            HttpResponse::json(
+                200,
+                json!({
+                    "message": "ok",
+                    "authenticated": true,
+                    "result": true,
+                    "token": token,
+                    "expires": TOKEN_LIFETIME_SECS,
+                }),
+            )
+        }
+        "logout" => {
+            if let Some(token) = params.get("token") {
+                state.tokens.remove(token);
+            }
+            HttpResponse::json(200, json!({ "status": "ok", "result": true }))
+        }
+        "getlibrary" => HttpResponse::json(
+            200,
+            json!({
+                "status": "ok",
+                "library": encode_library(&state.library),
+            }),
+        ),
+        "stream" => {
+            let Some(track) = id_param("id").and_then(|id| state.library.tracks.get(&id)) else {
+                return HttpResponse::error(404, "Unknown track");
+            };
+            HttpResponse::json(
+                200,
+                json!({
+                    "status": "ok",
+                    "stream_url": format!("http://{}/stream/{}", addr, track.id),
+                    "duration": track.length.unwrap_or(0),
+                    "bitrate": 320,
+                }),
+            )
+        }
+        "createplaylist" => {
+            let id = state.next_id();
+            let name = params.get("name").cloned().unwrap_or_default();
+            state.library.playlists.insert(
+                id,
+                Playlist {
+                    id,
+                    name: name.clone(),
+                    ..Default::default()
+                },
+            );
+            HttpResponse::json(200, json!({ "status": "ok", "playlist_id": id.to_string(), "name": name }))
+        }
+        "addtoplaylist" | "removefromplaylist" => {
+            let (Some(playlist_id), Some(media_id)) = (id_param("playlist_id"), id_param("media_id")) else {
+                return HttpResponse::error(400, "Missing playlist_id or media_id");
+            };
+            let Some(playlist) = state.library.playlists.get_mut(&playlist_id) else {
+                return HttpResponse::error(404, "Unknown playlist");
+            };
+            if mode == "addtoplaylist" {
+                playlist.tracks.push(media_id);
+            } else {
+                playlist.tracks.retain(|&id| id != media_id);
+            }
+            HttpResponse::json(200, json!({ "status": "ok", "result": true }))
+        }
+        "deleteplaylist" => match id_param("playlist_id") {
+            Some(id) if state.library.playlists.remove(&id).is_some() => {
+                HttpResponse::json(200, json!({ "status": "ok", "result": true }))
+            }
+            _ => HttpResponse::error(404, "Unknown playlist"),
+        },
+        "getplaylists" => {
+            let (id, name) = state
+                .library
+                .playlists
+                .values()
+                .next()
+                .map(|playlist| (playlist.id.to_string(), playlist.name.clone()))
+                .unwrap_or_default();
+            HttpResponse::json(200, json!({ "status": "ok", "playlist_id": id, "name": name }))
+        }
+        _ => HttpResponse::error(400, &format!("Unsupported mode: {}", mode)),
+    }
+}
+
+fn handle_stream(state: &MockState, id: &str, headers: &HashMap<String, String>) -> HttpResponse {
+    let Some(audio) = id.parse::<u64>().ok().and_then(|id| state.streams.get(&id)) else {
+        return HttpResponse::error(404, "Unknown track");
+    };
+    let etag = stream_etag(audio);
+
+    // A range is only honoured if the client's copy is still current
+    let stale = headers.get("if-range").is_some_and(|tag| *tag != etag);
+    let start = headers
+        .get("range")
+        .filter(|_| !stale)
+        .and_then(|range| range.strip_prefix("bytes="))
+        .and_then(|range| range.split('-').next())
+        .and_then(|start| start.parse::<usize>().ok());
+
+    match start {
+        Some(start) if start >= audio.len() => HttpResponse {
+            status: 416,
+            content_type: "audio/mpeg",
+            headers: vec![("Content-Range".to_string(), format!("bytes */{}", audio.len()))],
+            body: Vec::new(),
+        },
+        Some(start) => HttpResponse {
+            status: 206,
+            content_type: "audio/mpeg",
+            headers: vec![
+                (
+                    "Content-Range".to_string(),
+                    format!("bytes {}-{}/{}", start, audio.len() - 1, audio.len()),
+                ),
+                ("ETag".to_string(), etag),
+            ],
+            body: audio[start..].to_vec(),
+        },
+        None => HttpResponse {
+            status: 200,
+            content_type: "audio/mpeg",
+            headers: vec![("ETag".to_string(), etag)],
+            body: audio.clone(),
+        },
+    }
+}
+
+/// Changes whenever the track's audio does
+fn stream_etag(audio: &[u8]) -> String {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    audio.hash(&mut hasher);
+    format!("\"{:x}\"", hasher.finish())
+}
+
+/// Encodes a library back into iBroadcast's columnar `getlibrary` format
+fn encode_library(library: &Library) -> Value {
+    fn table<T>(
+        columns: &[&str],
+        items: impl Iterator<Item = (u64, T)>,
+        row: impl Fn(&T) -> Vec<Value>,
+    ) -> Value {
+        let mut table = Map::new();
+        let map: Map<String, Value> = columns
+            .iter()
+            .enumerate()
+            .map(|(position, column)| (column.to_string(), json!(position)))
+            .collect();
+        table.insert("map".to_string(), Value::Object(map));
+        for (id, item) in items {
+            table.insert(id.to_string(), Value::Array(row(&item)));
+        }
+        Value::Object(table)
+    }
+
+    let tags: Map<String, Value> = library
+        .tags
+        .values()
+        .map(|tag| {
+            (
+                tag.id.to_string(),
+                json!({ "name": tag.name, "archived": tag.archived, "tracks": tag.tracks }),
+            )
+        })
+        .collect();
+
+    json!({
+        "tracks": table(
+            &["title", "track", "year", "genre", "length", "album_id", "artist_id", "artwork_id",
+              "plays", "rating", "size", "file", "path", "uploaded_on", "trashed"],
+            library.tracks.iter().map(|(id, track)| (*id, track)),
+            |track| vec![
+                json!(track.title), json!(track.track_number), json!(track.year), json!(track.genre),
+                json!(track.length), json!(track.album_id), json!(track.artist_id),
+                json!(track.artwork_id), json!(track.plays), json!(track.rating), json!(track.size),
+                json!(track.file), json!(track.path), json!(track.uploaded_on), json!(track.trashed),
+            ],
+        ),
+        "albums": table(
+            &["name", "artist_id", "tracks", "disc", "year", "rating", "trashed"],
+            library.albums.iter().map(|(id, album)| (*id, album)),
+            |album| vec![
+                json!(album.name), json!(album.artist_id), json!(album.tracks), json!(album.disc),
+                json!(album.year), json!(album.rating), json!(album.trashed),
+            ],
+        ),
+        "artists": table(
+            &["name", "tracks", "artwork_id", "rating", "trashed"],
+            library.artists.iter().map(|(id, artist)| (*id, artist)),
+            |artist| vec![
+                json!(artist.name), json!(artist.tracks), json!(artist.artwork_id),
+                json!(artist.rating), json!(artist.trashed),
+            ],
+        ),
+        "playlists": table(
+            &["name", "description", "tracks", "artwork_id", "public_id", "type", "system_created"],
+            library.playlists.iter().map(|(id, playlist)| (*id, playlist)),
+            |playlist| vec![
+                json!(playlist.name), json!(playlist.description), json!(playlist.tracks),
+                json!(playlist.artwork_id), json!(playlist.public_id), json!(playlist.kind),
+                json!(playlist.system_created),
+            ],
+        ),
+        "tags": tags,
+        "trash": { "tracks": library.trash.tracks },
+    })
+}
@@ -1,18 +1,33 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::time::{Duration, SystemTime};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
+use tokio::sync::{broadcast, watch, Notify};
 use tokio::time::sleep;
-use std::pin::Pin;
-use std::future::Future;
+use tokio_util::sync::CancellationToken;
+
+mod library;
+#[cfg(feature = "mock-server")]
+pub mod mock;
+mod rate_limit;
+pub mod redact;
+mod request;
+mod retry;
+mod session;
+
+pub use library::{Album, Artist, Library, Playlist, Tag, Track, Trash};
+use rate_limit::RateLimiter;
+use request::{Params, Request};
+pub use retry::RetryPolicy;
+pub use session::SessionEvent;
+use session::{PendingRefresh, SessionState};
 
 const API_BASE_URL: &str = "https://api.ibroadcast.com/s/JSON";
 const TOKEN_REFRESH_THRESHOLD: Duration = Duration::from_secs(300); // 5 minutes
-const MAX_RETRIES: u32 = 3;
-const RETRY_DELAY: Duration = Duration::from_secs(1);
-const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
-const MAX_REQUESTS_PER_WINDOW: u32 = 60;
+const SESSION_EVENT_CAPACITY: usize = 16;
 
 #[derive(Debug, Error)]
 pub enum IBroadcastError {
@@ -28,6 +43,12 @@ pub enum IBroadcastError {
     InvalidResponse(String),
     #[error("Not logged in")]
     NotLoggedIn,
+    #[error("No network connection")]
+    Offline,
+    #[error("Request was cancelled")]
+    Cancelled,
+    #[error("Could not refresh the session: {0}")]
+    TokenRefresh(String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -60,8 +81,10 @@ pub struct ErrorResponse {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LibraryResponse {
+    #[serde(default)]
     pub status: String,
     pub library: serde_json::Value,
+    #[serde(default)]
     pub playlists: serde_json::Value,
 }
 
@@ -94,130 +117,356 @@ pub struct DeviceCodeResponse {
     pub token: Option<String>,
     #[serde(default)]
     pub user: Option<UserInfo>,
+    #[serde(default)]
+    pub expires: Option<i64>,
 }
 
-pub struct IBroadcastClient {
-    client: reqwest::Client,
+/// Authentication state that can be persisted and restored across launches
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub token: String,
+    #[serde(default)]
+    pub user_id: Option<String>,
+    /// Token expiry as seconds since the Unix epoch, if the server sent one
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+impl Session {
+    /// Returns whether the token has already expired
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| UNIX_EPOCH + Duration::from_secs(expires_at) <= SystemTime::now())
+    }
+}
+
+#[derive(Default)]
+struct Credentials {
     token: Option<String>,
     token_expires: Option<SystemTime>,
     user_id: Option<String>,
-    request_count: u32,
-    last_request_time: SystemTime,
+}
+
+/// State behind every clone of a client. Locks here are only held for
+/// plain reads and writes, never across an `.await`.
+struct Shared {
+    http: reqwest::Client,
+    base_url: String,
+    credentials: RwLock<Credentials>,
+    limiter: RateLimiter,
+    retry_policy: RwLock<RetryPolicy>,
+    online: AtomicBool,
+    /// The token refresh in flight, joined by every request that needs it
+    pending_refresh: Mutex<Option<PendingRefresh>>,
+    /// Wakes the background refresher when the session is replaced
+    session_changed: Arc<Notify>,
+    session_events: broadcast::Sender<SessionEvent>,
+    session_state: watch::Sender<SessionState>,
+    /// Whether requests wait for a new login when the session is lost
+    reauthenticate: AtomicBool,
+    /// Account of the session lost while requests wait for a new login;
+    /// only a login to the same account lets them go
+    lost_user_id: Mutex<Option<String>>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // Lets the background refresher see that the client is gone
+        self.session_changed.notify_waiters();
+    }
+}
+
+/// iBroadcast API client.
+///
+/// This is a cheap handle: clones share the session, rate limit budget and
+/// connection pool, and every method takes `&self`, so any number of
+/// requests can be in flight at once without an outer lock.
+#[derive(Clone)]
+pub struct IBroadcastClient {
+    shared: Arc<Shared>,
+    /// Cancels this handle's requests; see `with_cancellation`
+    cancel: Option<CancellationToken>,
+}
+
+impl Default for IBroadcastClient {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl IBroadcastClient {
     /// Creates a new iBroadcast API client
     pub fn new() -> Self {
+        Self::with_base_url(API_BASE_URL)
+    }
+
+    /// Creates a client that talks to the given endpoint instead of the
+    /// public iBroadcast API, e.g. a local mock server
+    pub fn with_base_url(base_url: impl Into<String>) -> Self {
+        let (session_events, _) = broadcast::channel(SESSION_EVENT_CAPACITY);
+        Self::from_shared(Arc::new(Shared {
+            http: reqwest::Client::new(),
+            base_url: base_url.into(),
+            credentials: RwLock::new(Credentials::default()),
+            limiter: RateLimiter::default(),
+            retry_policy: RwLock::new(RetryPolicy::default()),
+            online: AtomicBool::new(true),
+            pending_refresh: Mutex::new(None),
+            session_changed: Arc::new(Notify::new()),
+            session_events,
+            session_state: watch::channel(SessionState::Active).0,
+            reauthenticate: AtomicBool::new(false),
+            lost_user_id: Mutex::new(None),
+        }))
+    }
+
+    fn from_shared(shared: Arc<Shared>) -> Self {
+        Self { shared, cancel: None }
+    }
+
+    /// Returns a handle to the same session whose requests fail with
+    /// `IBroadcastError::Cancelled` as soon as `cancel` is cancelled.
+    ///
+    /// Cancelling never leaves the session half-updated: credentials are
+    /// only replaced once a response is complete, and a token refresh runs
+    /// to the end in the background.
+    pub fn with_cancellation(&self, cancel: CancellationToken) -> Self {
         Self {
-            client: reqwest::Client::new(),
-            token: None,
-            token_expires: None,
-            user_id: None,
-            request_count: 0,
-            last_request_time: SystemTime::now(),
+            shared: self.shared.clone(),
+            cancel: Some(cancel),
         }
     }
 
-    /// Handles rate limiting by checking and updating request counts
-    async fn check_rate_limit(&mut self) -> Result<(), IBroadcastError> {
-        let now = SystemTime::now();
-        if now.duration_since(self.last_request_time).unwrap_or(Duration::ZERO) > RATE_LIMIT_WINDOW {
-            self.request_count = 0;
-            self.last_request_time = now;
+    /// Runs `request` until it completes or this handle is cancelled
+    async fn cancellable<T>(
+        &self,
+        request: impl Future<Output = Result<T, IBroadcastError>>,
+    ) -> Result<T, IBroadcastError> {
+        let Some(cancel) = &self.cancel else {
+            return request.await;
+        };
+        tokio::select! {
+            biased;
+            _ = cancel.cancelled() => Err(IBroadcastError::Cancelled),
+            result = request => result,
         }
+    }
+
+    /// Tells the client whether the network is up. While it isn't, requests
+    /// fail right away with `IBroadcastError::Offline` instead of retrying.
+    pub fn set_online(&self, online: bool) {
+        self.shared.online.store(online, Ordering::Relaxed);
+    }
 
-        if self.request_count >= MAX_REQUESTS_PER_WINDOW {
-            return Err(IBroadcastError::RateLimitExceeded);
+    /// Replaces the policy for retrying failed calls
+    pub fn set_retry_policy(&self, policy: RetryPolicy) {
+        *self.shared.retry_policy.write().unwrap() = policy;
+    }
+
+    fn check_online(&self) -> Result<(), IBroadcastError> {
+        if self.shared.online.load(Ordering::Relaxed) {
+            Ok(())
+        } else {
+            Err(IBroadcastError::Offline)
         }
+    }
 
-        self.request_count += 1;
-        Ok(())
+    /// Returns the endpoint this client sends requests to
+    pub fn base_url(&self) -> &str {
+        &self.shared.base_url
     }
 
-    /// Makes an API request with retry logic
-    async fn make_request<T: for<'de> Deserialize<'de>>(
-        &mut self,
-        params: HashMap<String, String>,
-    ) -> Result<T, IBroadcastError> {
-        self.check_rate_limit().await?;
-        
-        // Skip token validation for login requests
-        if params.get("mode").map_or(false, |mode| mode != "login") {
-            self.ensure_valid_token().await?;
+    fn credentials(&self) -> RwLockReadGuard<'_, Credentials> {
+        self.shared.credentials.read().unwrap()
+    }
+
+    fn credentials_mut(&self) -> RwLockWriteGuard<'_, Credentials> {
+        self.shared.credentials.write().unwrap()
+    }
+
+    fn notify_session_changed(&self) {
+        self.shared.session_changed.notify_waiters();
+    }
+
+    /// Events about the session, e.g. that it was refreshed or lost
+    pub fn subscribe_session(&self) -> broadcast::Receiver<SessionEvent> {
+        self.shared.session_events.subscribe()
+    }
+
+    fn token(&self) -> Result<String, IBroadcastError> {
+        self.credentials().token.clone().ok_or(IBroadcastError::NotLoggedIn)
+    }
+
+    /// Returns the current session, if logged in
+    pub fn session(&self) -> Option<Session> {
+        let credentials = self.credentials();
+        Some(Session {
+            token: credentials.token.clone()?,
+            user_id: credentials.user_id.clone(),
+            expires_at: credentials
+                .token_expires
+                .and_then(|expires| expires.duration_since(UNIX_EPOCH).ok())
+                .map(|expires| expires.as_secs()),
+        })
+    }
+
+    /// Restores a previously saved session without contacting the server
+    pub fn restore_session(&self, session: Session) {
+        let mut credentials = self.credentials_mut();
+        credentials.token_expires = session
+            .expires_at
+            .map(|expires_at| UNIX_EPOCH + Duration::from_secs(expires_at));
+        credentials.token = Some(session.token);
+        credentials.user_id = session.user_id;
+        drop(credentials);
+        self.session_started();
+    }
+
+    /// Refreshes the token if it is close to expiring, so a restored session
+    /// is known to be usable
+    pub async fn validate_session(&self) -> Result<(), IBroadcastError> {
+        if self.credentials().token.is_none() {
+            return Err(IBroadcastError::NotLoggedIn);
         }
+        self.cancellable(self.ensure_valid_token()).await
+    }
+
+    /// Makes an API request with retry logic, unless cancelled first
+    async fn make_request<R: Request>(&self, request: R) -> Result<R::Response, IBroadcastError> {
+        // Without a session there is nothing to wait for
+        if R::AUTHENTICATED {
+            self.token()?;
+        }
+        self.cancellable(self.send_request(&request)).await
+    }
+
+    async fn send_request<R: Request>(&self, request: &R) -> Result<R::Response, IBroadcastError> {
+        self.check_online()?;
+        // Requests without a token, like those that log in, don't need a session
+        if !R::AUTHENTICATED {
+            return self.send_with_retries::<R>(&Params::build(request, None)).await;
+        }
+
+        loop {
+            self.session_ready().await?;
+            if let Err(e) = self.ensure_valid_token().await {
+                if self.awaiting_login() {
+                    continue;
+                }
+                return Err(e);
+            }
+            // The token may have been refreshed or replaced since the caller read it
+            let token = self.token()?;
+            let params = Params::build(request, Some(&token));
+
+            match self.send_with_retries::<R>(&params).await {
+                Err(IBroadcastError::Authentication(message)) => {
+                    self.session_lost(&token, &message);
+                    if !self.awaiting_login() {
+                        return Err(IBroadcastError::Authentication(message));
+                    }
+                    log::info!("Holding {} until the user logs in again", R::MODE);
+                }
+                result => return result,
+            }
+        }
+    }
 
-        let mut retries = 0;
+    /// Sends a request, retrying failures that may pass
+    async fn send_with_retries<R: Request>(&self, params: &Params) -> Result<R::Response, IBroadcastError> {
+        log::debug!("Request parameters: {:?}", redact::params(params));
+        let retry_policy = self.shared.retry_policy.read().unwrap().clone();
+        let started = tokio::time::Instant::now();
+        let mut attempt = 0;
         loop {
-            match self
-                .client
-                .post(API_BASE_URL)
-                .form(&params)
+            // Waits for capacity rather than failing; retries count as requests too
+            self.shared.limiter.acquire(R::MODE).await;
+            let (error, retry_after, retryable) = match self
+                .shared
+                .http
+                .post(&self.shared.base_url)
+                .form(params)
                 .send()
                 .await
             {
                 Ok(response) => {
-                    if response.status().is_success() {
-                        return response.json::<T>().await.map_err(|e| {
+                    let status = response.status();
+                    let retry_after = response
+                        .headers()
+                        .get(reqwest::header::RETRY_AFTER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(retry::parse_retry_after);
+                    if status.is_success() {
+                        return response.json::<R::Response>().await.map_err(|e| {
                             IBroadcastError::InvalidResponse(format!("Failed to parse response: {}", e))
                         });
-                    } else if response.status().as_u16() == 429 {
-                        if retries < MAX_RETRIES {
-                            retries += 1;
-                            sleep(RETRY_DELAY * retries).await;
-                            continue;
-                        }
-                        return Err(IBroadcastError::RateLimitExceeded);
+                    } else if status.as_u16() == 429 {
+                        // Rejected without being handled, so even mutations can go again
+                        (IBroadcastError::RateLimitExceeded, retry_after, true)
                     } else {
                         let error: ErrorResponse = response.json().await.unwrap_or(ErrorResponse {
                             status: "error".to_string(),
                             message: "Unknown error".to_string(),
                         });
-                        return Err(IBroadcastError::Api(error.message));
+                        if matches!(status.as_u16(), 401 | 403) {
+                            // The token was turned down, so retrying it can't help
+                            return Err(IBroadcastError::Authentication(error.message));
+                        }
+                        // A server error may come after the call took effect
+                        let retryable = status.is_server_error() && R::IDEMPOTENT;
+                        (IBroadcastError::Api(error.message), retry_after, retryable)
                     }
                 }
                 Err(e) => {
-                    if retries < MAX_RETRIES {
-                        retries += 1;
-                        sleep(RETRY_DELAY * retries).await;
-                        continue;
-                    }
-                    return Err(IBroadcastError::Network(e));
+                    // A failed connection never reached the server; anything
+                    // later (e.g. a timeout) might have
+                    let retryable = R::IDEMPOTENT || e.is_connect();
+                    (IBroadcastError::Network(e), None, retryable)
                 }
-            }
+            };
+
+            let delay = if retryable {
+                retry_policy.next_delay(attempt, started, retry_after)
+            } else {
+                None
+            };
+            let Some(delay) = delay else {
+                return Err(error);
+            };
+            attempt += 1;
+            log::debug!("Retrying {} in {:?} (attempt {}): {}", R::MODE, delay, attempt, error);
+            sleep(delay).await;
         }
     }
 
-    /// Authenticates with the iBroadcast API using email and password
-    pub async fn login(&mut self, email: &str, password: &str) -> Result<(), IBroadcastError> {
-        let mut params = HashMap::new();
-        params.insert("mode".to_string(), "login".to_string());
-        params.insert("email".to_string(), email.to_string());
-        params.insert("password".to_string(), password.to_string());
-        params.insert("app".to_string(), "Latke".to_string());
-        params.insert("version".to_string(), env!("CARGO_PKG_VERSION").to_string());
-        params.insert("device".to_string(), "desktop".to_string());
-        params.insert("client".to_string(), "Latke Desktop Client".to_string());
-
-        // Log request parameters (excluding password)
-        let mut debug_params = params.clone();
-        debug_params.remove("password");
-        log::debug!("Login request parameters: {:?}", debug_params);
-
-        // Make the request and get the raw response first
+    /// Sends a request once, without retries, and returns the response body
+    async fn send_raw<R: Request>(&self, params: &Params) -> Result<String, IBroadcastError> {
+        self.shared.limiter.acquire(R::MODE).await;
         let response = self
-            .client
-            .post(API_BASE_URL)
-            .form(&params)
+            .shared
+            .http
+            .post(&self.shared.base_url)
+            .form(params)
             .send()
             .await?;
 
-        // Log response status and headers
+        // Headers are left out, as they may set cookies
         log::debug!("Response status: {}", response.status());
-        log::debug!("Response headers: {:?}", response.headers());
 
-        // Log the response text for debugging
-        let response_text = response.text().await?;
-        log::debug!("Login response: {}", response_text);
+        Ok(response.text().await?)
+    }
+
+    /// Authenticates with the iBroadcast API using email and password
+    pub async fn login(&self, email: &str, password: &str) -> Result<(), IBroadcastError> {
+        self.check_online()?;
+        let request = request::Login { email, password };
+        let params = Params::build(&request, None);
+
+        log::debug!("Login request parameters: {:?}", redact::params(&params));
+
+        // Make the request and get the raw response first
+        let response_text = self.cancellable(self.send_raw::<request::Login>(&params)).await?;
+        log::debug!("Login response: {}", redact::body(&response_text));
 
         // Parse the response
         let response: LoginResponse = serde_json::from_str(&response_text).map_err(|e| {
@@ -226,11 +475,15 @@ impl IBroadcastClient {
 
         if response.authenticated && response.result {
             if let Some(token) = response.token {
-                self.token = Some(token);
-                self.user_id = response.user.map(|u| u.id);
-                if let Some(expires) = response.expires {
-                    self.token_expires = Some(SystemTime::now() + Duration::from_secs(expires as u64));
-                }
+                let mut credentials = self.credentials_mut();
+                credentials.token = Some(token);
+                credentials.user_id = response.user.map(|u| u.id);
+                // The old token's expiry must not outlive it
+                credentials.token_expires = response
+                    .expires
+                    .map(|expires| SystemTime::now() + Duration::from_secs(expires as u64));
+                drop(credentials);
+                self.session_started();
                 Ok(())
             } else {
                 Err(IBroadcastError::Authentication("No token received".to_string()))
@@ -240,182 +493,79 @@ impl IBroadcastClient {
         }
     }
 
-    fn ensure_valid_token(&mut self) -> Pin<Box<dyn Future<Output = Result<(), IBroadcastError>> + '_>> {
-        Box::pin(async move {
-            if let Some(expires) = self.token_expires {
-                if SystemTime::now() + TOKEN_REFRESH_THRESHOLD > expires {
-                    let mut params = HashMap::new();
-                    params.insert("mode".to_string(), "refresh".to_string());
-                    params.insert("token".to_string(), self.token.as_ref().ok_or(IBroadcastError::NotLoggedIn)?.to_string());
-
-                    let response = self.make_request::<LoginResponse>(params).await?;
-                    if response.authenticated && response.result {
-                        if let Some(token) = response.token {
-                            self.token = Some(token);
-                            if let Some(expires) = response.expires {
-                                self.token_expires = Some(SystemTime::now() + Duration::from_secs(expires as u64));
-                            }
-                            Ok(())
-                        } else {
-                            Err(IBroadcastError::Authentication("No token received during refresh".to_string()))
-                        }
-                    } else {
-                        Err(IBroadcastError::Authentication(response.message))
-                    }
-                } else {
-                    Ok(())
-                }
-            } else {
-                Ok(())
-            }
-        })
+    /// Fetches the user's library and decodes it into a typed model
+    pub async fn get_library(&self) -> Result<Library, IBroadcastError> {
+        let response = self.make_request(request::GetLibrary).await?;
+        Library::from_value(&response.library, &response.playlists)
     }
 
-    #[allow(dead_code)]
-    pub async fn get_library(&mut self) -> Result<LibraryResponse, IBroadcastError> {
-        let mut params = HashMap::new();
-        params.insert("mode".to_string(), "getlibrary".to_string());
-        params.insert("token".to_string(), self.token.as_ref().ok_or(IBroadcastError::NotLoggedIn)?.clone());
-        self.make_request::<LibraryResponse>(params).await
+    pub async fn get_stream_url(&self, track_id: &str) -> Result<PlaybackResponse, IBroadcastError> {
+        self.make_request(request::Stream { track_id }).await
     }
 
-    #[allow(dead_code)]
-    pub async fn get_stream_url(&mut self, track_id: &str) -> Result<PlaybackResponse, IBroadcastError> {
-        let mut params = HashMap::new();
-        params.insert("mode".to_string(), "stream".to_string());
-        params.insert("token".to_string(), self.token.as_ref().ok_or(IBroadcastError::NotLoggedIn)?.to_string());
-        params.insert("id".to_string(), track_id.to_string());
-
-        self.make_request::<PlaybackResponse>(params).await
+    pub async fn search(&self, query: &str) -> Result<serde_json::Value, IBroadcastError> {
+        self.make_request(request::Search { query }).await
     }
 
-    #[allow(dead_code)]
-    pub async fn search(&mut self, query: &str) -> Result<serde_json::Value, IBroadcastError> {
-        let mut params = HashMap::new();
-        params.insert("mode".to_string(), "search".to_string());
-        params.insert("token".to_string(), self.token.as_ref().ok_or(IBroadcastError::NotLoggedIn)?.to_string());
-        params.insert("query".to_string(), query.to_string());
-
-        self.make_request::<serde_json::Value>(params).await
-    }
-
-    #[allow(dead_code)]
-    pub async fn create_playlist(&mut self, name: &str) -> Result<(), IBroadcastError> {
-        let mut params = HashMap::new();
-        params.insert("mode".to_string(), "createplaylist".to_string());
-        params.insert("token".to_string(), self.token.as_ref().ok_or(IBroadcastError::NotLoggedIn)?.clone());
-        params.insert("name".to_string(), name.to_string());
-        self.make_request::<serde_json::Value>(params).await?;
-        Ok(())
+    /// Creates an empty playlist and returns its id
+    pub async fn create_playlist(&self, name: &str) -> Result<u64, IBroadcastError> {
+        let response = self.make_request(request::CreatePlaylist { name }).await?;
+        // The id comes back as either a number or a string
+        match &response["playlist_id"] {
+            serde_json::Value::Number(id) => id.as_u64(),
+            serde_json::Value::String(id) => id.parse().ok(),
+            _ => None,
+        }
+        .ok_or_else(|| IBroadcastError::InvalidResponse("Missing playlist_id".to_string()))
     }
 
-    #[allow(dead_code)]
-    pub async fn add_to_playlist(&mut self, playlist_id: &str, media_id: &str) -> Result<(), IBroadcastError> {
-        let mut params = HashMap::new();
-        params.insert("mode".to_string(), "addtoplaylist".to_string());
-        params.insert("token".to_string(), self.token.as_ref().ok_or(IBroadcastError::NotLoggedIn)?.clone());
-        params.insert("playlist_id".to_string(), playlist_id.to_string());
-        params.insert("media_id".to_string(), media_id.to_string());
-        self.make_request::<serde_json::Value>(params).await?;
+    pub async fn add_to_playlist(&self, playlist_id: &str, media_id: &str) -> Result<(), IBroadcastError> {
+        self.make_request(request::AddToPlaylist { playlist_id, media_id }).await?;
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub async fn remove_from_playlist(&mut self, playlist_id: &str, media_id: &str) -> Result<(), IBroadcastError> {
-        let mut params = HashMap::new();
-        params.insert("mode".to_string(), "removefromplaylist".to_string());
-        params.insert("token".to_string(), self.token.as_ref().ok_or(IBroadcastError::NotLoggedIn)?.clone());
-        params.insert("playlist_id".to_string(), playlist_id.to_string());
-        params.insert("media_id".to_string(), media_id.to_string());
-        self.make_request::<serde_json::Value>(params).await?;
+    pub async fn remove_from_playlist(&self, playlist_id: &str, media_id: &str) -> Result<(), IBroadcastError> {
+        self.make_request(request::RemoveFromPlaylist { playlist_id, media_id }).await?;
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub async fn delete_playlist(&mut self, playlist_id: &str) -> Result<serde_json::Value, IBroadcastError> {
-        let mut params = HashMap::new();
-        params.insert("mode".to_string(), "deleteplaylist".to_string());
-        params.insert("token".to_string(), self.token.as_ref().ok_or(IBroadcastError::NotLoggedIn)?.to_string());
-        params.insert("playlist_id".to_string(), playlist_id.to_string());
-
-        self.make_request::<serde_json::Value>(params).await
+    pub async fn delete_playlist(&self, playlist_id: &str) -> Result<serde_json::Value, IBroadcastError> {
+        self.make_request(request::DeletePlaylist { playlist_id }).await
     }
 
-    #[allow(dead_code)]
-    pub async fn get_playback_status(&mut self) -> Result<serde_json::Value, IBroadcastError> {
-        let mut params = HashMap::new();
-        params.insert("mode".to_string(), "getplaybackstatus".to_string());
-        params.insert("token".to_string(), self.token.as_ref().ok_or(IBroadcastError::NotLoggedIn)?.to_string());
-
-        self.make_request::<serde_json::Value>(params).await
+    pub async fn get_playback_status(&self) -> Result<serde_json::Value, IBroadcastError> {
+        self.make_request(request::GetPlaybackStatus).await
     }
 
-    #[allow(dead_code)]
-    pub async fn get_playback(&mut self) -> Result<PlaybackResponse, IBroadcastError> {
-        let mut params = HashMap::new();
-        params.insert("mode".to_string(), "getplayback".to_string());
-        params.insert("token".to_string(), self.token.as_ref().ok_or(IBroadcastError::NotLoggedIn)?.clone());
-        self.make_request::<PlaybackResponse>(params).await
+    pub async fn get_playback(&self) -> Result<PlaybackResponse, IBroadcastError> {
+        self.make_request(request::GetPlayback).await
     }
 
-    #[allow(dead_code)]
-    pub async fn play(&mut self, media_id: &str) -> Result<(), IBroadcastError> {
-        let mut params = HashMap::new();
-        params.insert("mode".to_string(), "play".to_string());
-        params.insert("token".to_string(), self.token.as_ref().ok_or(IBroadcastError::NotLoggedIn)?.clone());
-        params.insert("media_id".to_string(), media_id.to_string());
-        self.make_request::<serde_json::Value>(params).await?;
+    pub async fn play(&self, media_id: &str) -> Result<(), IBroadcastError> {
+        self.make_request(request::Play { media_id }).await?;
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub async fn get_playlists(&mut self) -> Result<PlaylistResponse, IBroadcastError> {
-        let mut params = HashMap::new();
-        params.insert("mode".to_string(), "getplaylists".to_string());
-        params.insert("token".to_string(), self.token.as_ref().ok_or(IBroadcastError::NotLoggedIn)?.clone());
-        self.make_request::<PlaylistResponse>(params).await
+    pub async fn get_playlists(&self) -> Result<PlaylistResponse, IBroadcastError> {
+        self.make_request(request::GetPlaylists).await
     }
 
     /// Initiates device code authentication flow
-    pub async fn get_device_code(&mut self) -> Result<DeviceCodeResponse, IBroadcastError> {
-        let mut params = HashMap::new();
-        params.insert("mode".to_string(), "getdevicecode".to_string());
-        params.insert("app".to_string(), "Latke".to_string());
-        params.insert("version".to_string(), env!("CARGO_PKG_VERSION").to_string());
-        params.insert("device".to_string(), "desktop".to_string());
-        params.insert("client".to_string(), "Latke Desktop Client".to_string());
-
-        self.make_request::<DeviceCodeResponse>(params).await
+    pub async fn get_device_code(&self) -> Result<DeviceCodeResponse, IBroadcastError> {
+        self.make_request(request::GetDeviceCode).await
     }
 
     /// Polls for device code authentication completion
-    pub async fn poll_device_code(&mut self, device_code: &str) -> Result<DeviceCodeResponse, IBroadcastError> {
-        let mut params = HashMap::new();
-        params.insert("mode".to_string(), "polldevicecode".to_string());
-        params.insert("device_code".to_string(), device_code.to_string());
-        params.insert("app".to_string(), "Latke".to_string());
-        params.insert("version".to_string(), env!("CARGO_PKG_VERSION").to_string());
-        params.insert("device".to_string(), "desktop".to_string());
-        params.insert("client".to_string(), "Latke Desktop Client".to_string());
-
-        // Log request parameters
-        log::debug!("Poll device code request parameters: {:?}", params);
+    pub async fn poll_device_code(&self, device_code: &str) -> Result<DeviceCodeResponse, IBroadcastError> {
+        self.check_online()?;
+        let request = request::PollDeviceCode { device_code };
+        let params = Params::build(&request, None);
 
-        // Make the request and get the raw response first
-        let response = self
-            .client
-            .post(API_BASE_URL)
-            .form(&params)
-            .send()
-            .await?;
+        log::debug!("Poll device code request parameters: {:?}", redact::params(&params));
 
-        // Log response status and headers
-        log::debug!("Response status: {}", response.status());
-        log::debug!("Response headers: {:?}", response.headers());
-
-        // Log the response text for debugging
-        let response_text = response.text().await?;
-        log::debug!("Poll device code response: {}", response_text);
+        // Make the request and get the raw response first
+        let response_text = self.cancellable(self.send_raw::<request::PollDeviceCode>(&params)).await?;
+        log::debug!("Poll device code response: {}", redact::body(&response_text));
 
         // Parse the response
         let response: DeviceCodeResponse = serde_json::from_str(&response_text).map_err(|e| {
@@ -424,10 +574,16 @@ impl IBroadcastClient {
 
         if response.authenticated && response.result {
             if let Some(token) = response.token.clone() {
-                self.token = Some(token);
+                let mut credentials = self.credentials_mut();
+                credentials.token = Some(token);
                 if let Some(user) = response.user.clone() {
-                    self.user_id = Some(user.id);
+                    credentials.user_id = Some(user.id);
                 }
+                credentials.token_expires = response
+                    .expires
+                    .map(|expires| SystemTime::now() + Duration::from_secs(expires as u64));
+                drop(credentials);
+                self.session_started();
             }
         }
 
@@ -0,0 +1,184 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+/// Requests that can be made in a burst
+pub const BURST: u32 = 60;
+/// Sustained request rate, one per second like the old fixed window
+pub const REFILL_PER_SEC: f64 = 1.0;
+/// Tokens only playback-critical requests may use, so background work like
+/// library sync can't leave playback waiting
+pub const PLAYBACK_RESERVE: u32 = 10;
+
+/// Token cost of a request. Heavier calls cost more so a burst of them
+/// doesn't crowd out everything else.
+fn weight(mode: &str) -> f64 {
+    match mode {
+        "getlibrary" => 10.0,
+        "search" => 2.0,
+        _ => 1.0,
+    }
+}
+
+/// Requests that playback or the session depend on; they may dip into the
+/// reserve
+fn is_critical(mode: &str) -> bool {
+    matches!(
+        mode,
+        "stream" | "play" | "getplayback" | "getplaybackstatus" | "login" | "refresh"
+    )
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Token bucket shared by all clones of a client. Requests wait for
+/// capacity instead of failing.
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    capacity: f64,
+    refill_per_sec: f64,
+    reserve: f64,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: u32, refill_per_sec: f64, reserve: u32) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                tokens: capacity as f64,
+                refilled: Instant::now(),
+            }),
+            capacity: capacity as f64,
+            refill_per_sec,
+            reserve: reserve.min(capacity) as f64,
+        }
+    }
+
+    /// Waits until a request of the given mode may be sent, then takes its
+    /// tokens
+    pub async fn acquire(&self, mode: &str) {
+        let floor = if is_critical(mode) { 0.0 } else { self.reserve };
+        // More than the bucket can ever hold above the floor would wait forever
+        let cost = weight(mode).min(self.capacity - floor);
+
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = Instant::now();
+                let refilled = (now - bucket.refilled).as_secs_f64() * self.refill_per_sec;
+                bucket.tokens = (bucket.tokens + refilled).min(self.capacity);
+                bucket.refilled = now;
+
+                if bucket.tokens - cost >= floor {
+                    bucket.tokens -= cost;
+                    return;
+                }
+                Duration::from_secs_f64((floor + cost - bucket.tokens) / self.refill_per_sec)
+            };
+            log::debug!("Rate limited, waiting {:?} before {}", wait, mode);
+            sleep(wait).await;
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(BURST, REFILL_PER_SEC, PLAYBACK_RESERVE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Time taken to acquire `count` requests of `mode`, one after another
+    async fn time_to_acquire(limiter: &RateLimiter, mode: &str, count: u32) -> Duration {
+        let started = Instant::now();
+        for _ in 0..count {
+            limiter.acquire(mode).await;
+        }
+        started.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_burst_goes_through_at_once_then_refills_steadily() {
+        let limiter = RateLimiter::new(5, 2.0, 0);
+
+        assert_eq!(
+            time_to_acquire(&limiter, "getplaylists", 5).await,
+            Duration::ZERO
+        );
+        assert_eq!(
+            time_to_acquire(&limiter, "getplaylists", 4).await,
+            Duration::from_secs(2)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_time_refills_up_to_the_burst() {
+        let limiter = RateLimiter::new(5, 1.0, 0);
+        time_to_acquire(&limiter, "getplaylists", 5).await;
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+
+        assert_eq!(
+            time_to_acquire(&limiter, "getplaylists", 5).await,
+            Duration::ZERO
+        );
+        assert_eq!(
+            time_to_acquire(&limiter, "getplaylists", 1).await,
+            Duration::from_secs(1)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_past_the_limit_wait_instead_of_failing() {
+        let limiter = RateLimiter::default();
+        let unreserved = BURST - PLAYBACK_RESERVE;
+
+        let elapsed = time_to_acquire(&limiter, "getplaylists", unreserved + 20).await;
+
+        assert_eq!(elapsed, Duration::from_secs_f64(20.0 / REFILL_PER_SEC));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn library_syncs_leave_the_reserve_to_playback() {
+        let limiter = std::sync::Arc::new(RateLimiter::default());
+        let syncs = (BURST - PLAYBACK_RESERVE) / weight("getlibrary") as u32;
+        assert_eq!(
+            time_to_acquire(&limiter, "getlibrary", syncs).await,
+            Duration::ZERO
+        );
+
+        // Another sync has to wait for the bucket to refill...
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { time_to_acquire(&limiter, "getlibrary", 1).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        // ...while playback still gets through right away
+        assert_eq!(
+            time_to_acquire(&limiter, "stream", PLAYBACK_RESERVE).await,
+            Duration::ZERO
+        );
+        assert!(waiting.await.unwrap() > Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn heavier_requests_cost_more() {
+        let limiter = RateLimiter::new(20, 1.0, 0);
+
+        assert_eq!(
+            time_to_acquire(&limiter, "getlibrary", 2).await,
+            Duration::ZERO
+        );
+        assert_eq!(
+            time_to_acquire(&limiter, "search", 1).await,
+            Duration::from_secs(2)
+        );
+    }
+}
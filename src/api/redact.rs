@@ -0,0 +1,145 @@
+//! Masks secrets before request and response details are logged
+
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use super::request::Params;
+
+/// Parameters and response fields whose values never appear in logs
+const SECRET_KEYS: &[&str] = &["token", "password", "device_code", "email"];
+const MASK: &str = "[redacted]";
+
+fn is_secret(key: &str) -> bool {
+    SECRET_KEYS.contains(&key.to_ascii_lowercase().as_str())
+}
+
+/// Request parameters with secrets masked, sorted by name
+pub fn params(params: &Params) -> BTreeMap<&str, &str> {
+    params
+        .iter()
+        .map(|(key, value)| {
+            let value = if is_secret(key) { MASK } else { value };
+            (key, value)
+        })
+        .collect()
+}
+
+/// A response body with secrets masked. Bodies that aren't JSON are only
+/// logged by size, as there is no telling what they contain.
+pub fn body(text: &str) -> String {
+    match serde_json::from_str::<Value>(text) {
+        Ok(mut value) => {
+            mask_value(&mut value);
+            value.to_string()
+        }
+        Err(_) => format!("<{} bytes, not JSON>", text.len()),
+    }
+}
+
+fn mask_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if is_secret(key) && !value.is_null() {
+                    *value = Value::String(MASK.to_string());
+                } else {
+                    mask_value(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(mask_value),
+        // Signed URLs can carry credentials in their query
+        Value::String(text) if text.starts_with("http") && text.contains('?') => {
+            if let Some((url, _)) = text.split_once('?') {
+                *text = format!("{}?{}", url, MASK);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// An email address reduced to its first letter and domain, enough to tell
+/// accounts apart in a log. Anything that isn't an address is kept as is.
+pub fn email(text: &str) -> String {
+    match text.split_once('@') {
+        Some((user, domain)) => {
+            let first = user.chars().next().map(String::from).unwrap_or_default();
+            format!("{}***@{}", first, domain)
+        }
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn secret_params_are_masked() {
+        let mut request = Params::default();
+        request.add("mode", "login");
+        request.add("email", "listener@example.com");
+        request.add("password", "hunter2");
+        request.add("token", "abc123");
+        request.add("device_code", "XYZ");
+
+        let logged = params(&request);
+
+        assert_eq!(logged["mode"], "login");
+        for key in ["email", "password", "token", "device_code"] {
+            assert_eq!(logged[key], MASK, "{} was logged", key);
+        }
+    }
+
+    #[test]
+    fn secrets_in_nested_bodies_are_masked() {
+        let response = json!({
+            "result": true,
+            "token": "abc123",
+            "user": { "id": "42", "Token": "def456", "expires": null },
+            "sessions": [{ "device_code": "XYZ", "name": "Desktop" }],
+            "playback": {
+                "url": "https://streams.example.com/1.mp3?Expires=1&Signature=secret",
+                "server": "https://streams.example.com/",
+                "title": "Song? Yes",
+            },
+        });
+
+        let logged: Value = serde_json::from_str(&body(&response.to_string())).unwrap();
+
+        assert_eq!(
+            logged,
+            json!({
+                "result": true,
+                "token": MASK,
+                "user": { "id": "42", "Token": MASK, "expires": null },
+                "sessions": [{ "device_code": MASK, "name": "Desktop" }],
+                "playback": {
+                    "url": format!("https://streams.example.com/1.mp3?{}", MASK),
+                    "server": "https://streams.example.com/",
+                    "title": "Song? Yes",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn missing_secrets_stay_missing() {
+        let logged: Value = serde_json::from_str(&body(r#"{"token":null}"#)).unwrap();
+
+        assert_eq!(logged, json!({ "token": null }));
+    }
+
+    #[test]
+    fn bodies_that_are_not_json_are_only_measured() {
+        assert_eq!(body("token=abc123"), "<12 bytes, not JSON>");
+    }
+
+    #[test]
+    fn emails_keep_only_the_first_letter_and_domain() {
+        assert_eq!(email("listener@example.com"), "l***@example.com");
+        assert_eq!(email("@example.com"), "***@example.com");
+        assert_eq!(email("device-user"), "device-user");
+    }
+}
@@ -0,0 +1,266 @@
+//! Typed API requests. Each mode declares its parameters, what it answers
+//! with, whether it needs a session and whether it may be sent twice.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::{
+    DeviceCodeResponse, LibraryResponse, LoginResponse, PlaybackResponse, PlaylistResponse,
+};
+
+/// How Latke identifies itself; sent with every request
+const CLIENT_FIELDS: [(&str, &str); 4] = [
+    ("app", "Latke"),
+    ("version", env!("CARGO_PKG_VERSION")),
+    ("device", "desktop"),
+    ("client", "Latke Desktop Client"),
+];
+
+/// A call to one API mode
+pub trait Request {
+    /// Sent as the `mode` parameter
+    const MODE: &'static str;
+
+    /// Whether the request carries the session's token. Such requests wait
+    /// for a refresh or a new login when the token won't do.
+    const AUTHENTICATED: bool = true;
+
+    /// Whether sending the request twice has the same effect as sending it
+    /// once. Other requests are only retried when they can't have reached
+    /// the server, so a timed-out mutation is never applied twice.
+    const IDEMPOTENT: bool = true;
+
+    type Response: DeserializeOwned;
+
+    /// Adds the mode's own parameters
+    fn params(&self, params: &mut Params);
+}
+
+/// Form parameters of a request, in the order they were added
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(transparent)]
+pub struct Params(Vec<(&'static str, String)>);
+
+impl Params {
+    /// The full parameters for `request`: its mode, the client fields, the
+    /// token if given, and its own
+    pub fn build<R: Request>(request: &R, token: Option<&str>) -> Self {
+        let mut params = Self::default();
+        params.add("mode", R::MODE);
+        for (name, value) in CLIENT_FIELDS {
+            params.add(name, value);
+        }
+        if let Some(token) = token {
+            params.add("token", token);
+        }
+        request.params(&mut params);
+        params
+    }
+
+    pub fn add(&mut self, name: &'static str, value: impl Into<String>) {
+        self.0.push((name, value.into()));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(name, value)| (*name, value.as_str()))
+    }
+}
+
+pub struct Login<'a> {
+    pub email: &'a str,
+    pub password: &'a str,
+}
+
+impl Request for Login<'_> {
+    const MODE: &'static str = "login";
+    const AUTHENTICATED: bool = false;
+    type Response = LoginResponse;
+
+    fn params(&self, params: &mut Params) {
+        params.add("email", self.email);
+        params.add("password", self.password);
+    }
+}
+
+/// Trades the current token for a fresh one
+pub struct Refresh;
+
+impl Request for Refresh {
+    const MODE: &'static str = "refresh";
+    type Response = LoginResponse;
+
+    fn params(&self, _params: &mut Params) {}
+}
+
+/// Revokes the token sent with it
+pub struct Logout;
+
+impl Request for Logout {
+    const MODE: &'static str = "logout";
+    type Response = serde_json::Value;
+
+    fn params(&self, _params: &mut Params) {}
+}
+
+pub struct GetDeviceCode;
+
+impl Request for GetDeviceCode {
+    const MODE: &'static str = "getdevicecode";
+    const AUTHENTICATED: bool = false;
+    type Response = DeviceCodeResponse;
+
+    fn params(&self, _params: &mut Params) {}
+}
+
+pub struct PollDeviceCode<'a> {
+    pub device_code: &'a str,
+}
+
+impl Request for PollDeviceCode<'_> {
+    const MODE: &'static str = "polldevicecode";
+    const AUTHENTICATED: bool = false;
+    type Response = DeviceCodeResponse;
+
+    fn params(&self, params: &mut Params) {
+        params.add("device_code", self.device_code);
+    }
+}
+
+pub struct GetLibrary;
+
+impl Request for GetLibrary {
+    const MODE: &'static str = "getlibrary";
+    type Response = LibraryResponse;
+
+    fn params(&self, _params: &mut Params) {}
+}
+
+pub struct Stream<'a> {
+    pub track_id: &'a str,
+}
+
+impl Request for Stream<'_> {
+    const MODE: &'static str = "stream";
+    type Response = PlaybackResponse;
+
+    fn params(&self, params: &mut Params) {
+        params.add("id", self.track_id);
+    }
+}
+
+pub struct Search<'a> {
+    pub query: &'a str,
+}
+
+impl Request for Search<'_> {
+    const MODE: &'static str = "search";
+    type Response = serde_json::Value;
+
+    fn params(&self, params: &mut Params) {
+        params.add("query", self.query);
+    }
+}
+
+pub struct CreatePlaylist<'a> {
+    pub name: &'a str,
+}
+
+impl Request for CreatePlaylist<'_> {
+    const MODE: &'static str = "createplaylist";
+    // A second one would make another playlist
+    const IDEMPOTENT: bool = false;
+    type Response = serde_json::Value;
+
+    fn params(&self, params: &mut Params) {
+        params.add("name", self.name);
+    }
+}
+
+pub struct AddToPlaylist<'a> {
+    pub playlist_id: &'a str,
+    pub media_id: &'a str,
+}
+
+impl Request for AddToPlaylist<'_> {
+    const MODE: &'static str = "addtoplaylist";
+    // Playlists may hold a track more than once
+    const IDEMPOTENT: bool = false;
+    type Response = serde_json::Value;
+
+    fn params(&self, params: &mut Params) {
+        params.add("playlist_id", self.playlist_id);
+        params.add("media_id", self.media_id);
+    }
+}
+
+pub struct RemoveFromPlaylist<'a> {
+    pub playlist_id: &'a str,
+    pub media_id: &'a str,
+}
+
+impl Request for RemoveFromPlaylist<'_> {
+    const MODE: &'static str = "removefromplaylist";
+    type Response = serde_json::Value;
+
+    fn params(&self, params: &mut Params) {
+        params.add("playlist_id", self.playlist_id);
+        params.add("media_id", self.media_id);
+    }
+}
+
+pub struct DeletePlaylist<'a> {
+    pub playlist_id: &'a str,
+}
+
+impl Request for DeletePlaylist<'_> {
+    const MODE: &'static str = "deleteplaylist";
+    // A repeat fails once the playlist is gone
+    const IDEMPOTENT: bool = false;
+    type Response = serde_json::Value;
+
+    fn params(&self, params: &mut Params) {
+        params.add("playlist_id", self.playlist_id);
+    }
+}
+
+pub struct GetPlaybackStatus;
+
+impl Request for GetPlaybackStatus {
+    const MODE: &'static str = "getplaybackstatus";
+    type Response = serde_json::Value;
+
+    fn params(&self, _params: &mut Params) {}
+}
+
+pub struct GetPlayback;
+
+impl Request for GetPlayback {
+    const MODE: &'static str = "getplayback";
+    type Response = PlaybackResponse;
+
+    fn params(&self, _params: &mut Params) {}
+}
+
+pub struct Play<'a> {
+    pub media_id: &'a str,
+}
+
+impl Request for Play<'_> {
+    const MODE: &'static str = "play";
+    // Each one counts as another play
+    const IDEMPOTENT: bool = false;
+    type Response = serde_json::Value;
+
+    fn params(&self, params: &mut Params) {
+        params.add("media_id", self.media_id);
+    }
+}
+
+pub struct GetPlaylists;
+
+impl Request for GetPlaylists {
+    const MODE: &'static str = "getplaylists";
+    type Response = PlaylistResponse;
+
+    fn params(&self, _params: &mut Params) {}
+}
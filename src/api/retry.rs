@@ -0,0 +1,171 @@
+use rand::Rng;
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+
+/// How failed API calls are retried: exponential backoff with full jitter,
+/// Retry-After taking precedence, all within an overall deadline
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// Backoff cap for the first retry; doubles with each one after
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Time allowed for a call across all attempts and waits
+    pub deadline: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            deadline: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `attempt` (counting from 0), or `None` if
+    /// the call should give up. A Retry-After from the server replaces the
+    /// backoff, but not the deadline.
+    pub fn next_delay(
+        &self,
+        attempt: u32,
+        started: Instant,
+        retry_after: Option<Duration>,
+    ) -> Option<Duration> {
+        if attempt >= self.max_retries {
+            return None;
+        }
+        let delay = retry_after.unwrap_or_else(|| self.backoff(attempt));
+        if started.elapsed() + delay > self.deadline {
+            return None;
+        }
+        Some(delay)
+    }
+
+    /// A random delay between zero and the exponential backoff cap, so
+    /// clients that failed together don't retry together
+    fn backoff(&self, attempt: u32) -> Duration {
+        let cap = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        rand::thread_rng().gen_range(Duration::ZERO..=cap)
+    }
+}
+
+/// Parses a Retry-After header, given either in seconds or as an HTTP date
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let at = httpdate::parse_http_date(value).ok()?;
+    Some(at.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 6,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            deadline: Duration::from_secs(10),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_stays_under_its_cap() {
+        let policy = policy();
+        let started = Instant::now();
+
+        for attempt in 0..policy.max_retries {
+            let cap = (policy.base_delay * 2u32.pow(attempt)).min(policy.max_delay);
+            for _ in 0..100 {
+                let delay = policy.next_delay(attempt, started, None).unwrap();
+                assert!(
+                    delay <= cap,
+                    "{:?} over {:?} on attempt {}",
+                    delay,
+                    cap,
+                    attempt
+                );
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_the_last_retry() {
+        let policy = policy();
+        let started = Instant::now();
+
+        assert_eq!(policy.next_delay(policy.max_retries, started, None), None);
+        assert_eq!(
+            policy.next_delay(policy.max_retries, started, Some(Duration::ZERO)),
+            None
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_after_replaces_the_backoff_within_the_deadline() {
+        let policy = policy();
+        let started = Instant::now();
+
+        let long_wait = Duration::from_secs(5);
+        assert_eq!(
+            policy.next_delay(0, started, Some(long_wait)),
+            Some(long_wait)
+        );
+
+        tokio::time::advance(Duration::from_secs(7)).await;
+        assert_eq!(policy.next_delay(1, started, Some(long_wait)), None);
+        assert_eq!(
+            policy.next_delay(1, started, Some(Duration::from_secs(2))),
+            Some(Duration::from_secs(2))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_retry_once_the_deadline_has_passed() {
+        let policy = policy();
+        let started = Instant::now();
+
+        tokio::time::advance(policy.deadline).await;
+
+        assert_eq!(
+            policy.next_delay(0, started, Some(Duration::ZERO)),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            policy.next_delay(0, started, Some(Duration::from_millis(1))),
+            None
+        );
+    }
+
+    #[test]
+    fn retry_after_in_seconds() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("soon"), None);
+        assert_eq!(parse_retry_after("-5"), None);
+    }
+
+    #[test]
+    fn retry_after_as_an_http_date() {
+        let at = SystemTime::now() + Duration::from_secs(90);
+        let delay = parse_retry_after(&httpdate::fmt_http_date(at)).unwrap();
+        // The date has whole seconds only
+        assert!(delay > Duration::from_secs(88) && delay <= Duration::from_secs(90));
+
+        let past = SystemTime::now() - Duration::from_secs(90);
+        assert_eq!(
+            parse_retry_after(&httpdate::fmt_http_date(past)),
+            Some(Duration::ZERO)
+        );
+    }
+}
@@ -0,0 +1,394 @@
+use futures::future::{BoxFuture, FutureExt, Shared};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Notify;
+use tokio::time::{sleep, timeout};
+
+use super::request::{self, Params, Request};
+use super::{
+    Credentials, ErrorResponse, IBroadcastClient, IBroadcastError, LoginResponse,
+    TOKEN_REFRESH_THRESHOLD,
+};
+
+/// How long the background refresher waits after a refresh that failed for
+/// a reason that may pass, e.g. no network
+const REFRESH_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// How long logging out waits for the server to revoke the token
+const REVOKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// The token was replaced by a refresh
+    Refreshed,
+    /// The server no longer accepts the session; the user has to log in again
+    Lost(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum SessionState {
+    Active,
+    /// Lost, and requests wait for the user to log in again
+    AwaitingLogin,
+    /// Lost for good; requests fail until the next login
+    Lost(String),
+}
+
+/// Result of a refresh, shared by everyone waiting on it
+#[derive(Debug, Clone)]
+pub(super) enum RefreshOutcome {
+    Refreshed,
+    /// The server turned the token down
+    Rejected(String),
+    /// The refresh didn't get an answer; the token may still be good
+    Failed(String),
+}
+
+/// The refresh in flight, if any
+pub(super) type PendingRefresh = Shared<BoxFuture<'static, RefreshOutcome>>;
+
+impl IBroadcastClient {
+    /// Refreshes the token if it is close to expiring. A refresh that fails
+    /// for lack of an answer is only an error once the token has expired.
+    pub(super) async fn ensure_valid_token(&self) -> Result<(), IBroadcastError> {
+        if !self.token_needs_refresh() {
+            return Ok(());
+        }
+        match self.refresh().await {
+            RefreshOutcome::Refreshed => Ok(()),
+            RefreshOutcome::Rejected(message) => Err(IBroadcastError::Authentication(message)),
+            RefreshOutcome::Failed(message) if self.token_expired() => {
+                Err(IBroadcastError::TokenRefresh(message))
+            }
+            RefreshOutcome::Failed(message) => {
+                log::warn!("Token refresh failed, using the current token: {}", message);
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether the token expires within `TOKEN_REFRESH_THRESHOLD`
+    fn token_needs_refresh(&self) -> bool {
+        self.credentials()
+            .token_expires
+            .is_some_and(|expires| SystemTime::now() + TOKEN_REFRESH_THRESHOLD > expires)
+    }
+
+    fn token_expired(&self) -> bool {
+        self.credentials()
+            .token_expires
+            .is_some_and(|expires| SystemTime::now() >= expires)
+    }
+
+    /// Joins the refresh in flight, or starts one. Only one is ever sent at a
+    /// time, and it runs in its own task, so cancelling a request that waits
+    /// on it can't lose the new token.
+    fn refresh(&self) -> PendingRefresh {
+        let mut pending = self.shared.pending_refresh.lock().unwrap();
+        if let Some(refresh) = &*pending {
+            return refresh.clone();
+        }
+
+        let client = Self::from_shared(self.shared.clone());
+        let task = tokio::spawn(async move {
+            let outcome = client.send_refresh().await;
+            // Blocks until the slot below is filled, then frees it
+            client.shared.pending_refresh.lock().unwrap().take();
+            outcome
+        });
+        let refresh = async move {
+            task.await
+                .unwrap_or_else(|e| RefreshOutcome::Failed(format!("Refresh task failed: {}", e)))
+        }
+        .boxed()
+        .shared();
+        *pending = Some(refresh.clone());
+        refresh
+    }
+
+    /// Sends the refresh request itself, bypassing `make_request` so that it
+    /// never waits on a refresh of its own
+    async fn send_refresh(&self) -> RefreshOutcome {
+        let Ok(token) = self.token() else {
+            return RefreshOutcome::Rejected("Not logged in".to_string());
+        };
+        if let Err(e) = self.check_online() {
+            return RefreshOutcome::Failed(e.to_string());
+        }
+
+        let params = Params::build(&request::Refresh, Some(&token));
+
+        log::debug!("Refreshing token");
+        self.shared.limiter.acquire(request::Refresh::MODE).await;
+        let response = match self
+            .shared
+            .http
+            .post(&self.shared.base_url)
+            .form(&params)
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => return RefreshOutcome::Failed(e.to_string()),
+        };
+        let status = response.status();
+        let response = if status.is_success() {
+            response.json::<LoginResponse>().await
+        } else if matches!(status.as_u16(), 401 | 403) {
+            // Turned down outright rather than answered with a failed refresh
+            let message = match response.json::<ErrorResponse>().await {
+                Ok(error) => error.message,
+                Err(_) => "Session expired".to_string(),
+            };
+            Ok(LoginResponse {
+                message,
+                authenticated: false,
+                result: false,
+                token: None,
+                user: None,
+                expires: None,
+            })
+        } else {
+            return RefreshOutcome::Failed(format!("Server returned {}", status));
+        };
+        let outcome = match response {
+            Ok(response) if response.authenticated && response.result => match response.token {
+                Some(new_token) => {
+                    let mut credentials = self.credentials_mut();
+                    // A login while the refresh was out takes precedence
+                    if credentials.token.as_deref() == Some(token.as_str()) {
+                        credentials.token = Some(new_token);
+                        credentials.token_expires = response
+                            .expires
+                            .map(|expires| SystemTime::now() + Duration::from_secs(expires as u64));
+                    }
+                    RefreshOutcome::Refreshed
+                }
+                None => {
+                    let message = "No token received during refresh".to_string();
+                    self.session_lost(&token, &message);
+                    RefreshOutcome::Rejected(message)
+                }
+            },
+            Ok(response) => {
+                self.session_lost(&token, &response.message);
+                RefreshOutcome::Rejected(response.message)
+            }
+            Err(e) => RefreshOutcome::Failed(format!("Failed to parse response: {}", e)),
+        };
+
+        match &outcome {
+            RefreshOutcome::Refreshed => {
+                log::info!("Token refreshed");
+                self.notify_session_changed();
+                let _ = self.shared.session_events.send(SessionEvent::Refreshed);
+            }
+            RefreshOutcome::Rejected(_) => {}
+            RefreshOutcome::Failed(message) => log::warn!("Token refresh failed: {}", message),
+        }
+        outcome
+    }
+
+    /// Marks the session as usable again after a login or restore, letting
+    /// held requests go. Requests held for another account's session stay
+    /// held, for the caller to abandon.
+    pub(super) fn session_started(&self) {
+        let user_id = self.credentials().user_id.clone();
+        let lost_user_id = self.shared.lost_user_id.lock().unwrap().clone();
+        let other_account = matches!(
+            (&lost_user_id, &user_id),
+            (Some(lost), Some(new)) if lost != new
+        );
+        self.shared.session_state.send_if_modified(|state| {
+            if *state == SessionState::AwaitingLogin && other_account {
+                log::warn!("Logged in to another account; not releasing held requests");
+                return false;
+            }
+            *state = SessionState::Active;
+            true
+        });
+        self.notify_session_changed();
+    }
+
+    /// Marks the session as lost, unless `token` has been replaced since it
+    /// was turned down. Publishes `SessionEvent::Lost` once per session.
+    pub(super) fn session_lost(&self, token: &str, reason: &str) {
+        if self.credentials().token.as_deref() != Some(token) {
+            return;
+        }
+        let awaiting = self.shared.reauthenticate.load(Ordering::Relaxed);
+        let lost = self.shared.session_state.send_if_modified(|state| {
+            if *state != SessionState::Active {
+                return false;
+            }
+            *state = if awaiting {
+                SessionState::AwaitingLogin
+            } else {
+                SessionState::Lost(reason.to_string())
+            };
+            true
+        });
+        if lost {
+            if awaiting {
+                *self.shared.lost_user_id.lock().unwrap() = self.credentials().user_id.clone();
+            }
+            log::warn!("Session lost: {}", reason);
+            let _ = self
+                .shared
+                .session_events
+                .send(SessionEvent::Lost(reason.to_string()));
+        }
+    }
+
+    /// Waits while a new login is expected. Fails if the session is lost
+    /// and nobody is logging in again.
+    pub(super) async fn session_ready(&self) -> Result<(), IBroadcastError> {
+        let mut state = self.shared.session_state.subscribe();
+        let state = state
+            .wait_for(|state| *state != SessionState::AwaitingLogin)
+            .await
+            .map(|state| state.clone())
+            // The sender lives as long as `self`
+            .unwrap_or(SessionState::Active);
+        match state {
+            SessionState::Lost(reason) => Err(IBroadcastError::Authentication(reason)),
+            _ => Ok(()),
+        }
+    }
+
+    pub(super) fn awaiting_login(&self) -> bool {
+        *self.shared.session_state.borrow() == SessionState::AwaitingLogin
+    }
+
+    /// Sets whether requests that lose the session wait for the user to log
+    /// in again, then go ahead as if nothing happened. Whoever turns this on
+    /// should offer a login on `SessionEvent::Lost` and call
+    /// `abandon_reauthentication` if the user declines.
+    pub fn set_reauthenticate(&self, reauthenticate: bool) {
+        self.shared
+            .reauthenticate
+            .store(reauthenticate, Ordering::Relaxed);
+        if !reauthenticate {
+            self.abandon_reauthentication();
+        }
+    }
+
+    /// Whether requests wait for a new login when the session is lost
+    pub fn reauthenticates(&self) -> bool {
+        self.shared.reauthenticate.load(Ordering::Relaxed)
+    }
+
+    /// Fails the requests held for a new login
+    pub fn abandon_reauthentication(&self) {
+        self.shared.session_state.send_if_modified(|state| {
+            if *state != SessionState::AwaitingLogin {
+                return false;
+            }
+            log::info!("Not logging in again, failing held requests");
+            *state = SessionState::Lost("Session expired".to_string());
+            true
+        });
+    }
+
+    /// Ends the session. The server is asked to revoke the token, but the
+    /// session ends here whether it answers or not; a token it never heard
+    /// about simply runs out. Requests still waiting fail as not logged in.
+    pub async fn logout(&self) {
+        // Nobody will log in again for held requests
+        self.shared.reauthenticate.store(false, Ordering::Relaxed);
+        if let Ok(token) = self.token() {
+            match timeout(REVOKE_TIMEOUT, self.revoke_token(token)).await {
+                Ok(Ok(())) => log::info!("Token revoked"),
+                // Already turned down, so there is nothing left to revoke
+                Ok(Err(IBroadcastError::Authentication(_))) => {}
+                Ok(Err(e)) => log::warn!("Could not revoke the token: {}", e),
+                Err(_) => log::warn!("Could not revoke the token: the server didn't answer"),
+            }
+        }
+
+        self.forget_session();
+        log::info!("Logged out");
+    }
+
+    /// Ends the session here without revoking it, e.g. to switch to another
+    /// account. The stored session stays good for switching back later.
+    pub fn forget_session(&self) {
+        self.shared.reauthenticate.store(false, Ordering::Relaxed);
+        *self.credentials_mut() = Credentials::default();
+        self.shared
+            .session_state
+            .send_replace(SessionState::Lost("Logged out".to_string()));
+        self.notify_session_changed();
+    }
+
+    /// Asks the server to stop accepting `token`. Sent as is, since neither
+    /// a refresh nor a held login make sense for a token being given up.
+    async fn revoke_token(&self, token: String) -> Result<(), IBroadcastError> {
+        self.check_online()?;
+        let params = Params::build(&request::Logout, Some(&token));
+
+        self.send_with_retries::<request::Logout>(&params)
+            .await
+            .map(|_| ())
+    }
+
+    /// Starts a task that refreshes the token ahead of expiry, so requests
+    /// don't have to wait for it. The task ends once every handle to this
+    /// client is dropped.
+    pub fn start_token_refresher(&self) {
+        let shared = Arc::downgrade(&self.shared);
+        let changed = self.shared.session_changed.clone();
+        tokio::spawn(refresh_ahead(shared, changed));
+    }
+}
+
+async fn refresh_ahead(shared: Weak<super::Shared>, changed: Arc<Notify>) {
+    loop {
+        // Registered before reading the expiry, so a login in between isn't missed
+        let notified = changed.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        let Some(client) = shared.upgrade().map(IBroadcastClient::from_shared) else {
+            return;
+        };
+        let due = client.credentials().token_expires.map(|expires| {
+            expires
+                .checked_sub(TOKEN_REFRESH_THRESHOLD)
+                .unwrap_or(expires)
+        });
+        let Some(due) = due else {
+            drop(client);
+            // Logged out or no expiry known; wait for a new session
+            notified.await;
+            continue;
+        };
+
+        let wait = due.duration_since(SystemTime::now()).unwrap_or_default();
+        if !wait.is_zero() {
+            drop(client);
+            tokio::select! {
+                _ = sleep(wait) => {}
+                _ = &mut notified => {}
+            }
+            continue;
+        }
+
+        match client.refresh().await {
+            RefreshOutcome::Refreshed => {}
+            RefreshOutcome::Rejected(_) => {
+                drop(client);
+                // Nothing to do until the user logs in again
+                notified.await;
+            }
+            RefreshOutcome::Failed(_) => {
+                drop(client);
+                tokio::select! {
+                    _ = sleep(REFRESH_RETRY_DELAY) => {}
+                    _ = &mut notified => {}
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,465 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::player::StreamTee;
+use crate::profiles::Profile;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    file: String,
+    size: u64,
+    /// Value of `Index::clock` when the entry was last played
+    last_used: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index {
+    entries: BTreeMap<u64, CacheEntry>,
+    clock: u64,
+}
+
+struct CacheState {
+    index: Index,
+    quota: u64,
+}
+
+/// Recently streamed tracks, kept on disk up to a quota and evicted least
+/// recently played first.
+///
+/// Unlike downloads, nothing here is pinned; any entry may go when space is
+/// needed.
+#[derive(Clone)]
+pub struct StreamCache {
+    dir: PathBuf,
+    state: Arc<Mutex<CacheState>>,
+}
+
+impl StreamCache {
+    /// Default location of the profile's stream cache
+    pub fn default_dir(profile: &Profile) -> PathBuf {
+        profile.cache_dir().join("stream")
+    }
+
+    /// Opens the cache, dropping entries whose files are gone and partial
+    /// files left behind by an earlier run
+    pub fn open(dir: PathBuf, quota: u64) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let mut index: Index = match std::fs::read_to_string(dir.join("index.json")) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Index::default(),
+            Err(e) => return Err(e.into()),
+        };
+        index
+            .entries
+            .retain(|_, entry| dir.join(&entry.file).exists());
+
+        for file in std::fs::read_dir(&dir)? {
+            let path = file?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "part")
+            {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+
+        let cache = Self {
+            dir,
+            state: Arc::new(Mutex::new(CacheState { index, quota })),
+        };
+        cache.evict();
+        Ok(cache)
+    }
+
+    /// Changes the quota, evicting right away if the cache is now too big
+    pub fn set_quota(&self, quota: u64) {
+        self.state.lock().unwrap().quota = quota;
+        self.evict();
+    }
+
+    /// Bytes currently used by cached tracks
+    pub fn usage(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.index.entries.values().map(|entry| entry.size).sum()
+    }
+
+    /// Path of the cached copy of a track, marking it as recently played
+    pub fn lookup(&self, track_id: u64) -> Option<PathBuf> {
+        let path = {
+            let mut state = self.state.lock().unwrap();
+            state.index.clock += 1;
+            let clock = state.index.clock;
+            let entry = state.index.entries.get_mut(&track_id)?;
+            entry.last_used = clock;
+            self.dir.join(&entry.file)
+        };
+        if !path.exists() {
+            self.remove(track_id);
+            return None;
+        }
+        self.save_index();
+        Some(path)
+    }
+
+    /// Starts caching a stream, or `None` if caching is turned off
+    pub fn writer(&self, track_id: u64) -> Option<CacheWriter> {
+        if self.state.lock().unwrap().quota == 0 {
+            return None;
+        }
+        // A unique name, since the same track may be streaming twice
+        let part = self
+            .dir
+            .join(format!("{}.{:08x}.part", track_id, rand::random::<u32>()));
+        match File::create(&part) {
+            Ok(file) => Some(CacheWriter {
+                cache: self.clone(),
+                track_id,
+                part,
+                file: Some(file),
+                contiguous: 0,
+            }),
+            Err(e) => {
+                log::warn!("Failed to create stream cache file: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Deletes every cached track
+    pub fn clear(&self) {
+        let ids: Vec<u64> = {
+            let state = self.state.lock().unwrap();
+            state.index.entries.keys().copied().collect()
+        };
+        for track_id in ids {
+            self.remove(track_id);
+        }
+    }
+
+    /// Moves a complete stream into the cache
+    fn insert(&self, track_id: u64, part: &Path, size: u64) -> Result<()> {
+        let file = track_id.to_string();
+        std::fs::rename(part, self.dir.join(&file))?;
+        {
+            let mut state = self.state.lock().unwrap();
+            state.index.clock += 1;
+            let last_used = state.index.clock;
+            state.index.entries.insert(
+                track_id,
+                CacheEntry {
+                    file,
+                    size,
+                    last_used,
+                },
+            );
+        }
+        log::debug!("Cached stream of track {} ({} bytes)", track_id, size);
+        self.evict();
+        Ok(())
+    }
+
+    fn remove(&self, track_id: u64) {
+        let entry = self.state.lock().unwrap().index.entries.remove(&track_id);
+        if let Some(entry) = entry {
+            let _ = std::fs::remove_file(self.dir.join(&entry.file));
+        }
+        self.save_index();
+    }
+
+    /// Drops least recently played entries until the cache fits its quota
+    fn evict(&self) {
+        let evicted: Vec<CacheEntry> = {
+            let mut state = self.state.lock().unwrap();
+            let mut usage: u64 = state.index.entries.values().map(|entry| entry.size).sum();
+            let mut by_age: Vec<(u64, u64)> = state
+                .index
+                .entries
+                .iter()
+                .map(|(id, entry)| (entry.last_used, *id))
+                .collect();
+            by_age.sort_unstable();
+
+            let mut evicted = Vec::new();
+            for (_, track_id) in by_age {
+                if usage <= state.quota {
+                    break;
+                }
+                if let Some(entry) = state.index.entries.remove(&track_id) {
+                    usage -= entry.size;
+                    evicted.push(entry);
+                }
+            }
+            evicted
+        };
+
+        for entry in &evicted {
+            if let Err(e) = std::fs::remove_file(self.dir.join(&entry.file)) {
+                log::warn!("Failed to evict {}: {}", entry.file, e);
+            }
+        }
+        if !evicted.is_empty() {
+            log::debug!("Evicted {} tracks from the stream cache", evicted.len());
+        }
+        self.save_index();
+    }
+
+    fn save_index(&self) {
+        // Holding the lock while writing keeps concurrent saves from interleaving
+        let state = self.state.lock().unwrap();
+        let result = (|| -> Result<()> {
+            let json = serde_json::to_vec(&state.index)?;
+            let path = self.dir.join("index.json");
+            let temp = path.with_extension("json.tmp");
+            std::fs::write(&temp, json)?;
+            std::fs::rename(&temp, &path)?;
+            Ok(())
+        })();
+        if let Err(e) = result {
+            log::warn!("Failed to save stream cache index: {}", e);
+        }
+    }
+}
+
+/// Writes a stream into a partial cache file as the player fetches it.
+///
+/// Only a stream seen from start to end without gaps becomes a cache entry;
+/// seeking past what has been written gives up on caching this play.
+pub struct CacheWriter {
+    cache: StreamCache,
+    track_id: u64,
+    part: PathBuf,
+    /// `None` once finished or given up
+    file: Option<File>,
+    /// Bytes written without gaps from the start of the stream
+    contiguous: u64,
+}
+
+impl CacheWriter {
+    fn give_up(&mut self) {
+        if self.file.take().is_some() {
+            let _ = std::fs::remove_file(&self.part);
+        }
+    }
+}
+
+impl StreamTee for CacheWriter {
+    fn write_at(&mut self, offset: u64, data: &[u8]) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        if offset > self.contiguous {
+            log::debug!("Seeked past the cached part of track {}", self.track_id);
+            self.give_up();
+            return;
+        }
+        let written = file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| file.write_all(data));
+        match written {
+            Ok(()) => self.contiguous = self.contiguous.max(offset + data.len() as u64),
+            Err(e) => {
+                log::warn!("Failed to write stream cache: {}", e);
+                self.give_up();
+            }
+        }
+    }
+
+    fn finish(mut self: Box<Self>, size: u64) {
+        let Some(file) = self.file.take() else {
+            return;
+        };
+        let complete = self.contiguous == size && file.sync_all().is_ok();
+        drop(file);
+        if !complete {
+            let _ = std::fs::remove_file(&self.part);
+            return;
+        }
+        if let Err(e) = self.cache.insert(self.track_id, &self.part, size) {
+            log::warn!("Failed to cache track {}: {}", self.track_id, e);
+            let _ = std::fs::remove_file(&self.part);
+        }
+    }
+}
+
+impl Drop for CacheWriter {
+    fn drop(&mut self) {
+        self.give_up();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Streams `data` through a writer in chunks, as the player would
+    fn stream(cache: &StreamCache, track_id: u64, data: &[u8]) {
+        let mut writer = cache.writer(track_id).unwrap();
+        for (i, chunk) in data.chunks(64).enumerate() {
+            writer.write_at(i as u64 * 64, chunk);
+        }
+        Box::new(writer).finish(data.len() as u64);
+    }
+
+    fn part_files(dir: &TempDir) -> Vec<PathBuf> {
+        std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|file| file.unwrap().path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "part")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn complete_streams_are_cached() {
+        let dir = TempDir::new().unwrap();
+        let cache = StreamCache::open(dir.path().to_path_buf(), 1000).unwrap();
+        let data: Vec<u8> = (0..200).collect();
+
+        let mut writer = cache.writer(1).unwrap();
+        writer.write_at(0, &data[..150]);
+        // Seeking back over what was written keeps the stream whole
+        writer.write_at(50, &data[50..100]);
+        writer.write_at(150, &data[150..]);
+        Box::new(writer).finish(data.len() as u64);
+
+        let path = cache.lookup(1).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), data);
+        assert_eq!(cache.usage(), 200);
+        assert!(part_files(&dir).is_empty());
+    }
+
+    #[test]
+    fn seeking_past_the_written_part_gives_up() {
+        let dir = TempDir::new().unwrap();
+        let cache = StreamCache::open(dir.path().to_path_buf(), 1000).unwrap();
+        let data: Vec<u8> = (0..200).collect();
+
+        let mut writer = cache.writer(1).unwrap();
+        writer.write_at(0, &data[..50]);
+        writer.write_at(100, &data[100..]);
+        assert!(part_files(&dir).is_empty());
+        // Filling the gap afterwards doesn't bring it back
+        writer.write_at(50, &data[50..100]);
+        Box::new(writer).finish(data.len() as u64);
+
+        assert_eq!(cache.lookup(1), None);
+        assert_eq!(cache.usage(), 0);
+    }
+
+    #[test]
+    fn streams_that_stop_early_are_not_cached() {
+        let dir = TempDir::new().unwrap();
+        let cache = StreamCache::open(dir.path().to_path_buf(), 1000).unwrap();
+        let data: Vec<u8> = (0..200).collect();
+
+        let mut writer = cache.writer(1).unwrap();
+        writer.write_at(0, &data[..150]);
+        Box::new(writer).finish(data.len() as u64);
+
+        assert_eq!(cache.lookup(1), None);
+        assert!(part_files(&dir).is_empty());
+    }
+
+    #[test]
+    fn dropped_writers_leave_nothing_behind() {
+        let dir = TempDir::new().unwrap();
+        let cache = StreamCache::open(dir.path().to_path_buf(), 1000).unwrap();
+
+        let mut writer = cache.writer(1).unwrap();
+        writer.write_at(0, &[1; 100]);
+        drop(writer);
+
+        assert!(part_files(&dir).is_empty());
+        assert_eq!(cache.lookup(1), None);
+    }
+
+    #[test]
+    fn caching_is_off_without_a_quota() {
+        let dir = TempDir::new().unwrap();
+        let cache = StreamCache::open(dir.path().to_path_buf(), 0).unwrap();
+
+        assert!(cache.writer(1).is_none());
+    }
+
+    #[test]
+    fn least_recently_played_tracks_are_evicted_first() {
+        let dir = TempDir::new().unwrap();
+        let cache = StreamCache::open(dir.path().to_path_buf(), 300).unwrap();
+        for track_id in 1..=3 {
+            stream(&cache, track_id, &[track_id as u8; 100]);
+        }
+        // Played again, so track 2 is now the oldest
+        assert!(cache.lookup(1).is_some());
+
+        cache.set_quota(200);
+        assert_eq!(cache.usage(), 200);
+        assert!(!dir.path().join("2").exists());
+        assert_eq!(cache.lookup(2), None);
+
+        cache.set_quota(100);
+        assert_eq!(cache.usage(), 100);
+        assert!(!dir.path().join("3").exists());
+        assert!(cache.lookup(1).is_some());
+    }
+
+    #[test]
+    fn caching_past_the_quota_evicts_the_oldest() {
+        let dir = TempDir::new().unwrap();
+        let cache = StreamCache::open(dir.path().to_path_buf(), 250).unwrap();
+        stream(&cache, 1, &[1; 100]);
+        stream(&cache, 2, &[2; 100]);
+
+        stream(&cache, 3, &[3; 100]);
+
+        assert_eq!(cache.lookup(1), None);
+        assert!(cache.lookup(2).is_some());
+        assert!(cache.lookup(3).is_some());
+    }
+
+    #[test]
+    fn opening_cleans_up_after_an_earlier_run() {
+        let dir = TempDir::new().unwrap();
+        {
+            let cache = StreamCache::open(dir.path().to_path_buf(), 1000).unwrap();
+            stream(&cache, 1, &[1; 100]);
+            stream(&cache, 2, &[2; 100]);
+            // Interrupted mid-stream without unwinding
+            let mut writer = cache.writer(3).unwrap();
+            writer.write_at(0, &[3; 50]);
+            std::mem::forget(writer);
+        }
+        std::fs::remove_file(dir.path().join("2")).unwrap();
+        assert_eq!(part_files(&dir).len(), 1);
+
+        let cache = StreamCache::open(dir.path().to_path_buf(), 1000).unwrap();
+
+        assert!(part_files(&dir).is_empty());
+        assert_eq!(cache.usage(), 100);
+        assert!(cache.lookup(1).is_some());
+        assert_eq!(cache.lookup(2), None);
+    }
+
+    #[test]
+    fn opening_with_a_smaller_quota_evicts() {
+        let dir = TempDir::new().unwrap();
+        {
+            let cache = StreamCache::open(dir.path().to_path_buf(), 1000).unwrap();
+            stream(&cache, 1, &[1; 100]);
+            stream(&cache, 2, &[2; 100]);
+        }
+
+        let cache = StreamCache::open(dir.path().to_path_buf(), 150).unwrap();
+
+        assert_eq!(cache.usage(), 100);
+        assert_eq!(cache.lookup(1), None);
+        assert!(cache.lookup(2).is_some());
+    }
+}
@@ -0,0 +1,264 @@
+use anyhow::{anyhow, bail, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::CredentialStore;
+use crate::utils;
+
+const FORMAT_VERSION: u32 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// Secrets by service, then username
+type Secrets = BTreeMap<String, BTreeMap<String, String>>;
+
+/// The file as stored. The secrets are encrypted as a whole, with a fresh
+/// nonce on every write.
+#[derive(Serialize, Deserialize)]
+struct SealedFile {
+    version: u32,
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+struct Unlocked {
+    cipher: XChaCha20Poly1305,
+    salt: Vec<u8>,
+    secrets: Secrets,
+}
+
+/// Secrets kept in a file, encrypted with XChaCha20-Poly1305 under a key
+/// derived from a passphrase with Argon2id. It has to be unlocked before
+/// use; until then every lookup fails.
+pub struct FileStore {
+    path: PathBuf,
+    unlocked: Mutex<Option<Unlocked>>,
+}
+
+impl FileStore {
+    /// Default location of the encrypted file, shared by all profiles
+    pub fn default_path() -> PathBuf {
+        utils::data_dir().join("secrets.json")
+    }
+
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            unlocked: Mutex::new(None),
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Opens the file with `passphrase`, creating an empty one if there is
+    /// none yet. Deriving the key is slow on purpose, so call this off the
+    /// UI thread.
+    pub fn unlock(&self, passphrase: &str) -> Result<()> {
+        let unlocked = match std::fs::read(&self.path) {
+            Ok(data) => {
+                let sealed: SealedFile = serde_json::from_slice(&data)?;
+                if sealed.version != FORMAT_VERSION {
+                    bail!("Unsupported credential file version {}", sealed.version);
+                }
+                if sealed.nonce.len() != NONCE_LEN {
+                    bail!("Credential file is damaged");
+                }
+                let cipher = derive_cipher(passphrase, &sealed.salt)?;
+                let plaintext = cipher
+                    .decrypt(
+                        XNonce::from_slice(&sealed.nonce),
+                        sealed.ciphertext.as_ref(),
+                    )
+                    .map_err(|_| anyhow!("Wrong passphrase"))?;
+                Unlocked {
+                    cipher,
+                    salt: sealed.salt,
+                    secrets: serde_json::from_slice(&plaintext)?,
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let salt = rand::random::<[u8; SALT_LEN]>().to_vec();
+                let unlocked = Unlocked {
+                    cipher: derive_cipher(passphrase, &salt)?,
+                    salt,
+                    secrets: Secrets::new(),
+                };
+                // Written right away so the passphrase is the one asked for
+                // next time, even if nothing gets stored
+                self.write(&unlocked, &unlocked.secrets)?;
+                log::info!("Created credential file");
+                unlocked
+            }
+            Err(e) => return Err(e.into()),
+        };
+        *self.unlocked.lock().unwrap() = Some(unlocked);
+        Ok(())
+    }
+
+    /// Locks the store and deletes the file, e.g. once its secrets have
+    /// moved to the keyring
+    pub fn remove(&self) -> Result<()> {
+        *self.unlocked.lock().unwrap() = None;
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => {
+                log::info!("Removed credential file");
+                Ok(())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Runs `f` on the unlocked contents, failing if the store is locked
+    fn with_unlocked<T>(&self, f: impl FnOnce(&mut Unlocked) -> Result<T>) -> Result<T> {
+        let mut unlocked = self.unlocked.lock().unwrap();
+        let unlocked = unlocked
+            .as_mut()
+            .ok_or_else(|| anyhow!("Credential file is locked"))?;
+        f(unlocked)
+    }
+
+    /// Encrypts `secrets` and replaces the file with them
+    fn write(&self, unlocked: &Unlocked, secrets: &Secrets) -> Result<()> {
+        let nonce = rand::random::<[u8; NONCE_LEN]>();
+        let plaintext = serde_json::to_vec(secrets)?;
+        let ciphertext = unlocked
+            .cipher
+            .encrypt(XNonce::from_slice(&nonce), plaintext.as_ref())
+            .map_err(|_| anyhow!("Failed to encrypt credentials"))?;
+        let sealed = SealedFile {
+            version: FORMAT_VERSION,
+            salt: unlocked.salt.clone(),
+            nonce: nonce.to_vec(),
+            ciphertext,
+        };
+
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temp = self.path.with_extension("json.tmp");
+        write_private(&temp, &serde_json::to_vec(&sealed)?)?;
+        std::fs::rename(&temp, &self.path)?;
+        Ok(())
+    }
+}
+
+impl CredentialStore for FileStore {
+    fn get(&self, service: &str, user: &str) -> Result<Option<String>> {
+        self.with_unlocked(|unlocked| {
+            let secret = unlocked
+                .secrets
+                .get(service)
+                .and_then(|users| users.get(user));
+            Ok(secret.cloned())
+        })
+    }
+
+    fn set(&self, service: &str, user: &str, secret: &str) -> Result<()> {
+        self.with_unlocked(|unlocked| {
+            // Only kept once it is on disk
+            let mut secrets = unlocked.secrets.clone();
+            secrets
+                .entry(service.to_string())
+                .or_default()
+                .insert(user.to_string(), secret.to_string());
+            self.write(unlocked, &secrets)?;
+            unlocked.secrets = secrets;
+            Ok(())
+        })
+    }
+
+    fn delete(&self, service: &str, user: &str) -> Result<()> {
+        self.with_unlocked(|unlocked| {
+            let mut secrets = unlocked.secrets.clone();
+            let Some(users) = secrets.get_mut(service) else {
+                return Ok(());
+            };
+            if users.remove(user).is_none() {
+                return Ok(());
+            }
+            if users.is_empty() {
+                secrets.remove(service);
+            }
+            self.write(unlocked, &secrets)?;
+            unlocked.secrets = secrets;
+            Ok(())
+        })
+    }
+}
+
+fn derive_cipher(passphrase: &str, salt: &[u8]) -> Result<XChaCha20Poly1305> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("Failed to derive key: {}", e))?;
+    Ok(XChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+/// Writes a file only the user can read
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn secrets_survive_reopening_the_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("secrets.json");
+        let store = FileStore::new(path.clone());
+        assert!(store.get("service", "user").is_err());
+
+        store.unlock("correct horse").unwrap();
+        assert!(store.exists());
+        store.set("service", "user", "secret").unwrap();
+        store.set("service", "other", "gone").unwrap();
+        store.delete("service", "other").unwrap();
+
+        let reopened = FileStore::new(path.clone());
+        reopened.unlock("correct horse").unwrap();
+        let secret = reopened.get("service", "user").unwrap();
+        let deleted = reopened.get("service", "other").unwrap();
+        reopened.remove().unwrap();
+
+        assert_eq!(secret.as_deref(), Some("secret"));
+        assert_eq!(deleted, None);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("secrets.json");
+        let store = FileStore::new(path.clone());
+        store.unlock("correct horse").unwrap();
+        store.set("service", "user", "secret").unwrap();
+
+        let reopened = FileStore::new(path);
+        let result = reopened.unlock("battery staple");
+        let lookup = reopened.get("service", "user");
+
+        assert_eq!(result.unwrap_err().to_string(), "Wrong passphrase");
+        assert!(lookup.is_err());
+    }
+}
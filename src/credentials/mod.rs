@@ -0,0 +1,103 @@
+//! Where Latke keeps its secrets: the system keyring where there is one,
+//! otherwise a file encrypted with a passphrase
+
+use anyhow::Result;
+use keyring::Entry;
+use std::sync::{Arc, RwLock};
+
+use crate::utils::KEYRING_SERVICE;
+
+mod file;
+
+pub use file::FileStore;
+
+/// Keyring username looked up to find out whether the keyring works
+const PROBE_USER: &str = "probe";
+
+/// A place to keep secrets, by service and username
+pub trait CredentialStore: Send + Sync {
+    /// Returns the secret, or `None` if there is none
+    fn get(&self, service: &str, user: &str) -> Result<Option<String>>;
+
+    fn set(&self, service: &str, user: &str, secret: &str) -> Result<()>;
+
+    /// Removes the secret; removing one that isn't there is not an error
+    fn delete(&self, service: &str, user: &str) -> Result<()>;
+}
+
+/// The system keyring: Secret Service, the macOS Keychain or the Windows
+/// Credential Manager
+pub struct KeyringStore;
+
+impl KeyringStore {
+    /// Whether the keyring can be used. Looking up an entry that doesn't
+    /// exist fails differently from having no keyring to ask.
+    pub fn is_available() -> bool {
+        match Entry::new(KEYRING_SERVICE, PROBE_USER).and_then(|entry| entry.get_password()) {
+            Ok(_) | Err(keyring::Error::NoEntry) => true,
+            Err(e) => {
+                log::info!("Keyring unavailable: {}", e);
+                false
+            }
+        }
+    }
+}
+
+impl CredentialStore for KeyringStore {
+    fn get(&self, service: &str, user: &str) -> Result<Option<String>> {
+        match Entry::new(service, user)?.get_password() {
+            Ok(secret) => Ok(Some(secret)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set(&self, service: &str, user: &str, secret: &str) -> Result<()> {
+        Entry::new(service, user)?.set_password(secret)?;
+        Ok(())
+    }
+
+    fn delete(&self, service: &str, user: &str) -> Result<()> {
+        match Entry::new(service, user)?.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Which store Latke keeps its secrets in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Keyring,
+    File,
+}
+
+/// Picks the keyring if it works and the encrypted file otherwise. The
+/// `LATKE_CREDENTIAL_STORE` environment variable (`keyring` or `file`)
+/// overrides the choice.
+pub fn detect() -> Backend {
+    match std::env::var("LATKE_CREDENTIAL_STORE").as_deref() {
+        Ok("keyring") => return Backend::Keyring,
+        Ok("file") => return Backend::File,
+        Ok(other) => log::warn!("Unknown LATKE_CREDENTIAL_STORE value: {}", other),
+        Err(_) => {}
+    }
+    if KeyringStore::is_available() {
+        Backend::Keyring
+    } else {
+        Backend::File
+    }
+}
+
+static STORE: RwLock<Option<Arc<dyn CredentialStore>>> = RwLock::new(None);
+
+/// The store in use: the keyring, unless `set_store` picked another
+pub fn store() -> Arc<dyn CredentialStore> {
+    let store = STORE.read().unwrap().clone();
+    store.unwrap_or_else(|| Arc::new(KeyringStore))
+}
+
+/// Keeps secrets in `store` from now on
+pub fn set_store(store: Arc<dyn CredentialStore>) {
+    *STORE.write().unwrap() = Some(store);
+}
@@ -0,0 +1,651 @@
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::api::{Album, Artist, Library, Playlist, Tag, Track, Trash};
+use crate::profiles::Profile;
+use crate::sync::{ItemKind, LibraryChange, LibraryItem};
+
+/// Bumped whenever `SCHEMA` changes; older caches are rebuilt from scratch
+const SCHEMA_VERSION: i32 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE tracks (
+        id INTEGER PRIMARY KEY,
+        title TEXT NOT NULL,
+        track_number INTEGER,
+        year INTEGER,
+        genre TEXT,
+        length INTEGER,
+        album_id INTEGER,
+        artist_id INTEGER,
+        artwork_id INTEGER,
+        plays INTEGER NOT NULL,
+        rating INTEGER NOT NULL,
+        size INTEGER,
+        file TEXT,
+        path TEXT,
+        uploaded_on TEXT,
+        trashed INTEGER NOT NULL
+    );
+    CREATE INDEX tracks_artist ON tracks (artist_id);
+    CREATE INDEX tracks_album ON tracks (album_id);
+    CREATE INDEX tracks_title ON tracks (title COLLATE NOCASE);
+    CREATE INDEX tracks_genre ON tracks (genre COLLATE NOCASE);
+
+    CREATE TABLE albums (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        artist_id INTEGER,
+        tracks TEXT NOT NULL,
+        disc INTEGER,
+        year INTEGER,
+        rating INTEGER NOT NULL,
+        trashed INTEGER NOT NULL
+    );
+    CREATE INDEX albums_name ON albums (name COLLATE NOCASE);
+    CREATE INDEX albums_artist ON albums (artist_id);
+
+    CREATE TABLE artists (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        tracks TEXT NOT NULL,
+        artwork_id INTEGER,
+        rating INTEGER NOT NULL,
+        trashed INTEGER NOT NULL
+    );
+    CREATE INDEX artists_name ON artists (name COLLATE NOCASE);
+
+    CREATE TABLE playlists (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        description TEXT,
+        tracks TEXT NOT NULL,
+        artwork_id INTEGER,
+        public_id TEXT,
+        kind TEXT,
+        system_created INTEGER NOT NULL
+    );
+
+    CREATE TABLE tags (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        tracks TEXT NOT NULL,
+        archived INTEGER NOT NULL
+    );
+
+    CREATE TABLE trash (
+        track_id INTEGER PRIMARY KEY
+    );
+
+    CREATE TABLE meta (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+";
+
+const LAST_SYNCED_KEY: &str = "last_synced";
+
+/// On-disk copy of the library, so the browser can open without the network.
+///
+/// Id lists (album, artist, playlist and tag tracks) are stored as JSON
+/// arrays since they are only ever read back whole.
+pub struct LibraryCache {
+    conn: Connection,
+}
+
+impl LibraryCache {
+    /// Default location of the profile's library database
+    pub fn default_path(profile: &Profile) -> PathBuf {
+        profile.data_dir().join("library.db")
+    }
+
+    /// Opens (creating if needed) the cache at `path`
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Self::init(Connection::open(path)?)
+    }
+
+    /// Deletes the cache at `path` along with its write-ahead log, e.g. when
+    /// the user logs out and wipes offline data
+    pub fn remove(path: &Path) -> Result<()> {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.as_os_str().to_owned();
+            file.push(suffix);
+            match std::fs::remove_file(&file) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Opens a throwaway cache that lives only in memory
+    #[allow(dead_code)]
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+
+        let version: i32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version != SCHEMA_VERSION {
+            // The cache can always be refetched, so there is nothing to migrate
+            log::info!(
+                "Rebuilding library cache (schema {} -> {})",
+                version,
+                SCHEMA_VERSION
+            );
+            let tables: Vec<String> = conn
+                .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'")?
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            for table in tables {
+                conn.execute_batch(&format!("DROP TABLE IF EXISTS \"{}\"", table))?;
+            }
+            conn.execute_batch(SCHEMA)?;
+            conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        }
+
+        Ok(Self { conn })
+    }
+
+    /// When the cache was last synced with the server, if ever
+    pub fn last_synced(&self) -> Result<Option<SystemTime>> {
+        let value: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT value FROM meta WHERE key = ?1",
+                [LAST_SYNCED_KEY],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(value
+            .and_then(|value| value.parse::<u64>().ok())
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)))
+    }
+
+    /// Replaces the cached library with `library`
+    pub fn store_library(&mut self, library: &Library) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute_batch(
+            "DELETE FROM tracks; DELETE FROM albums; DELETE FROM artists;
+             DELETE FROM playlists; DELETE FROM tags; DELETE FROM trash;",
+        )?;
+
+        for artist in library.artists.values() {
+            put_artist(&tx, artist)?;
+        }
+        for album in library.albums.values() {
+            put_album(&tx, album)?;
+        }
+        for track in library.tracks.values() {
+            put_track(&tx, track)?;
+        }
+        for playlist in library.playlists.values() {
+            put_playlist(&tx, playlist)?;
+        }
+        for tag in library.tags.values() {
+            put_tag(&tx, tag)?;
+        }
+        put_trash(&tx, &library.trash)?;
+
+        set_last_synced(&tx, SystemTime::now())?;
+        tx.commit()?;
+        log::info!(
+            "Stored {} tracks in the library cache",
+            library.tracks.len()
+        );
+        Ok(())
+    }
+
+    /// Applies the changes found by a sync and records the sync time, even
+    /// when there were no changes
+    pub fn apply_changes(&mut self, changes: &[LibraryChange]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        for change in changes {
+            match change {
+                LibraryChange::Added(item) | LibraryChange::Changed(item) => put_item(&tx, item)?,
+                LibraryChange::Removed(kind, id) => {
+                    let table = match kind {
+                        ItemKind::Track => "tracks",
+                        ItemKind::Album => "albums",
+                        ItemKind::Artist => "artists",
+                        ItemKind::Playlist => "playlists",
+                        ItemKind::Tag => "tags",
+                    };
+                    tx.prepare_cached(&format!("DELETE FROM {} WHERE id = ?1", table))?
+                        .execute([id])?;
+                }
+                LibraryChange::TrashChanged(trash) => {
+                    tx.execute("DELETE FROM trash", [])?;
+                    put_trash(&tx, trash)?;
+                }
+            }
+        }
+        set_last_synced(&tx, SystemTime::now())?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Loads the cached library, or `None` if the cache was never filled
+    pub fn load_library(&self) -> Result<Option<Library>> {
+        if self.last_synced()?.is_none() {
+            return Ok(None);
+        }
+
+        let tracks = self.load_table("SELECT * FROM tracks", track_from_row, |track| track.id)?;
+        let albums = self.load_table("SELECT * FROM albums", album_from_row, |album| album.id)?;
+        let artists =
+            self.load_table("SELECT * FROM artists", artist_from_row, |artist| artist.id)?;
+        let playlists =
+            self.load_table("SELECT * FROM playlists", playlist_from_row, |playlist| {
+                playlist.id
+            })?;
+        let tags = self.load_table("SELECT * FROM tags", tag_from_row, |tag| tag.id)?;
+        let trash = self
+            .conn
+            .prepare("SELECT track_id FROM trash ORDER BY track_id")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<u64>, _>>()?;
+
+        Ok(Some(Library {
+            tracks,
+            albums,
+            artists,
+            playlists,
+            tags,
+            trash: Trash { tracks: trash },
+        }))
+    }
+
+    fn load_table<T>(
+        &self,
+        sql: &str,
+        from_row: fn(&Row) -> rusqlite::Result<T>,
+        id: fn(&T) -> u64,
+    ) -> Result<BTreeMap<u64, T>> {
+        let mut statement = self.conn.prepare(sql)?;
+        let rows = statement.query_map([], from_row)?;
+        let mut items = BTreeMap::new();
+        for item in rows {
+            let item = item?;
+            items.insert(id(&item), item);
+        }
+        Ok(items)
+    }
+}
+
+fn set_last_synced(conn: &Connection, at: SystemTime) -> Result<()> {
+    let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    conn.execute(
+        "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+        params![LAST_SYNCED_KEY, secs.to_string()],
+    )?;
+    Ok(())
+}
+
+fn put_track(conn: &Connection, track: &Track) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO tracks (id, title, track_number, year, genre, length, album_id,
+            artist_id, artwork_id, plays, rating, size, file, path, uploaded_on, trashed)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+    )?
+    .execute(params![
+        track.id,
+        track.title,
+        track.track_number,
+        track.year,
+        track.genre,
+        track.length,
+        track.album_id,
+        track.artist_id,
+        track.artwork_id,
+        track.plays,
+        track.rating,
+        track.size,
+        track.file,
+        track.path,
+        track.uploaded_on,
+        track.trashed,
+    ])?;
+    Ok(())
+}
+
+/// Inserts or replaces a single item
+fn put_item(conn: &Connection, item: &LibraryItem) -> Result<()> {
+    match item {
+        LibraryItem::Track(track) => put_track(conn, track),
+        LibraryItem::Album(album) => put_album(conn, album),
+        LibraryItem::Artist(artist) => put_artist(conn, artist),
+        LibraryItem::Playlist(playlist) => put_playlist(conn, playlist),
+        LibraryItem::Tag(tag) => put_tag(conn, tag),
+    }
+}
+
+fn put_album(conn: &Connection, album: &Album) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO albums (id, name, artist_id, tracks, disc, year, rating, trashed)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    )?
+    .execute(params![
+        album.id,
+        album.name,
+        album.artist_id,
+        serde_json::to_string(&album.tracks)?,
+        album.disc,
+        album.year,
+        album.rating,
+        album.trashed,
+    ])?;
+    Ok(())
+}
+
+fn put_artist(conn: &Connection, artist: &Artist) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO artists (id, name, tracks, artwork_id, rating, trashed)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?
+    .execute(params![
+        artist.id,
+        artist.name,
+        serde_json::to_string(&artist.tracks)?,
+        artist.artwork_id,
+        artist.rating,
+        artist.trashed,
+    ])?;
+    Ok(())
+}
+
+fn put_playlist(conn: &Connection, playlist: &Playlist) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO playlists (id, name, description, tracks, artwork_id,
+            public_id, kind, system_created)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    )?
+    .execute(params![
+        playlist.id,
+        playlist.name,
+        playlist.description,
+        serde_json::to_string(&playlist.tracks)?,
+        playlist.artwork_id,
+        playlist.public_id,
+        playlist.kind,
+        playlist.system_created,
+    ])?;
+    Ok(())
+}
+
+fn put_tag(conn: &Connection, tag: &Tag) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO tags (id, name, tracks, archived) VALUES (?1, ?2, ?3, ?4)",
+    )?
+    .execute(params![
+        tag.id,
+        tag.name,
+        serde_json::to_string(&tag.tracks)?,
+        tag.archived,
+    ])?;
+    Ok(())
+}
+
+fn put_trash(conn: &Connection, trash: &Trash) -> Result<()> {
+    let mut insert = conn.prepare_cached("INSERT OR IGNORE INTO trash (track_id) VALUES (?1)")?;
+    for track_id in &trash.tracks {
+        insert.execute([track_id])?;
+    }
+    Ok(())
+}
+
+fn ids_from_json(row: &Row, column: &str) -> rusqlite::Result<Vec<u64>> {
+    let json: String = row.get(column)?;
+    serde_json::from_str(&json).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+fn track_from_row(row: &Row) -> rusqlite::Result<Track> {
+    Ok(Track {
+        id: row.get("id")?,
+        title: row.get("title")?,
+        track_number: row.get("track_number")?,
+        year: row.get("year")?,
+        genre: row.get("genre")?,
+        length: row.get("length")?,
+        album_id: row.get("album_id")?,
+        artist_id: row.get("artist_id")?,
+        artwork_id: row.get("artwork_id")?,
+        plays: row.get("plays")?,
+        rating: row.get("rating")?,
+        size: row.get("size")?,
+        file: row.get("file")?,
+        path: row.get("path")?,
+        uploaded_on: row.get("uploaded_on")?,
+        trashed: row.get("trashed")?,
+    })
+}
+
+fn album_from_row(row: &Row) -> rusqlite::Result<Album> {
+    Ok(Album {
+        id: row.get("id")?,
+        name: row.get("name")?,
+        artist_id: row.get("artist_id")?,
+        tracks: ids_from_json(row, "tracks")?,
+        disc: row.get("disc")?,
+        year: row.get("year")?,
+        rating: row.get("rating")?,
+        trashed: row.get("trashed")?,
+    })
+}
+
+fn artist_from_row(row: &Row) -> rusqlite::Result<Artist> {
+    Ok(Artist {
+        id: row.get("id")?,
+        name: row.get("name")?,
+        tracks: ids_from_json(row, "tracks")?,
+        artwork_id: row.get("artwork_id")?,
+        rating: row.get("rating")?,
+        trashed: row.get("trashed")?,
+    })
+}
+
+fn playlist_from_row(row: &Row) -> rusqlite::Result<Playlist> {
+    Ok(Playlist {
+        id: row.get("id")?,
+        name: row.get("name")?,
+        description: row.get("description")?,
+        tracks: ids_from_json(row, "tracks")?,
+        artwork_id: row.get("artwork_id")?,
+        public_id: row.get("public_id")?,
+        kind: row.get("kind")?,
+        system_created: row.get("system_created")?,
+    })
+}
+
+fn tag_from_row(row: &Row) -> rusqlite::Result<Tag> {
+    Ok(Tag {
+        id: row.get("id")?,
+        name: row.get("name")?,
+        tracks: ids_from_json(row, "tracks")?,
+        archived: row.get("archived")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync;
+    use tempfile::TempDir;
+
+    fn library() -> Library {
+        let mut library = Library::default();
+        library.tracks.insert(
+            1,
+            Track {
+                id: 1,
+                title: "One".to_string(),
+                track_number: Some(1),
+                year: Some(1999),
+                genre: Some("Rock".to_string()),
+                length: Some(180),
+                album_id: Some(10),
+                artist_id: Some(30),
+                artwork_id: Some(5),
+                plays: 3,
+                rating: 4,
+                size: Some(4_000_000),
+                file: Some("/1.mp3".to_string()),
+                path: Some("Artist/Album".to_string()),
+                uploaded_on: Some("2024-01-01".to_string()),
+                trashed: false,
+            },
+        );
+        library.tracks.insert(
+            2,
+            Track {
+                id: 2,
+                title: "Two".to_string(),
+                trashed: true,
+                ..Default::default()
+            },
+        );
+        library.albums.insert(
+            10,
+            Album {
+                id: 10,
+                name: "Album".to_string(),
+                artist_id: Some(30),
+                tracks: vec![1, 2],
+                disc: Some(1),
+                year: Some(1999),
+                ..Default::default()
+            },
+        );
+        library.artists.insert(
+            30,
+            Artist {
+                id: 30,
+                name: "Artist".to_string(),
+                tracks: vec![1, 2],
+                ..Default::default()
+            },
+        );
+        library.playlists.insert(
+            20,
+            Playlist {
+                id: 20,
+                name: "Mix".to_string(),
+                description: Some("Favourites".to_string()),
+                tracks: vec![2, 1],
+                ..Default::default()
+            },
+        );
+        library.tags.insert(
+            40,
+            Tag {
+                id: 40,
+                name: "Loud".to_string(),
+                tracks: vec![1],
+                archived: true,
+            },
+        );
+        library.trash.tracks = vec![2];
+        library
+    }
+
+    fn assert_same(a: &Library, b: &Library) {
+        assert_eq!(a.tracks, b.tracks);
+        assert_eq!(a.albums, b.albums);
+        assert_eq!(a.artists, b.artists);
+        assert_eq!(a.playlists, b.playlists);
+        assert_eq!(a.tags, b.tags);
+        assert_eq!(a.trash, b.trash);
+    }
+
+    #[test]
+    fn new_cache_has_no_library() {
+        let cache = LibraryCache::open_in_memory().unwrap();
+
+        assert!(cache.last_synced().unwrap().is_none());
+        assert!(cache.load_library().unwrap().is_none());
+    }
+
+    #[test]
+    fn stored_library_loads_back() {
+        let mut cache = LibraryCache::open_in_memory().unwrap();
+        let before = SystemTime::now() - Duration::from_secs(1);
+
+        cache.store_library(&library()).unwrap();
+
+        assert_same(&cache.load_library().unwrap().unwrap(), &library());
+        assert!(cache.last_synced().unwrap().unwrap() >= before);
+    }
+
+    #[test]
+    fn changes_apply_to_single_items() {
+        let mut cache = LibraryCache::open_in_memory().unwrap();
+        cache.store_library(&library()).unwrap();
+        let mut new = library();
+        new.tracks.insert(
+            3,
+            Track {
+                id: 3,
+                title: "Three".to_string(),
+                ..Default::default()
+            },
+        );
+        new.albums.get_mut(&10).unwrap().tracks.push(3);
+        new.playlists.remove(&20);
+        new.tags.get_mut(&40).unwrap().archived = false;
+        new.trash.tracks.clear();
+
+        cache.apply_changes(&sync::diff(&library(), &new)).unwrap();
+
+        assert_same(&cache.load_library().unwrap().unwrap(), &new);
+    }
+
+    #[test]
+    fn a_sync_without_changes_still_counts() {
+        let mut cache = LibraryCache::open_in_memory().unwrap();
+
+        cache.apply_changes(&[]).unwrap();
+
+        assert!(cache.last_synced().unwrap().is_some());
+        let library = cache.load_library().unwrap().unwrap();
+        assert!(library.tracks.is_empty());
+    }
+
+    #[test]
+    fn cache_from_another_schema_is_rebuilt() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("library.db");
+        LibraryCache::open(&path)
+            .unwrap()
+            .store_library(&library())
+            .unwrap();
+        let conn = Connection::open(&path).unwrap();
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1).unwrap();
+        conn.execute_batch("CREATE TABLE leftover (id INTEGER)").unwrap();
+        drop(conn);
+
+        let cache = LibraryCache::open(&path).unwrap();
+
+        assert!(cache.load_library().unwrap().is_none());
+        let conn = Connection::open(&path).unwrap();
+        let leftover: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name = 'leftover'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(leftover, 0);
+    }
+}
@@ -0,0 +1,800 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, watch, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+use crate::api::{IBroadcastClient, Library};
+use crate::profiles::Profile;
+use crate::runtime;
+use crate::utils;
+
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// Downloads running at the same time
+pub const DEFAULT_CONCURRENCY: usize = 3;
+
+/// Bytes between progress events for a single download
+const PROGRESS_STEP: u64 = 256 * 1024;
+
+/// Something the user asked to keep available offline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Pin {
+    Track(u64),
+    Album(u64),
+    Playlist(u64),
+}
+
+impl Pin {
+    /// Tracks covered by the pin in the current library
+    fn track_ids(&self, library: &Library) -> Vec<u64> {
+        match self {
+            Pin::Track(id) => vec![*id],
+            Pin::Album(id) => library
+                .albums
+                .get(id)
+                .map(|album| album.tracks.clone())
+                .unwrap_or_default(),
+            Pin::Playlist(id) => library
+                .playlists
+                .get(id)
+                .map(|playlist| playlist.tracks.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// A completed download
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadEntry {
+    pub file: String,
+    pub size: u64,
+    /// Hex SHA-256 of the file, taken once the transfer was verified complete
+    pub sha256: String,
+    /// Modification time of the file when it was hashed. While the file
+    /// keeps it and its size, it is taken to be intact.
+    #[serde(default)]
+    pub modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    pins: BTreeSet<Pin>,
+    downloads: BTreeMap<u64, DownloadEntry>,
+}
+
+#[derive(Default)]
+struct State {
+    manifest: Manifest,
+    /// Tracks covered by a pin, as of the last `sync_pins`
+    wanted: BTreeSet<u64>,
+    /// Tracks queued or downloading
+    active: HashSet<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadEvent {
+    Queued(u64),
+    Progress {
+        track_id: u64,
+        downloaded: u64,
+        total: Option<u64>,
+    },
+    Finished(u64),
+    Failed(u64, String),
+    Removed(u64),
+}
+
+/// Keeps pinned tracks downloaded to the cache directory.
+///
+/// Partial files are kept as `<id>.part`, with the server's ETag for them in
+/// `<id>.etag`, and resumed with a range request, so an interrupted download
+/// picks up where it stopped on the next run.
+/// Pausing interrupts running transfers the same way and holds queued ones
+/// until resumed.
+#[derive(Clone)]
+pub struct DownloadManager {
+    client: IBroadcastClient,
+    http: reqwest::Client,
+    dir: PathBuf,
+    state: Arc<Mutex<State>>,
+    limit: Arc<Semaphore>,
+    paused: Arc<watch::Sender<bool>>,
+    /// Cancelled once downloads end for good, e.g. on logout
+    stopped: CancellationToken,
+    events: broadcast::Sender<DownloadEvent>,
+}
+
+impl DownloadManager {
+    /// Default location of the profile's downloaded tracks
+    pub fn default_dir(profile: &Profile) -> PathBuf {
+        profile.cache_dir().join("tracks")
+    }
+
+    pub fn new(client: IBroadcastClient, dir: PathBuf, concurrency: usize) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let manifest = match std::fs::read_to_string(dir.join("manifest.json")) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Manifest::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+
+        Ok(Self {
+            client,
+            http: reqwest::Client::new(),
+            dir,
+            state: Arc::new(Mutex::new(State {
+                manifest,
+                ..State::default()
+            })),
+            limit: Arc::new(Semaphore::new(concurrency.max(1))),
+            paused: Arc::new(watch::channel(false).0),
+            stopped: CancellationToken::new(),
+            events,
+        })
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<DownloadEvent> {
+        self.events.subscribe()
+    }
+
+    /// Holds downloads, e.g. while the network is down. Running transfers
+    /// stop and continue from their partial file once resumed.
+    pub fn set_paused(&self, paused: bool) {
+        if self.paused.send_replace(paused) != paused {
+            log::info!("Downloads {}", if paused { "paused" } else { "resumed" });
+        }
+    }
+
+    /// Ends every download for good, e.g. on logout. Finished downloads and
+    /// partial files stay, and nothing new is queued afterwards.
+    pub fn stop(&self) {
+        if !self.stopped.is_cancelled() {
+            self.stopped.cancel();
+            log::info!("Downloads stopped");
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn is_pinned(&self, pin: Pin) -> bool {
+        self.state.lock().unwrap().manifest.pins.contains(&pin)
+    }
+
+    /// Keeps `pins` available offline and starts downloading what's missing
+    pub fn pin(&self, pins: &[Pin], library: &Library) {
+        self.state
+            .lock()
+            .unwrap()
+            .manifest
+            .pins
+            .extend(pins.iter().copied());
+        self.sync_pins(library);
+    }
+
+    /// Stops keeping `pins` offline, deleting tracks no other pin covers
+    pub fn unpin(&self, pins: &[Pin], library: &Library) {
+        {
+            let mut state = self.state.lock().unwrap();
+            for pin in pins {
+                state.manifest.pins.remove(pin);
+            }
+        }
+        self.sync_pins(library);
+    }
+
+    /// Brings the downloads in line with the pins, e.g. after a library sync
+    /// added tracks to a pinned playlist
+    pub fn sync_pins(&self, library: &Library) {
+        let (missing, unwanted) = {
+            let mut state = self.state.lock().unwrap();
+            state.wanted = state
+                .manifest
+                .pins
+                .iter()
+                .flat_map(|pin| pin.track_ids(library))
+                .filter(|id| library.tracks.contains_key(id))
+                .collect();
+            let missing: Vec<u64> = state
+                .wanted
+                .iter()
+                .copied()
+                .filter(|id| {
+                    !state.manifest.downloads.contains_key(id) && !state.active.contains(id)
+                })
+                .collect();
+            let unwanted: Vec<u64> = state
+                .manifest
+                .downloads
+                .keys()
+                .copied()
+                .filter(|id| !state.wanted.contains(id))
+                .collect();
+            (missing, unwanted)
+        };
+
+        for track_id in unwanted {
+            self.remove_download(track_id);
+        }
+        if let Err(e) = self.save_manifest() {
+            log::warn!("Failed to save download manifest: {}", e);
+        }
+        for track_id in missing {
+            self.queue(track_id);
+        }
+    }
+
+    /// Path of the downloaded copy of a track, if there is one
+    pub fn local_path(&self, track_id: u64) -> Option<PathBuf> {
+        let state = self.state.lock().unwrap();
+        let entry = state.manifest.downloads.get(&track_id)?;
+        Some(self.dir.join(&entry.file)).filter(|path| path.exists())
+    }
+
+    /// Like `local_path`, but first checks that the file is intact. A file
+    /// whose size and modification time are unchanged since it was hashed is
+    /// trusted; one that was touched is hashed again. A corrupt file is
+    /// deleted and downloaded again.
+    ///
+    /// The hash is of what was received, as the API offers none to compare
+    /// against, so this catches damage on disk after the download. The
+    /// transfer itself is only checked against the length the server
+    /// reported and, when resuming, its ETag.
+    pub async fn verified_path(&self, track_id: u64) -> Option<PathBuf> {
+        let path = self.local_path(track_id)?;
+        let entry = self
+            .state
+            .lock()
+            .unwrap()
+            .manifest
+            .downloads
+            .get(&track_id)?
+            .clone();
+
+        let metadata = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                log::warn!("Failed to check download of track {}: {}", track_id, e);
+                return None;
+            }
+        };
+        if metadata.len() != entry.size {
+            return self.discard_corrupt(track_id);
+        }
+        let modified = metadata.modified().ok();
+        if modified.is_some() && modified == entry.modified {
+            return Some(path);
+        }
+
+        let hashed = path.clone();
+        match tokio::task::spawn_blocking(move || sha256_file(&hashed)).await {
+            Ok(Ok(actual)) if actual == entry.sha256 => {
+                if let Some(entry) = self
+                    .state
+                    .lock()
+                    .unwrap()
+                    .manifest
+                    .downloads
+                    .get_mut(&track_id)
+                {
+                    entry.modified = modified;
+                }
+                if let Err(e) = self.save_manifest() {
+                    log::warn!("Failed to save download manifest: {}", e);
+                }
+                Some(path)
+            }
+            Ok(Ok(_)) => self.discard_corrupt(track_id),
+            Ok(Err(e)) => {
+                log::warn!("Failed to check download of track {}: {}", track_id, e);
+                None
+            }
+            Err(e) => {
+                log::warn!("Checksum task failed: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Deletes a corrupt download and fetches it again if it is still pinned
+    fn discard_corrupt(&self, track_id: u64) -> Option<PathBuf> {
+        log::warn!(
+            "Download of track {} is corrupt, fetching it again",
+            track_id
+        );
+        self.remove_download(track_id);
+        if let Err(e) = self.save_manifest() {
+            log::warn!("Failed to save download manifest: {}", e);
+        }
+        if self.is_wanted(track_id) {
+            self.queue(track_id);
+        }
+        None
+    }
+
+    fn queue(&self, track_id: u64) {
+        if self.stopped.is_cancelled() {
+            return;
+        }
+        if !self.state.lock().unwrap().active.insert(track_id) {
+            return;
+        }
+        let _ = self.events.send(DownloadEvent::Queued(track_id));
+
+        let this = self.clone();
+        glib::spawn_future_local(async move {
+            // `download` goes first so it can abort a running transfer
+            let result = tokio::select! {
+                biased;
+                result = this.download(track_id) => result,
+                _ = this.stopped.cancelled() => Err(anyhow!("Downloads stopped")),
+            };
+            this.state.lock().unwrap().active.remove(&track_id);
+            match result {
+                Ok(()) => {
+                    let _ = this.events.send(DownloadEvent::Finished(track_id));
+                }
+                Err(_) if this.stopped.is_cancelled() => {
+                    log::debug!("Stopped download of track {}", track_id);
+                }
+                Err(e) => {
+                    log::error!("Failed to download track {}: {}", track_id, e);
+                    let _ = this
+                        .events
+                        .send(DownloadEvent::Failed(track_id, e.to_string()));
+                }
+            }
+        });
+    }
+
+    async fn download(&self, track_id: u64) -> Result<()> {
+        let mut paused = self.paused.subscribe();
+        let file = track_id.to_string();
+        let (size, sha256) = loop {
+            paused.wait_for(|paused| !paused).await?;
+            let _permit = self.limit.acquire().await?;
+            if !self.is_wanted(track_id) {
+                bail!("Track is no longer pinned");
+            }
+            // Paused again while waiting for a free slot
+            if *paused.borrow() {
+                continue;
+            }
+
+            let client = self.client.clone();
+            let url =
+                runtime::spawn(async move { client.get_stream_url(&track_id.to_string()).await })
+                    .await?
+                    .stream_url;
+
+            let part = self.dir.join(format!("{}.part", track_id));
+            let http = self.http.clone();
+            let events = self.events.clone();
+            let mut transfer = tokio::spawn({
+                let path = self.dir.join(&file);
+                async move { transfer(http, url, track_id, part, path, events).await }
+            });
+            tokio::select! {
+                result = &mut transfer => {
+                    break result.map_err(|e| anyhow!("Download task failed: {}", e))??;
+                }
+                _ = paused.wait_for(|paused| *paused) => {
+                    // The partial file stays, so the transfer resumes from there
+                    transfer.abort();
+                    let _ = transfer.await;
+                    log::debug!("Paused download of track {}", track_id);
+                }
+                _ = self.stopped.cancelled() => {
+                    transfer.abort();
+                    bail!("Downloads stopped");
+                }
+            }
+        };
+
+        let modified = std::fs::metadata(self.dir.join(&file))
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        {
+            let mut state = self.state.lock().unwrap();
+            let entry = DownloadEntry {
+                file,
+                size,
+                sha256,
+                modified,
+            };
+            state.manifest.downloads.insert(track_id, entry);
+        }
+        // Unpinned while downloading
+        if !self.is_wanted(track_id) {
+            self.remove_download(track_id);
+        }
+        self.save_manifest()
+    }
+
+    fn is_wanted(&self, track_id: u64) -> bool {
+        self.state.lock().unwrap().wanted.contains(&track_id)
+    }
+
+    /// Deletes a download and its partial file. The caller saves the manifest.
+    fn remove_download(&self, track_id: u64) {
+        let entry = self
+            .state
+            .lock()
+            .unwrap()
+            .manifest
+            .downloads
+            .remove(&track_id);
+        if let Some(entry) = entry {
+            if let Err(e) = std::fs::remove_file(self.dir.join(&entry.file)) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("Failed to delete download of track {}: {}", track_id, e);
+                }
+            }
+            let _ = self.events.send(DownloadEvent::Removed(track_id));
+        }
+        let _ = std::fs::remove_file(self.dir.join(format!("{}.part", track_id)));
+        let _ = std::fs::remove_file(self.dir.join(format!("{}.etag", track_id)));
+    }
+
+    fn save_manifest(&self) -> Result<()> {
+        let json = serde_json::to_vec(&self.state.lock().unwrap().manifest)?;
+        let path = self.dir.join("manifest.json");
+        let temp = path.with_extension("json.tmp");
+        std::fs::write(&temp, json)?;
+        std::fs::rename(&temp, &path)?;
+        Ok(())
+    }
+}
+
+/// Downloads `url` into `part`, resuming whatever is already there if the
+/// server confirms it still has the same file, then checks the length
+/// against what the server reported, hashes the file and moves it to `path`
+async fn transfer(
+    http: reqwest::Client,
+    url: String,
+    track_id: u64,
+    part: PathBuf,
+    path: PathBuf,
+    events: broadcast::Sender<DownloadEvent>,
+) -> Result<(u64, String)> {
+    let etag_path = part.with_extension("etag");
+    let existing = match tokio::fs::metadata(&part).await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    };
+    // Without an ETag there's no telling whether the partial file still
+    // matches, so it is only resumed with one
+    let etag = match existing {
+        0 => None,
+        _ => tokio::fs::read_to_string(&etag_path).await.ok(),
+    };
+
+    let mut request = http.get(&url);
+    if let Some(etag) = &etag {
+        log::debug!("Resuming track {} at byte {}", track_id, existing);
+        request = request
+            .header(reqwest::header::RANGE, format!("bytes={}-", existing))
+            .header(reqwest::header::IF_RANGE, etag.as_str());
+    }
+    // Errors leave out the URL, as stream URLs are signed
+    let mut response = request.send().await.map_err(reqwest::Error::without_url)?;
+
+    // The partial file is already complete, or the source changed; start over
+    if response.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+        tokio::fs::remove_file(&part).await?;
+        response = http
+            .get(&url)
+            .send()
+            .await
+            .map_err(reqwest::Error::without_url)?;
+    }
+    let mut response = response
+        .error_for_status()
+        .map_err(reqwest::Error::without_url)?;
+
+    // Anything but the asked-for range means the whole file follows
+    let range = match response.status() {
+        reqwest::StatusCode::PARTIAL_CONTENT => utils::content_range(&response),
+        _ => None,
+    };
+    let (resumed, total) = match range {
+        Some((start, total)) if start == existing && etag.is_some() => (true, total),
+        Some(_) => {
+            let _ = tokio::fs::remove_file(&part).await;
+            bail!("Server sent a different range than asked for");
+        }
+        None => (false, response.content_length()),
+    };
+    let total = total.ok_or_else(|| anyhow!("Server didn't report the track's size"))?;
+
+    if !resumed {
+        let etag = response
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|value| value.to_str().ok());
+        match etag {
+            Some(etag) => tokio::fs::write(&etag_path, etag).await?,
+            None => {
+                let _ = tokio::fs::remove_file(&etag_path).await;
+            }
+        }
+    }
+
+    let mut downloaded = if resumed { existing } else { 0 };
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
+        .open(&part)
+        .await?;
+
+    let mut reported = downloaded;
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(reqwest::Error::without_url)?
+    {
+        file.write_all(&chunk).await?;
+        downloaded += chunk.len() as u64;
+        if downloaded - reported >= PROGRESS_STEP {
+            reported = downloaded;
+            let _ = events.send(DownloadEvent::Progress {
+                track_id,
+                downloaded,
+                total: Some(total),
+            });
+        }
+    }
+    file.flush().await?;
+    file.sync_all().await?;
+    drop(file);
+
+    if downloaded != total {
+        bail!("Incomplete download: {} of {} bytes", downloaded, total);
+    }
+
+    let hashed = part.clone();
+    let sha256 = tokio::task::spawn_blocking(move || sha256_file(&hashed)).await??;
+    tokio::fs::rename(&part, &path).await?;
+    let _ = tokio::fs::remove_file(&etag_path).await;
+    Ok((downloaded, sha256))
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const TRACK_ID: u64 = 7;
+
+    fn audio() -> Vec<u8> {
+        (0..100_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// A manager over `dir` whose client is never used
+    fn manager(dir: &TempDir) -> DownloadManager {
+        let client = IBroadcastClient::with_base_url("http://127.0.0.1:9/");
+        DownloadManager::new(client, dir.path().to_path_buf(), 1).unwrap()
+    }
+
+    /// Leaves a finished download of `audio` in `dir`, as a manager would
+    fn finished_download(dir: &TempDir, audio: &[u8]) -> PathBuf {
+        let path = dir.path().join(TRACK_ID.to_string());
+        std::fs::write(&path, audio).unwrap();
+        let mut manifest = Manifest::default();
+        manifest.downloads.insert(
+            TRACK_ID,
+            DownloadEntry {
+                file: TRACK_ID.to_string(),
+                size: audio.len() as u64,
+                sha256: sha256_file(&path).unwrap(),
+                modified: None,
+            },
+        );
+        let json = serde_json::to_vec(&manifest).unwrap();
+        std::fs::write(dir.path().join("manifest.json"), json).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn intact_downloads_are_trusted() {
+        let dir = TempDir::new().unwrap();
+        let path = finished_download(&dir, &audio());
+        let downloads = manager(&dir);
+
+        assert_eq!(downloads.verified_path(TRACK_ID).await, Some(path.clone()));
+        // The second check goes by the recorded modification time
+        assert_eq!(downloads.verified_path(TRACK_ID).await, Some(path));
+    }
+
+    #[tokio::test]
+    async fn touched_downloads_that_no_longer_match_are_discarded() {
+        let dir = TempDir::new().unwrap();
+        let path = finished_download(&dir, &audio());
+        let downloads = manager(&dir);
+        let mut events = downloads.subscribe();
+        assert!(downloads.verified_path(TRACK_ID).await.is_some());
+
+        let mut corrupt = audio();
+        corrupt[10] ^= 1;
+        std::fs::write(&path, &corrupt).unwrap();
+        let later = SystemTime::now() + std::time::Duration::from_secs(10);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+
+        assert_eq!(downloads.verified_path(TRACK_ID).await, None);
+        assert!(!path.exists());
+        assert_eq!(downloads.local_path(TRACK_ID), None);
+        assert_eq!(events.try_recv(), Ok(DownloadEvent::Removed(TRACK_ID)));
+    }
+
+    #[tokio::test]
+    async fn downloads_of_another_size_are_discarded() {
+        let dir = TempDir::new().unwrap();
+        let path = finished_download(&dir, &audio());
+        let downloads = manager(&dir);
+
+        std::fs::write(&path, &audio()[..1000]).unwrap();
+
+        assert_eq!(downloads.verified_path(TRACK_ID).await, None);
+        assert!(!path.exists());
+        // Gone from the manifest too, so the next sync fetches it again
+        let reopened = manager(&dir);
+        assert_eq!(reopened.local_path(TRACK_ID), None);
+    }
+
+    #[cfg(feature = "mock-server")]
+    mod transfers {
+        use super::*;
+        use crate::api::mock::MockServer;
+        use crate::api::Track;
+
+        struct Fixture {
+            _server: MockServer,
+            dir: TempDir,
+            url: String,
+            etag: String,
+        }
+
+        impl Fixture {
+            /// Serves `audio()` as the track's stream
+            async fn new() -> Self {
+                let server = MockServer::start().await.unwrap();
+                server.add_account("listener@example.com", "hunter2");
+                let track = Track {
+                    id: TRACK_ID,
+                    ..Default::default()
+                };
+                server.add_track(track, audio());
+                let client = IBroadcastClient::with_base_url(server.url());
+                client
+                    .login("listener@example.com", "hunter2")
+                    .await
+                    .unwrap();
+                let url = client
+                    .get_stream_url(&TRACK_ID.to_string())
+                    .await
+                    .unwrap()
+                    .stream_url;
+                let response = reqwest::get(&url).await.unwrap();
+                let etag = response.headers()[reqwest::header::ETAG]
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+                Self {
+                    _server: server,
+                    dir: TempDir::new().unwrap(),
+                    url,
+                    etag,
+                }
+            }
+
+            fn part(&self) -> PathBuf {
+                self.dir.path().join(format!("{}.part", TRACK_ID))
+            }
+
+            fn path(&self) -> PathBuf {
+                self.dir.path().join(TRACK_ID.to_string())
+            }
+
+            /// Leaves a partial file behind, with `etag` if given
+            fn interrupted(&self, data: &[u8], etag: Option<&str>) {
+                std::fs::write(self.part(), data).unwrap();
+                if let Some(etag) = etag {
+                    std::fs::write(self.part().with_extension("etag"), etag).unwrap();
+                }
+            }
+
+            async fn transfer(&self) -> Result<(u64, String)> {
+                let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+                transfer(
+                    reqwest::Client::new(),
+                    self.url.clone(),
+                    TRACK_ID,
+                    self.part(),
+                    self.path(),
+                    events,
+                )
+                .await
+            }
+        }
+
+        #[tokio::test]
+        async fn whole_file_is_downloaded_and_hashed() {
+            let fixture = Fixture::new().await;
+
+            let (size, sha256) = fixture.transfer().await.unwrap();
+
+            assert_eq!(std::fs::read(fixture.path()).unwrap(), audio());
+            assert_eq!(size, audio().len() as u64);
+            assert_eq!(sha256, sha256_file(&fixture.path()).unwrap());
+            assert!(!fixture.part().exists());
+        }
+
+        #[tokio::test]
+        async fn partial_file_is_resumed_while_the_server_has_the_same_file() {
+            let fixture = Fixture::new().await;
+            // Marked, so the test can tell the kept bytes from refetched ones
+            let kept = vec![0xff; 30_000];
+            fixture.interrupted(&kept, Some(&fixture.etag));
+
+            let (size, sha256) = fixture.transfer().await.unwrap();
+
+            let data = std::fs::read(fixture.path()).unwrap();
+            assert_eq!(&data[..kept.len()], kept.as_slice());
+            assert_eq!(&data[kept.len()..], &audio()[kept.len()..]);
+            assert_eq!(size, audio().len() as u64);
+            // Only what arrived is hashed; a bad transfer goes unnoticed
+            assert_eq!(sha256, sha256_file(&fixture.path()).unwrap());
+            assert!(!fixture.part().with_extension("etag").exists());
+        }
+
+        #[tokio::test]
+        async fn changed_file_is_downloaded_from_the_start() {
+            let fixture = Fixture::new().await;
+            fixture.interrupted(&[0xff; 30_000], Some("\"old\""));
+
+            fixture.transfer().await.unwrap();
+
+            assert_eq!(std::fs::read(fixture.path()).unwrap(), audio());
+        }
+
+        #[tokio::test]
+        async fn partial_file_without_an_etag_is_not_resumed() {
+            let fixture = Fixture::new().await;
+            fixture.interrupted(&[0xff; 30_000], None);
+
+            fixture.transfer().await.unwrap();
+
+            assert_eq!(std::fs::read(fixture.path()).unwrap(), audio());
+        }
+
+        #[tokio::test]
+        async fn unsatisfiable_range_starts_over() {
+            let fixture = Fixture::new().await;
+            // As long as the whole file, so the server answers 416
+            fixture.interrupted(&vec![0xff; audio().len()], Some(&fixture.etag));
+
+            fixture.transfer().await.unwrap();
+
+            assert_eq!(std::fs::read(fixture.path()).unwrap(), audio());
+        }
+    }
+}
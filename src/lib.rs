@@ -0,0 +1,4 @@
+//! The iBroadcast API client, built as a library as well so the tests in
+//! `tests/` can drive it against the mock server
+
+pub mod api;
@@ -1,21 +1,36 @@
 use adw::prelude::*;
 use gtk::Application;
-use log::{info, debug};
-use std::sync::{Arc, Mutex};
+use log::{info, debug, error, warn};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
 
-mod api;
+use latke::api;
+
+mod cache;
+mod credentials;
+mod db;
+mod downloads;
+mod network;
+mod outbox;
+mod player;
+mod profiles;
+mod queue;
+mod runtime;
+mod settings;
+mod sync;
 mod ui;
 mod utils;
 
 fn main() {
-    // Initialize logging with debug level
-    std::env::set_var("RUST_LOG", "debug");
-    env_logger::init();
+    // Log at info unless RUST_LOG asks for more or less, e.g. RUST_LOG=latke=debug
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     info!("Starting Latke...");
 
     // Initialize Tokio runtime
     let runtime = tokio::runtime::Runtime::new().unwrap();
     let _guard = runtime.enter();
+    runtime::init(runtime.handle().clone());
 
     // Create GTK application
     let app = Application::builder()
@@ -25,19 +40,387 @@ fn main() {
     // Set up application activation handler
     app.connect_activate(move |app| {
         info!("Application activated");
-        
-        // Create API client
-        let client = Arc::new(Mutex::new(api::IBroadcastClient::new()));
-        
-        // Create and show login window
-        let login_window = ui::LoginWindow::new(app, client.clone());
-        login_window.connect_login(|| {
-            info!("Login successful");
-            // TODO: Show main window
+
+        // Create API client, optionally pointed at another endpoint (e.g. a mock server)
+        let client = match std::env::var("LATKE_API_URL") {
+            Ok(url) => api::IBroadcastClient::with_base_url(url),
+            Err(_) => api::IBroadcastClient::new(),
+        };
+        // Profiles are loaded before anything touches the data directories,
+        // as a fresh install moves data from before profiles into the first
+        let profiles = match profiles::Profiles::load(&profiles::Profiles::default_path()) {
+            Ok(profiles) => profiles,
+            Err(e) => {
+                warn!("Failed to load account profiles: {}", e);
+                profiles::Profiles::default()
+            }
+        };
+        let profiles = Rc::new(RefCell::new(profiles));
+
+        client.start_token_refresher();
+        watch_session(app, &client, &profiles);
+        let network = network::NetworkWatcher::new(client.clone());
+
+        // LATKE_AUDIO_SINK selects another sink, e.g. fakesink on machines without audio
+        let player = match std::env::var("LATKE_AUDIO_SINK") {
+            Ok(sink) => player::Player::with_audio_sink(&sink),
+            Err(_) => player::Player::new(),
+        };
+        let player = match player {
+            Ok(player) => player,
+            Err(e) => {
+                error!("Failed to create audio player: {}", e);
+                app.quit();
+                return;
+            }
+        };
+
+        let opening = app.clone();
+        let profile_list = profiles.clone();
+        unlock_credentials(app, &profile_list, move || {
+            let (client, network, player) = (client.clone(), network.clone(), player.clone());
+            open_profile(&opening, client, network, player, profiles.clone(), None);
         });
-        login_window.show();
     });
 
     // Run the application
     app.run();
-} 
\ No newline at end of file
+}
+
+/// Picks where secrets are kept, then calls `then`. Without a keyring that
+/// is an encrypted file, whose passphrase is asked for first; closing the
+/// prompt quits. With a keyring, secrets left in such a file from before
+/// are offered to move into it.
+fn unlock_credentials<F>(app: &Application, profiles: &Rc<RefCell<profiles::Profiles>>, then: F)
+where
+    F: Fn() + 'static,
+{
+    let file = Arc::new(credentials::FileStore::new(credentials::FileStore::default_path()));
+    let prompt = match credentials::detect() {
+        credentials::Backend::Keyring if file.exists() => ui::PassphrasePrompt::MoveToKeyring,
+        credentials::Backend::Keyring => {
+            then();
+            return;
+        }
+        credentials::Backend::File => ui::PassphrasePrompt::Unlock,
+    };
+
+    let window = ui::PassphraseWindow::new(app, file.clone(), prompt);
+    let then = Rc::new(then);
+    let profiles = profiles.clone();
+    let unlocked = then.clone();
+    window.connect_unlocked(move || {
+        let keyring = credentials::KeyringStore;
+        let all = profiles.borrow().all().to_vec();
+        match prompt {
+            ui::PassphrasePrompt::MoveToKeyring => {
+                match utils::move_secrets(&*file, &keyring, &all) {
+                    Ok(moved) => {
+                        info!("Moved {} secrets to the keyring", moved);
+                        if let Err(e) = file.remove() {
+                            warn!("Failed to remove credential file: {}", e);
+                        }
+                    }
+                    // The file stays, to try again next time
+                    Err(e) => warn!("Failed to move secrets to the keyring: {}", e),
+                }
+            }
+            ui::PassphrasePrompt::Unlock => {
+                credentials::set_store(file.clone());
+                // A working keyring passed over by LATKE_CREDENTIAL_STORE
+                if credentials::KeyringStore::is_available() {
+                    match utils::move_secrets(&keyring, &*file, &all) {
+                        Ok(0) => {}
+                        Ok(moved) => info!("Moved {} secrets from the keyring", moved),
+                        Err(e) => warn!("Failed to move secrets from the keyring: {}", e),
+                    }
+                }
+            }
+        }
+        unlocked();
+    });
+    if prompt == ui::PassphrasePrompt::MoveToKeyring {
+        // Carry on with the keyring, leaving the file for next time
+        window.connect_cancel(move || then());
+    }
+    window.show();
+}
+
+/// Opens the current profile: its stored session if still usable, the login
+/// window otherwise. `previous` is the profile switched away from, if any,
+/// which closing the login window goes back to.
+fn open_profile(
+    app: &Application,
+    client: api::IBroadcastClient,
+    network: network::NetworkWatcher,
+    player: player::Player,
+    profiles: Rc<RefCell<profiles::Profiles>>,
+    previous: Option<String>,
+) {
+    // Skip the login window when a usable session is stored
+    let profile = profiles.borrow().current().clone();
+    let session = match utils::load_session(&profile) {
+        Ok(Some(session)) if !session.is_expired() => session,
+        Ok(Some(_)) => {
+            info!("Stored session has expired");
+            if let Err(e) = utils::clear_session(&profile) {
+                warn!("Failed to clear expired session: {}", e);
+            }
+            show_login(app, client, network, player, profiles, previous);
+            return;
+        }
+        Ok(None) => {
+            show_login(app, client, network, player, profiles, previous);
+            return;
+        }
+        Err(e) => {
+            warn!("Failed to load stored session: {}", e);
+            show_login(app, client, network, player, profiles, previous);
+            return;
+        }
+    };
+
+    client.restore_session(session);
+
+    // Offline, the session can't be checked; the cached library is used as is
+    let work_offline = match settings::Settings::load(&settings::Settings::default_path(&profile)) {
+        Ok(settings) => settings.work_offline,
+        Err(e) => {
+            warn!("Failed to load settings: {}", e);
+            false
+        }
+    };
+    if work_offline || !network.is_online() {
+        info!("Offline, using the stored session unchecked");
+        on_logged_in(app, client, network, player, profiles);
+        return;
+    }
+
+    // No window may be open while the session is checked
+    let hold = app.hold();
+    let app = app.clone();
+    glib::spawn_future_local(async move {
+        let _hold = hold;
+        let validating = client.clone();
+        let result = runtime::spawn(async move { validating.validate_session().await }).await;
+        match result {
+            Ok(()) => {
+                info!("Restored stored session");
+                on_logged_in(&app, client, network, player, profiles);
+            }
+            Err(e @ (api::IBroadcastError::Network(_)
+            | api::IBroadcastError::Offline
+            | api::IBroadcastError::TokenRefresh(_))) => {
+                // The session may well be fine; show the cached library until the network is back
+                warn!("Could not validate stored session: {}", e);
+                on_logged_in(&app, client, network, player, profiles);
+            }
+            Err(e) => {
+                warn!("Stored session is no longer valid: {}", e);
+                if let Err(e) = utils::clear_session(&profile) {
+                    warn!("Failed to clear stored session: {}", e);
+                }
+                show_login(&app, client, network, player, profiles, previous);
+            }
+        }
+    });
+}
+
+/// Shows the login window and continues to the app once authenticated
+fn show_login(
+    app: &Application,
+    client: api::IBroadcastClient,
+    network: network::NetworkWatcher,
+    player: player::Player,
+    profiles: Rc<RefCell<profiles::Profiles>>,
+    previous: Option<String>,
+) {
+    let profile = profiles.borrow().current().clone();
+    let login_window = ui::LoginWindow::new(app, client.clone(), profile);
+
+    // Closing the window goes back to the account switched away from. That
+    // one opens without a `previous`, so closing its own login window quits
+    // rather than bouncing between the two.
+    if let Some(previous) = previous {
+        let app = app.clone();
+        let (client, network, player, profiles) =
+            (client.clone(), network.clone(), player.clone(), profiles.clone());
+        login_window.connect_cancel(move || {
+            if profiles.borrow_mut().switch_to(&previous).is_none() {
+                return;
+            }
+            info!("Going back to the previous account");
+            client.forget_session();
+            let (client, network, player) = (client.clone(), network.clone(), player.clone());
+            open_profile(&app, client, network, player, profiles.clone(), None);
+        });
+    }
+
+    let app_clone = app.clone();
+    login_window.connect_login(move || {
+        info!("Login successful");
+        on_logged_in(&app_clone, client.clone(), network.clone(), player.clone(), profiles.clone());
+    });
+    login_window.show();
+}
+
+/// Persists the new session and moves on to the main UI
+fn on_logged_in(
+    app: &Application,
+    client: api::IBroadcastClient,
+    network: network::NetworkWatcher,
+    player: player::Player,
+    profiles: Rc<RefCell<profiles::Profiles>>,
+) {
+    let profile = profiles.borrow().current().clone();
+    let session = client.session();
+    match session {
+        Some(session) => {
+            if let Err(e) = utils::save_session(&profile, &session) {
+                warn!("Failed to save session: {}", e);
+            }
+        }
+        None => debug!("No session to save"),
+    }
+    // Saved only now, so the next start opens an account that was logged in to
+    if let Err(e) = profiles.borrow().save(&profiles::Profiles::default_path()) {
+        warn!("Failed to save account profiles: {}", e);
+    }
+
+    // From here on a lost session is renewed in place rather than starting over
+    client.set_reauthenticate(true);
+    let main_window = ui::MainWindow::new(
+        app,
+        client.clone(),
+        network.clone(),
+        player.clone(),
+        profile,
+        profiles.borrow().all(),
+    );
+
+    let app_clone = app.clone();
+    let (logout_client, logout_network, logout_player, logout_profiles) =
+        (client.clone(), network.clone(), player.clone(), profiles.clone());
+    main_window.connect_logout(move || {
+        show_login(
+            &app_clone,
+            logout_client.clone(),
+            logout_network.clone(),
+            logout_player.clone(),
+            logout_profiles.clone(),
+            None,
+        );
+    });
+
+    let app_clone = app.clone();
+    main_window.connect_switch_profile(move |choice| {
+        let (client, network, player) = (client.clone(), network.clone(), player.clone());
+        switch_profile(&app_clone, choice, client, network, player, profiles.clone());
+    });
+
+    main_window.show();
+    main_window.load_library();
+}
+
+/// Puts the chosen profile in use and opens it. The current account's
+/// session stays stored, so switching back needs no login.
+fn switch_profile(
+    app: &Application,
+    choice: ui::ProfileChoice,
+    client: api::IBroadcastClient,
+    network: network::NetworkWatcher,
+    player: player::Player,
+    profiles: Rc<RefCell<profiles::Profiles>>,
+) {
+    let previous = profiles.borrow().current().id.clone();
+    let profile = {
+        let mut profiles = profiles.borrow_mut();
+        match choice {
+            ui::ProfileChoice::Existing(id) => profiles.switch_to(&id),
+            ui::ProfileChoice::New(name) => {
+                let id = profiles.add(&name).id;
+                profiles.switch_to(&id)
+            }
+        }
+    };
+    let Some(profile) = profile else {
+        warn!("No such account profile");
+        return;
+    };
+
+    info!("Switching to account {}", profile.name);
+    client.forget_session();
+    open_profile(app, client, network, player, profiles, Some(previous));
+}
+
+/// Keeps the stored session in step with the client: refreshed tokens are
+/// saved, and a lost session is forgotten. Once the main window is up, a
+/// lost session is renewed with a login dialog over it.
+fn watch_session(
+    app: &Application,
+    client: &api::IBroadcastClient,
+    profiles: &Rc<RefCell<profiles::Profiles>>,
+) {
+    use tokio::sync::broadcast::error::RecvError;
+
+    let mut events = client.subscribe_session();
+    let app = app.clone();
+    let client = client.clone();
+    let profiles = profiles.clone();
+    glib::spawn_future_local(async move {
+        loop {
+            let event = events.recv().await;
+            // The client only ever holds the current profile's session
+            let profile = profiles.borrow().current().clone();
+            match event {
+                // A missed event may have been a refresh, so save either way
+                Ok(api::SessionEvent::Refreshed) | Err(RecvError::Lagged(_)) => {
+                    if let Some(session) = client.session() {
+                        if let Err(e) = utils::save_session(&profile, &session) {
+                            warn!("Failed to save refreshed session: {}", e);
+                        }
+                    }
+                }
+                Ok(api::SessionEvent::Lost(reason)) => {
+                    warn!("Session lost: {}", reason);
+                    if let Err(e) = utils::clear_session(&profile) {
+                        warn!("Failed to clear stored session: {}", e);
+                    }
+                    if client.reauthenticates() {
+                        reauthenticate(&app, &client, profile);
+                    }
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Asks the user to log in again over the current window, to the same
+/// account. Requests held for the new session go ahead once it is in;
+/// closing the dialog or logging in to another account fails them.
+fn reauthenticate(app: &Application, client: &api::IBroadcastClient, profile: profiles::Profile) {
+    let Some(parent) = app.active_window().or_else(|| app.windows().into_iter().next()) else {
+        client.abandon_reauthentication();
+        return;
+    };
+
+    // The session is lost but not forgotten, so it still names its account
+    let user_id = client.session().and_then(|session| session.user_id);
+    let login_window = ui::LoginWindow::new(app, client.clone(), profile.clone());
+    let session_client = client.clone();
+    login_window.connect_login(move || {
+        info!("Logged in again");
+        match session_client.session() {
+            Some(session) => {
+                if let Err(e) = utils::save_session(&profile, &session) {
+                    warn!("Failed to save session: {}", e);
+                }
+            }
+            None => debug!("No session to save"),
+        }
+    });
+    let cancel_client = client.clone();
+    login_window.connect_cancel(move || cancel_client.abandon_reauthentication());
+    login_window.show_reauthentication(&parent, user_id);
+}
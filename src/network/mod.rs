@@ -0,0 +1,83 @@
+use gio::prelude::*;
+use std::cell::Cell;
+use std::rc::Rc;
+use tokio::sync::broadcast;
+
+use crate::api::IBroadcastClient;
+
+const EVENT_CHANNEL_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkEvent {
+    Online,
+    Offline,
+}
+
+/// Follows the system's network state through `gio::NetworkMonitor` and
+/// keeps the API client told, so requests fail fast while offline.
+///
+/// Only full connectivity counts as online; a captive portal or a network
+/// without a route to the internet can't reach iBroadcast either.
+#[derive(Clone)]
+pub struct NetworkWatcher {
+    monitor: gio::NetworkMonitor,
+    online: Rc<Cell<bool>>,
+    events: broadcast::Sender<NetworkEvent>,
+}
+
+impl NetworkWatcher {
+    pub fn new(client: IBroadcastClient) -> Self {
+        let monitor = gio::NetworkMonitor::default();
+        let online = is_online(&monitor);
+        client.set_online(online);
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+
+        let watcher = Self {
+            monitor,
+            online: Rc::new(Cell::new(online)),
+            events,
+        };
+
+        // network-changed fires on every routing change, and connectivity is
+        // updated separately, so both just re-check the overall state
+        let this = watcher.clone();
+        let changed_client = client.clone();
+        watcher
+            .monitor
+            .connect_network_changed(move |monitor, _| this.update(monitor, &changed_client));
+        let this = watcher.clone();
+        watcher
+            .monitor
+            .connect_connectivity_notify(move |monitor| this.update(monitor, &client));
+
+        log::info!("Network is {}", if online { "online" } else { "offline" });
+        watcher
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.get()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<NetworkEvent> {
+        self.events.subscribe()
+    }
+
+    fn update(&self, monitor: &gio::NetworkMonitor, client: &IBroadcastClient) {
+        let online = is_online(monitor);
+        if self.online.replace(online) == online {
+            return;
+        }
+        client.set_online(online);
+        if online {
+            log::info!("Network is back");
+            let _ = self.events.send(NetworkEvent::Online);
+        } else {
+            log::info!("Network is gone");
+            let _ = self.events.send(NetworkEvent::Offline);
+        }
+    }
+}
+
+fn is_online(monitor: &gio::NetworkMonitor) -> bool {
+    monitor.is_network_available() && monitor.connectivity() == gio::NetworkConnectivity::Full
+}
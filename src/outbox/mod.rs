@@ -0,0 +1,700 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::api::{IBroadcastClient, IBroadcastError, Library, Playlist};
+use crate::profiles::Profile;
+
+/// Playlist an edit applies to. Playlists created while offline only have
+/// a local id until their creation has been replayed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PlaylistRef {
+    Remote(u64),
+    Local(u64),
+}
+
+impl PlaylistRef {
+    /// Refers to a playlist by the id it has in the library
+    pub fn from_id(id: u64) -> Self {
+        if Outbox::is_local_id(id) {
+            PlaylistRef::Local(id)
+        } else {
+            PlaylistRef::Remote(id)
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        match self {
+            PlaylistRef::Remote(id) | PlaylistRef::Local(id) => *id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PlaylistEdit {
+    Create {
+        local_id: u64,
+        name: String,
+    },
+    AddTrack {
+        playlist: PlaylistRef,
+        track_id: u64,
+    },
+    RemoveTrack {
+        playlist: PlaylistRef,
+        track_id: u64,
+    },
+    Delete {
+        playlist: PlaylistRef,
+        /// Tracks the playlist had when the user deleted it; if it changed
+        /// on the server since, the delete is treated as a conflict
+        tracks: Vec<u64>,
+    },
+}
+
+/// An edit that couldn't be replayed because the server state moved on
+#[derive(Debug, Clone, PartialEq)]
+#[allow(dead_code)]
+pub struct Conflict {
+    pub edit: PlaylistEdit,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct FlushReport {
+    pub applied: usize,
+    pub conflicts: Vec<Conflict>,
+    /// Edits still queued because the server couldn't be reached
+    pub remaining: usize,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct OutboxState {
+    edits: Vec<PlaylistEdit>,
+    /// Remote ids of replayed creations, for edits queued after them
+    created: BTreeMap<u64, u64>,
+    /// Creations that were sent without an answer, with the ids of the
+    /// playlists that had their name before they were first sent
+    #[serde(default)]
+    attempted: BTreeMap<u64, BTreeSet<u64>>,
+    next_local_id: u64,
+}
+
+/// Playlist edits waiting to be sent, persisted so they survive restarts.
+///
+/// Edits are replayed strictly in the order they were made. Each one is
+/// checked against the current server library first: edits that are already
+/// in effect are skipped, and edits whose target changed or disappeared are
+/// dropped and reported as conflicts.
+#[derive(Clone)]
+pub struct Outbox {
+    path: PathBuf,
+    state: Arc<Mutex<OutboxState>>,
+    /// Held while replaying so two flushes never send the same edit
+    flushing: Arc<tokio::sync::Mutex<()>>,
+}
+
+/// Local playlist ids count down from here so they never meet server ids
+const FIRST_LOCAL_ID: u64 = i64::MAX as u64;
+
+impl Outbox {
+    /// Default location of the profile's persisted outbox
+    pub fn default_path(profile: &Profile) -> PathBuf {
+        profile.data_dir().join("outbox.json")
+    }
+
+    /// Loads the outbox, starting empty if there is none
+    pub fn load(path: &Path) -> Result<Self> {
+        let state = match std::fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => OutboxState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            state: Arc::new(Mutex::new(state)),
+            flushing: Arc::new(tokio::sync::Mutex::new(())),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().edits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reserves an id for a playlist created locally
+    pub fn next_local_id(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.next_local_id += 1;
+        FIRST_LOCAL_ID - state.next_local_id
+    }
+
+    /// Whether `id` was handed out by `next_local_id`
+    pub fn is_local_id(id: u64) -> bool {
+        id < FIRST_LOCAL_ID && id > FIRST_LOCAL_ID / 2
+    }
+
+    /// Queues an edit and saves the outbox
+    pub fn push(&self, edit: PlaylistEdit) -> Result<()> {
+        self.state.lock().unwrap().edits.push(edit);
+        self.save()
+    }
+
+    /// Applies every queued edit to a library, so the UI shows edits before
+    /// they reach the server. Safe to repeat on a library that already has
+    /// them. Returns the ids of the playlists touched.
+    pub fn apply_pending(&self, library: &mut Library) -> BTreeSet<u64> {
+        let state = self.state.lock().unwrap();
+        state
+            .edits
+            .iter()
+            .map(|edit| {
+                let edit = resolve(edit, &state.created);
+                apply_edit(&edit, library)
+            })
+            .collect()
+    }
+
+    /// Replays queued edits in order. Stops at the first edit that fails for
+    /// a reason that may pass (network, rate limit, session, cancellation),
+    /// leaving it and everything after it queued.
+    pub async fn flush(&self, client: &IBroadcastClient) -> FlushReport {
+        let _flushing = self.flushing.lock().await;
+        let mut report = FlushReport::default();
+        if self.is_empty() {
+            return report;
+        }
+
+        let result = client.get_library().await;
+        let mut remote = match result {
+            Ok(library) => library,
+            Err(e) => {
+                log::info!("Not replaying playlist edits: {}", e);
+                report.remaining = self.len();
+                return report;
+            }
+        };
+
+        loop {
+            let edit = {
+                let state = self.state.lock().unwrap();
+                match state.edits.first() {
+                    Some(edit) => resolve(edit, &state.created),
+                    None => break,
+                }
+            };
+
+            let outcome = match self.earlier_creation(&edit, &remote) {
+                Some(remote_id) => {
+                    log::info!("Playlist {} was created by an earlier attempt", remote_id);
+                    Replay::Applied(Some(remote_id))
+                }
+                None => replay(client, &edit, &mut remote).await,
+            };
+            let done = {
+                let mut state = self.state.lock().unwrap();
+                if let PlaylistEdit::Create { local_id, .. } = &edit {
+                    if !matches!(outcome, Replay::Retry(_)) {
+                        state.attempted.remove(local_id);
+                    }
+                }
+                match outcome {
+                    Replay::Applied(created) => {
+                        if let (PlaylistEdit::Create { local_id, .. }, Some(remote_id)) =
+                            (&edit, created)
+                        {
+                            state.created.insert(*local_id, remote_id);
+                        }
+                        report.applied += 1;
+                        state.edits.remove(0);
+                        false
+                    }
+                    Replay::Skipped => {
+                        state.edits.remove(0);
+                        false
+                    }
+                    Replay::Conflict(reason) => {
+                        log::warn!("Dropping playlist edit {:?}: {}", edit, reason);
+                        report.conflicts.push(Conflict { edit, reason });
+                        state.edits.remove(0);
+                        false
+                    }
+                    Replay::Retry(e) => {
+                        log::info!("Pausing playlist edit replay: {}", e);
+                        true
+                    }
+                }
+            };
+            if let Err(e) = self.save() {
+                log::error!("Failed to save outbox: {}", e);
+            }
+            if done {
+                break;
+            }
+        }
+
+        report.remaining = self.len();
+        let mut state = self.state.lock().unwrap();
+        // Mappings are only needed while edits may still refer to them
+        if state.edits.is_empty() {
+            state.created.clear();
+        }
+        report
+    }
+
+    /// Finds the playlist an earlier attempt at a creation made, in case
+    /// the server got it but the answer was lost. The first attempt notes
+    /// which playlists have the name already; a playlist of the name that
+    /// isn't among them is the one it made.
+    fn earlier_creation(&self, edit: &PlaylistEdit, remote: &Library) -> Option<u64> {
+        let PlaylistEdit::Create { local_id, name } = edit else {
+            return None;
+        };
+        let named: BTreeSet<u64> = remote
+            .playlists
+            .values()
+            .filter(|playlist| &playlist.name == name)
+            .map(|playlist| playlist.id)
+            .collect();
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(before) = state.attempted.get(local_id) {
+                return named.difference(before).next().copied();
+            }
+            state.attempted.insert(*local_id, named);
+        }
+        // Noted before sending, so a crash mid-request can't lose it
+        if let Err(e) = self.save() {
+            log::error!("Failed to save outbox: {}", e);
+        }
+        None
+    }
+
+    fn save(&self) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temp = self.path.with_extension("json.tmp");
+        std::fs::write(&temp, serde_json::to_vec(&*state)?)?;
+        std::fs::rename(&temp, &self.path)?;
+        Ok(())
+    }
+}
+
+enum Replay {
+    /// Sent; carries the new id for a created playlist
+    Applied(Option<u64>),
+    /// Already in effect on the server
+    Skipped,
+    Conflict(String),
+    /// Couldn't be sent right now; try again later
+    Retry(IBroadcastError),
+}
+
+/// Applies one edit to a library and returns the playlist it touched
+pub fn apply_edit(edit: &PlaylistEdit, library: &mut Library) -> u64 {
+    match edit {
+        PlaylistEdit::Create { local_id, name } => {
+            library
+                .playlists
+                .entry(*local_id)
+                .or_insert_with(|| Playlist {
+                    id: *local_id,
+                    name: name.clone(),
+                    ..Playlist::default()
+                });
+            *local_id
+        }
+        PlaylistEdit::AddTrack { playlist, track_id } => {
+            let id = playlist.id();
+            if let Some(playlist) = library.playlists.get_mut(&id) {
+                if !playlist.tracks.contains(track_id) {
+                    playlist.tracks.push(*track_id);
+                }
+            }
+            id
+        }
+        PlaylistEdit::RemoveTrack { playlist, track_id } => {
+            let id = playlist.id();
+            if let Some(playlist) = library.playlists.get_mut(&id) {
+                playlist.tracks.retain(|track| track != track_id);
+            }
+            id
+        }
+        PlaylistEdit::Delete { playlist, .. } => {
+            let id = playlist.id();
+            library.playlists.remove(&id);
+            id
+        }
+    }
+}
+
+/// Swaps local playlist ids for remote ones where the creation was replayed
+fn resolve(edit: &PlaylistEdit, created: &BTreeMap<u64, u64>) -> PlaylistEdit {
+    let resolve_ref = |playlist: PlaylistRef| match playlist {
+        PlaylistRef::Local(id) => created
+            .get(&id)
+            .map_or(playlist, |remote| PlaylistRef::Remote(*remote)),
+        remote => remote,
+    };
+    match edit.clone() {
+        PlaylistEdit::AddTrack { playlist, track_id } => PlaylistEdit::AddTrack {
+            playlist: resolve_ref(playlist),
+            track_id,
+        },
+        PlaylistEdit::RemoveTrack { playlist, track_id } => PlaylistEdit::RemoveTrack {
+            playlist: resolve_ref(playlist),
+            track_id,
+        },
+        PlaylistEdit::Delete { playlist, tracks } => PlaylistEdit::Delete {
+            playlist: resolve_ref(playlist),
+            tracks,
+        },
+        create => create,
+    }
+}
+
+/// Checks an edit against the server library and sends it if it still
+/// makes sense, keeping `remote` up to date with what was sent
+async fn replay(client: &IBroadcastClient, edit: &PlaylistEdit, remote: &mut Library) -> Replay {
+    let playlist_id = match edit {
+        PlaylistEdit::Create { .. } => None,
+        PlaylistEdit::AddTrack { playlist, .. }
+        | PlaylistEdit::RemoveTrack { playlist, .. }
+        | PlaylistEdit::Delete { playlist, .. } => match playlist {
+            PlaylistRef::Remote(id) => Some(*id),
+            // Its creation was dropped, so there is nothing to edit
+            PlaylistRef::Local(_) => {
+                return Replay::Conflict("The playlist was never created".to_string())
+            }
+        },
+    };
+    let current = playlist_id.and_then(|id| remote.playlists.get(&id));
+
+    let result = match edit {
+        PlaylistEdit::Create { name, .. } => {
+            let result = client.create_playlist(name).await;
+            match result {
+                Ok(id) => {
+                    remote.playlists.insert(
+                        id,
+                        Playlist {
+                            id,
+                            name: name.clone(),
+                            ..Playlist::default()
+                        },
+                    );
+                    return Replay::Applied(Some(id));
+                }
+                Err(e) => Err(e),
+            }
+        }
+        PlaylistEdit::AddTrack { track_id, .. } => {
+            let Some(current) = current else {
+                return Replay::Conflict("The playlist was deleted".to_string());
+            };
+            if !remote.tracks.contains_key(track_id) {
+                return Replay::Conflict("The track was deleted".to_string());
+            }
+            if current.tracks.contains(track_id) {
+                return Replay::Skipped;
+            }
+            let playlist_id = current.id;
+            let result = client
+                .add_to_playlist(&playlist_id.to_string(), &track_id.to_string())
+                .await;
+            if result.is_ok() {
+                if let Some(playlist) = remote.playlists.get_mut(&playlist_id) {
+                    playlist.tracks.push(*track_id);
+                }
+            }
+            result
+        }
+        PlaylistEdit::RemoveTrack { track_id, .. } => {
+            let Some(current) = current else {
+                return Replay::Skipped;
+            };
+            if !current.tracks.contains(track_id) {
+                return Replay::Skipped;
+            }
+            let playlist_id = current.id;
+            let result = client
+                .remove_from_playlist(&playlist_id.to_string(), &track_id.to_string())
+                .await;
+            if result.is_ok() {
+                if let Some(playlist) = remote.playlists.get_mut(&playlist_id) {
+                    playlist.tracks.retain(|track| track != track_id);
+                }
+            }
+            result
+        }
+        PlaylistEdit::Delete { tracks, .. } => {
+            let Some(current) = current else {
+                return Replay::Skipped;
+            };
+            if &current.tracks != tracks {
+                return Replay::Conflict(format!(
+                    "\"{}\" was changed elsewhere since it was deleted",
+                    current.name
+                ));
+            }
+            let playlist_id = current.id;
+            let result = client
+                .delete_playlist(&playlist_id.to_string())
+                .await
+                .map(|_| ());
+            if result.is_ok() {
+                remote.playlists.remove(&playlist_id);
+            }
+            result
+        }
+    };
+
+    match result {
+        Ok(()) => Replay::Applied(None),
+        Err(
+            e @ (IBroadcastError::Network(_)
+            | IBroadcastError::RateLimitExceeded
+            | IBroadcastError::Offline
+            | IBroadcastError::NotLoggedIn
+            | IBroadcastError::Authentication(_)
+            | IBroadcastError::TokenRefresh(_)
+            | IBroadcastError::Cancelled),
+        ) => Replay::Retry(e),
+        Err(e) => Replay::Conflict(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::Track;
+
+    fn library(playlists: &[(u64, &str, &[u64])]) -> Library {
+        let mut library = Library::default();
+        for &(id, name, tracks) in playlists {
+            library.playlists.insert(
+                id,
+                Playlist {
+                    id,
+                    name: name.to_string(),
+                    tracks: tracks.to_vec(),
+                    ..Playlist::default()
+                },
+            );
+            for &track in tracks {
+                library.tracks.insert(
+                    track,
+                    Track {
+                        id: track,
+                        ..Track::default()
+                    },
+                );
+            }
+        }
+        library
+    }
+
+    fn temp_outbox(name: &str) -> (Outbox, PathBuf) {
+        let path = std::env::temp_dir()
+            .join(format!("latke-outbox-{}-{}", std::process::id(), name))
+            .join("outbox.json");
+        let _ = std::fs::remove_file(&path);
+        (Outbox::load(&path).unwrap(), path)
+    }
+
+    #[tokio::test]
+    async fn edits_already_in_effect_are_skipped() {
+        // Never contacted: nothing needs sending
+        let client = IBroadcastClient::new();
+        let mut remote = library(&[(1, "Mix", &[10])]);
+
+        let add = PlaylistEdit::AddTrack {
+            playlist: PlaylistRef::Remote(1),
+            track_id: 10,
+        };
+        let remove = PlaylistEdit::RemoveTrack {
+            playlist: PlaylistRef::Remote(1),
+            track_id: 11,
+        };
+        let delete = PlaylistEdit::Delete {
+            playlist: PlaylistRef::Remote(2),
+            tracks: Vec::new(),
+        };
+
+        for edit in [add, remove, delete] {
+            assert!(matches!(
+                replay(&client, &edit, &mut remote).await,
+                Replay::Skipped
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn edits_whose_target_changed_conflict() {
+        let client = IBroadcastClient::new();
+        let mut remote = library(&[(1, "Mix", &[10, 11])]);
+
+        let to_deleted_playlist = PlaylistEdit::AddTrack {
+            playlist: PlaylistRef::Remote(2),
+            track_id: 10,
+        };
+        let deleted_track = PlaylistEdit::AddTrack {
+            playlist: PlaylistRef::Remote(1),
+            track_id: 12,
+        };
+        let never_created = PlaylistEdit::AddTrack {
+            playlist: PlaylistRef::Local(FIRST_LOCAL_ID - 1),
+            track_id: 10,
+        };
+        let changed_since = PlaylistEdit::Delete {
+            playlist: PlaylistRef::Remote(1),
+            tracks: vec![10],
+        };
+
+        for edit in [
+            to_deleted_playlist,
+            deleted_track,
+            never_created,
+            changed_since,
+        ] {
+            assert!(matches!(
+                replay(&client, &edit, &mut remote).await,
+                Replay::Conflict(_)
+            ));
+        }
+        assert_eq!(remote.playlists[&1].tracks, vec![10, 11]);
+    }
+
+    #[tokio::test]
+    async fn edits_that_cant_be_sent_wait_for_a_retry() {
+        // Logged out, so nothing reaches a server
+        let client = IBroadcastClient::new();
+        let mut remote = library(&[(1, "Mix", &[10])]);
+
+        let create = PlaylistEdit::Create {
+            local_id: FIRST_LOCAL_ID - 1,
+            name: "New".to_string(),
+        };
+        let remove = PlaylistEdit::RemoveTrack {
+            playlist: PlaylistRef::Remote(1),
+            track_id: 10,
+        };
+
+        for edit in [create, remove] {
+            assert!(matches!(
+                replay(&client, &edit, &mut remote).await,
+                Replay::Retry(IBroadcastError::NotLoggedIn)
+            ));
+        }
+        assert_eq!(remote.playlists.len(), 1);
+        assert_eq!(remote.playlists[&1].tracks, vec![10]);
+    }
+
+    #[test]
+    fn a_creation_is_noted_before_it_is_first_sent() {
+        let (outbox, path) = temp_outbox("noted");
+        let local_id = outbox.next_local_id();
+        let edit = PlaylistEdit::Create {
+            local_id,
+            name: "Mix".to_string(),
+        };
+        let before = library(&[(1, "Mix", &[])]);
+
+        assert_eq!(outbox.earlier_creation(&edit, &before), None);
+        let saved = Outbox::load(&path).unwrap();
+        let attempted = saved.state.lock().unwrap().attempted.clone();
+        assert_eq!(attempted[&local_id], BTreeSet::from([1]));
+
+        // The playlist that was there already isn't taken for the new one
+        assert_eq!(outbox.earlier_creation(&edit, &before), None);
+        let after = library(&[(1, "Mix", &[]), (2, "Other", &[]), (3, "Mix", &[])]);
+        assert_eq!(outbox.earlier_creation(&edit, &after), Some(3));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn edits_are_applied_on_the_server() {
+        use crate::api::mock::MockServer;
+
+        let server = MockServer::start().await.unwrap();
+        server.add_account("listener@example.com", "hunter2");
+        server.add_track(
+            Track {
+                id: 10,
+                ..Track::default()
+            },
+            Vec::new(),
+        );
+        let client = IBroadcastClient::with_base_url(server.url());
+        client
+            .login("listener@example.com", "hunter2")
+            .await
+            .unwrap();
+        let mut remote = client.get_library().await.unwrap();
+
+        let create = PlaylistEdit::Create {
+            local_id: FIRST_LOCAL_ID - 1,
+            name: "Mix".to_string(),
+        };
+        let Replay::Applied(Some(id)) = replay(&client, &create, &mut remote).await else {
+            panic!("The playlist wasn't created");
+        };
+        let add = PlaylistEdit::AddTrack {
+            playlist: PlaylistRef::Remote(id),
+            track_id: 10,
+        };
+        assert!(matches!(
+            replay(&client, &add, &mut remote).await,
+            Replay::Applied(None)
+        ));
+
+        assert_eq!(remote.playlists[&id].tracks, vec![10]);
+        assert_eq!(server.library().playlists[&id].tracks, vec![10]);
+    }
+
+    #[cfg(feature = "mock-server")]
+    #[tokio::test]
+    async fn a_creation_whose_answer_was_lost_is_not_repeated() {
+        use crate::api::mock::MockServer;
+
+        let server = MockServer::start().await.unwrap();
+        server.add_account("listener@example.com", "hunter2");
+        let client = IBroadcastClient::with_base_url(server.url());
+        client
+            .login("listener@example.com", "hunter2")
+            .await
+            .unwrap();
+
+        let (outbox, path) = temp_outbox("lost");
+        let create = PlaylistEdit::Create {
+            local_id: outbox.next_local_id(),
+            name: "Mix".to_string(),
+        };
+        outbox.push(create.clone()).unwrap();
+        // As if the first attempt got through but its answer never came
+        outbox.earlier_creation(&create, &Library::default());
+        let id = client.create_playlist("Mix").await.unwrap();
+
+        let report = outbox.flush(&client).await;
+
+        assert_eq!(report.applied, 1);
+        assert!(outbox.is_empty());
+        let creations = server
+            .modes()
+            .into_iter()
+            .filter(|mode| mode == "createplaylist")
+            .count();
+        assert_eq!(creations, 1);
+        assert_eq!(server.library().playlists.len(), 1);
+        assert!(server.library().playlists.contains_key(&id));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
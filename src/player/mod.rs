@@ -0,0 +1,702 @@
+use futures::StreamExt;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app as gst_app;
+use gstreamer_audio::{prelude::*, StreamVolume, StreamVolumeFormat};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::runtime::Handle;
+use tokio::sync::{broadcast, watch, Notify};
+use tokio::task::JoinHandle;
+
+use crate::utils;
+
+const EVENT_CHANNEL_CAPACITY: usize = 64;
+
+/// How much fetched audio the app source buffers before asking us to pause
+const MAX_QUEUED_BYTES: u64 = 2 * 1024 * 1024;
+
+/// URI that makes playbin create an `appsrc` we feed ourselves
+const APPSRC_URI: &str = "appsrc://";
+
+#[derive(Debug, Error)]
+pub enum PlayerError {
+    #[error("Failed to initialize GStreamer: {0}")]
+    Init(String),
+    #[error("Missing GStreamer element: {0}")]
+    MissingElement(String),
+    #[error("State change failed: {0}")]
+    StateChange(String),
+    #[error("Seek failed: {0}")]
+    Seek(String),
+}
+
+/// Receives a copy of every byte the player streams, e.g. to cache it.
+///
+/// Dropping the tee without `finish` means the stream was abandoned.
+pub trait StreamTee: Send + 'static {
+    /// Called with each chunk at its byte offset in the stream. Offsets jump
+    /// when the user seeks.
+    fn write_at(&mut self, offset: u64, data: &[u8]);
+    /// Called once the stream has been read to its end at `size` bytes
+    fn finish(self: Box<Self>, size: u64);
+}
+
+/// A stream fed through the app source rather than fetched by playbin
+#[derive(Default)]
+struct TeedStream {
+    url: Option<String>,
+    /// Taken by the first feeder; replays after a stop don't tee again
+    tee: Option<Box<dyn StreamTee>>,
+    feeder: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayerState {
+    Stopped,
+    Paused,
+    Playing,
+}
+
+/// Events published from the pipeline's bus
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlayerEvent {
+    StateChanged(PlayerState),
+    DurationChanged(Option<Duration>),
+    /// Buffering progress in percent
+    Buffering(i32),
+    EndOfStream,
+    Error(String),
+}
+
+/// Audio player wrapping a GStreamer `playbin` pipeline.
+///
+/// Cloning is cheap and every clone controls the same pipeline.
+#[derive(Clone)]
+pub struct Player {
+    pipeline: Arc<Pipeline>,
+    teed: Arc<Mutex<TeedStream>>,
+    events: broadcast::Sender<PlayerEvent>,
+}
+
+/// Owns the playbin and shuts it down once the last `Player` handle is gone
+struct Pipeline {
+    playbin: gst::Element,
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        let _ = self.playbin.set_state(gst::State::Null);
+    }
+}
+
+impl Player {
+    /// Creates a player that outputs to the default audio device
+    pub fn new() -> Result<Self, PlayerError> {
+        Self::build(None)
+    }
+
+    /// Creates a player that outputs to the given sink element, e.g.
+    /// `fakesink` to drive the pipeline without an audio device
+    pub fn with_audio_sink(factory: &str) -> Result<Self, PlayerError> {
+        Self::build(Some(factory))
+    }
+
+    fn build(audio_sink: Option<&str>) -> Result<Self, PlayerError> {
+        gst::init().map_err(|e| PlayerError::Init(e.to_string()))?;
+
+        let playbin = gst::ElementFactory::make("playbin")
+            .name("latke-player")
+            .build()
+            .map_err(|_| PlayerError::MissingElement("playbin".to_string()))?;
+
+        // Audio only: skip video and subtitle decoding entirely
+        playbin.set_property_from_str("flags", "audio+soft-volume");
+
+        if let Some(factory) = audio_sink {
+            let sink = gst::ElementFactory::make(factory)
+                .build()
+                .map_err(|_| PlayerError::MissingElement(factory.to_string()))?;
+            // Sinks like fakesink default to consuming buffers as fast as possible
+            if sink.find_property("sync").is_some() {
+                sink.set_property("sync", true);
+            }
+            playbin.set_property("audio-sink", &sink);
+        }
+
+        let runtime =
+            Handle::try_current().map_err(|_| PlayerError::Init("no Tokio runtime".to_string()))?;
+
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let player = Self {
+            pipeline: Arc::new(Pipeline { playbin }),
+            teed: Arc::new(Mutex::new(TeedStream::default())),
+            events,
+        };
+        player.watch_bus();
+        player.watch_source_setup(runtime);
+        Ok(player)
+    }
+
+    /// Starts feeding the app source whenever playbin creates one for a
+    /// stream loaded with `load_teed`
+    fn watch_source_setup(&self, runtime: Handle) {
+        let teed = self.teed.clone();
+        let http = reqwest::Client::new();
+        self.pipeline
+            .playbin
+            .connect("source-setup", false, move |values| {
+                let source = values.get(1)?.get::<gst::Element>().ok()?;
+                let appsrc = source.dynamic_cast::<gst_app::AppSrc>().ok()?;
+
+                let mut teed = teed.lock().unwrap();
+                let Some(url) = teed.url.clone() else {
+                    log::warn!("App source created without a stream to feed it");
+                    return None;
+                };
+                let tee = teed.tee.take();
+                let feeder = feed(&runtime, http.clone(), appsrc, url, tee);
+                if let Some(previous) = teed.feeder.replace(feeder) {
+                    previous.abort();
+                }
+                None
+            });
+    }
+
+    /// Forwards bus messages to subscribers until the pipeline is dropped
+    fn watch_bus(&self) {
+        let Some(bus) = self.pipeline.playbin.bus() else {
+            log::error!("Player pipeline has no bus");
+            return;
+        };
+
+        let playbin = self.pipeline.playbin.downgrade();
+        let events = self.events.clone();
+        let mut messages = bus.stream();
+        tokio::spawn(async move {
+            while let Some(message) = messages.next().await {
+                let Some(playbin) = playbin.upgrade() else {
+                    break;
+                };
+                if let Some(event) = event_for_message(&playbin, &message) {
+                    // No subscribers is fine; the UI may not be listening yet
+                    let _ = events.send(event);
+                }
+            }
+        });
+    }
+
+    /// Subscribes to state changes, end-of-stream and errors
+    pub fn subscribe(&self) -> broadcast::Receiver<PlayerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Loads a new stream, stopping whatever was playing
+    pub fn load(&self, uri: &str) -> Result<(), PlayerError> {
+        self.stop()?;
+        self.reset_teed(None, None);
+        log::debug!("Loading stream");
+        self.pipeline.playbin.set_property("uri", uri);
+        Ok(())
+    }
+
+    /// Loads an HTTP stream that is fetched by the player itself, handing
+    /// every byte to `tee` as it arrives
+    pub fn load_teed(&self, url: &str, tee: Box<dyn StreamTee>) -> Result<(), PlayerError> {
+        self.stop()?;
+        self.reset_teed(Some(url.to_string()), Some(tee));
+        log::debug!("Loading teed stream");
+        self.pipeline.playbin.set_property("uri", APPSRC_URI);
+        Ok(())
+    }
+
+    fn reset_teed(&self, url: Option<String>, tee: Option<Box<dyn StreamTee>>) {
+        let mut teed = self.teed.lock().unwrap();
+        if let Some(feeder) = teed.feeder.take() {
+            feeder.abort();
+        }
+        teed.url = url;
+        teed.tee = tee;
+    }
+
+    /// Returns the URI of the loaded stream
+    pub fn uri(&self) -> Option<String> {
+        self.pipeline.playbin.property::<Option<String>>("uri")
+    }
+
+    pub fn play(&self) -> Result<(), PlayerError> {
+        self.set_state(gst::State::Playing)
+    }
+
+    pub fn pause(&self) -> Result<(), PlayerError> {
+        self.set_state(gst::State::Paused)
+    }
+
+    /// Toggles between playing and paused
+    pub fn toggle(&self) -> Result<(), PlayerError> {
+        match self.state() {
+            PlayerState::Playing => self.pause(),
+            _ => self.play(),
+        }
+    }
+
+    pub fn stop(&self) -> Result<(), PlayerError> {
+        self.set_state(gst::State::Null)?;
+        let _ = self.events.send(PlayerEvent::StateChanged(PlayerState::Stopped));
+        Ok(())
+    }
+
+    fn set_state(&self, state: gst::State) -> Result<(), PlayerError> {
+        self.pipeline
+            .playbin
+            .set_state(state)
+            .map(|_| ())
+            .map_err(|e| PlayerError::StateChange(e.to_string()))
+    }
+
+    /// Returns the current (not pending) state of the pipeline
+    pub fn state(&self) -> PlayerState {
+        let (_, current, _) = self.pipeline.playbin.state(Some(gst::ClockTime::ZERO));
+        player_state(current)
+    }
+
+    /// Seeks to an absolute position in the current stream
+    pub fn seek(&self, position: Duration) -> Result<(), PlayerError> {
+        self.pipeline
+            .playbin
+            .seek_simple(
+                gst::SeekFlags::FLUSH | gst::SeekFlags::KEY_UNIT,
+                gst::ClockTime::from_nseconds(position.as_nanos() as u64),
+            )
+            .map_err(|e| PlayerError::Seek(e.to_string()))
+    }
+
+    pub fn position(&self) -> Option<Duration> {
+        self.pipeline
+            .playbin
+            .query_position::<gst::ClockTime>()
+            .map(|position| Duration::from_nanos(position.nseconds()))
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        self.pipeline
+            .playbin
+            .query_duration::<gst::ClockTime>()
+            .map(|duration| Duration::from_nanos(duration.nseconds()))
+    }
+
+    /// Sets the volume from 0.0 to 1.0 on a perceptual (cubic) scale
+    pub fn set_volume(&self, volume: f64) {
+        if let Some(stream_volume) = self.pipeline.playbin.dynamic_cast_ref::<StreamVolume>() {
+            stream_volume.set_volume(StreamVolumeFormat::Cubic, volume.clamp(0.0, 1.0));
+        }
+    }
+
+    /// Returns the volume from 0.0 to 1.0 on a perceptual (cubic) scale
+    pub fn volume(&self) -> f64 {
+        self.pipeline
+            .playbin
+            .dynamic_cast_ref::<StreamVolume>()
+            .map(|stream_volume| stream_volume.volume(StreamVolumeFormat::Cubic))
+            .unwrap_or(1.0)
+    }
+}
+
+/// Fetches `url` into `appsrc`, restarting the request with a byte range
+/// whenever the pipeline seeks
+fn feed(
+    runtime: &Handle,
+    http: reqwest::Client,
+    appsrc: gst_app::AppSrc,
+    url: String,
+    tee: Option<Box<dyn StreamTee>>,
+) -> JoinHandle<()> {
+    let (seeks, seek_rx) = watch::channel(0u64);
+    let wanted = Arc::new(Notify::new());
+    let enough = Arc::new(AtomicBool::new(false));
+
+    appsrc.set_stream_type(gst_app::AppStreamType::Seekable);
+    appsrc.set_max_bytes(MAX_QUEUED_BYTES);
+    appsrc.set_callbacks(
+        gst_app::AppSrcCallbacks::builder()
+            .need_data({
+                let wanted = wanted.clone();
+                let enough = enough.clone();
+                move |_, _| {
+                    enough.store(false, Ordering::SeqCst);
+                    wanted.notify_one();
+                }
+            })
+            .enough_data({
+                let enough = enough.clone();
+                move |_| enough.store(true, Ordering::SeqCst)
+            })
+            .seek_data(move |_, offset| {
+                seeks.send_replace(offset);
+                true
+            })
+            .build(),
+    );
+
+    runtime.spawn(async move {
+        let mut feeder = Feeder {
+            http,
+            appsrc,
+            url,
+            tee,
+            seek_rx,
+            wanted,
+            enough,
+        };
+        if let Err(e) = feeder.run().await {
+            log::error!("Failed to fetch stream: {}", e);
+            gst::element_error!(
+                feeder.appsrc,
+                gst::ResourceError::Read,
+                ["Failed to fetch stream: {}", e]
+            );
+        }
+    })
+}
+
+/// Why feeding the app source stopped
+#[derive(Debug, Error)]
+enum FeedError {
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error("Server sent a range starting at {start} when asked for {offset}")]
+    Range { offset: u64, start: u64 },
+}
+
+enum Next<T> {
+    /// The pipeline seeked; `false` once the app source is gone
+    Seek(bool),
+    Chunk(Option<T>),
+}
+
+struct Feeder {
+    http: reqwest::Client,
+    appsrc: gst_app::AppSrc,
+    url: String,
+    tee: Option<Box<dyn StreamTee>>,
+    seek_rx: watch::Receiver<u64>,
+    wanted: Arc<Notify>,
+    enough: Arc<AtomicBool>,
+}
+
+impl Feeder {
+    async fn run(&mut self) -> Result<(), FeedError> {
+        loop {
+            let offset = *self.seek_rx.borrow_and_update();
+            let mut request = self.http.get(&self.url);
+            if offset > 0 {
+                request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+            }
+            let mut response = request.send().await?.error_for_status()?;
+            if offset == 0 {
+                if let Some(size) = response.content_length() {
+                    self.appsrc.set_size(size as i64);
+                }
+            }
+
+            // A server that ignores the range sends the whole file, so
+            // everything before the offset is skipped
+            let start = match response.status() {
+                reqwest::StatusCode::PARTIAL_CONTENT => utils::content_range(&response)
+                    .map(|(start, _)| start)
+                    .ok_or(FeedError::Range { offset, start: 0 })?,
+                _ => 0,
+            };
+            if start > offset {
+                return Err(FeedError::Range { offset, start });
+            }
+            let mut skip = offset - start;
+            if skip > 0 {
+                log::debug!("Stream ignored the range, skipping {} bytes", skip);
+            }
+
+            let mut position = offset;
+            let mut seeked = false;
+            loop {
+                // Hold off while the app source has plenty queued
+                while self.enough.load(Ordering::SeqCst) && !seeked {
+                    let notified = self.wanted.notified();
+                    tokio::select! {
+                        _ = notified => {}
+                        changed = self.seek_rx.changed() => {
+                            if changed.is_err() {
+                                return Ok(());
+                            }
+                            seeked = true;
+                        }
+                    }
+                }
+                if seeked {
+                    break;
+                }
+
+                let next = tokio::select! {
+                    changed = self.seek_rx.changed() => Next::Seek(changed.is_ok()),
+                    chunk = response.chunk() => Next::Chunk(chunk?),
+                };
+                let chunk = match next {
+                    Next::Seek(true) => {
+                        seeked = true;
+                        break;
+                    }
+                    Next::Seek(false) => return Ok(()),
+                    Next::Chunk(None) => break,
+                    Next::Chunk(Some(chunk)) => chunk,
+                };
+                let chunk = if skip > 0 {
+                    let skipped = skip.min(chunk.len() as u64);
+                    skip -= skipped;
+                    chunk.slice(skipped as usize..)
+                } else {
+                    chunk
+                };
+                if chunk.is_empty() {
+                    continue;
+                }
+
+                if let Some(tee) = &mut self.tee {
+                    tee.write_at(position, &chunk);
+                }
+                position += chunk.len() as u64;
+                if self
+                    .appsrc
+                    .push_buffer(gst::Buffer::from_slice(chunk))
+                    .is_err()
+                {
+                    // Flushing for a seek, or shutting down
+                    if self.seek_rx.changed().await.is_err() {
+                        return Ok(());
+                    }
+                    seeked = true;
+                    break;
+                }
+            }
+            if seeked {
+                continue;
+            }
+
+            // Ending before the offset means the file isn't the one teed so far
+            if skip > 0 {
+                self.tee = None;
+            }
+            if let Some(tee) = self.tee.take() {
+                tee.finish(position);
+            }
+            let _ = self.appsrc.end_of_stream();
+
+            // Seeking back after the end restarts the fetch
+            if self.seek_rx.changed().await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn player_state(state: gst::State) -> PlayerState {
+    match state {
+        gst::State::Playing => PlayerState::Playing,
+        gst::State::Paused => PlayerState::Paused,
+        _ => PlayerState::Stopped,
+    }
+}
+
+fn event_for_message(playbin: &gst::Element, message: &gst::Message) -> Option<PlayerEvent> {
+    use gst::MessageView;
+
+    match message.view() {
+        MessageView::Eos(_) => Some(PlayerEvent::EndOfStream),
+        MessageView::Error(err) => {
+            let debug = err.debug().map(|debug| debug.to_string()).unwrap_or_default();
+            log::error!("Playback error: {} ({})", err.error(), debug);
+            Some(PlayerEvent::Error(err.error().to_string()))
+        }
+        MessageView::StateChanged(change) => {
+            // Children of playbin post their own state changes; only report the pipeline's
+            match change.src() {
+                Some(src) if src == playbin.upcast_ref::<gst::Object>() => {}
+                _ => return None,
+            }
+            if change.old() == change.current() {
+                return None;
+            }
+            Some(PlayerEvent::StateChanged(player_state(change.current())))
+        }
+        MessageView::DurationChanged(_) => Some(PlayerEvent::DurationChanged(
+            playbin
+                .query_duration::<gst::ClockTime>()
+                .map(|duration| Duration::from_nanos(duration.nseconds())),
+        )),
+        MessageView::Buffering(buffering) => Some(PlayerEvent::Buffering(buffering.percent())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+    use tokio::time::{sleep, timeout};
+
+    const EVENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Writes `seconds` of silent 8 kHz mono PCM as a WAV file
+    fn write_wav(path: &Path, seconds: u32) {
+        const RATE: u32 = 8000;
+        let data_len = RATE * 2 * seconds;
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+        wav.extend_from_slice(&RATE.to_le_bytes());
+        wav.extend_from_slice(&(RATE * 2).to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_len.to_le_bytes());
+        wav.resize(wav.len() + data_len as usize, 0);
+        std::fs::write(path, wav).unwrap();
+    }
+
+    /// A WAV file in the temp directory, removed on drop
+    struct TestFile(PathBuf);
+
+    impl TestFile {
+        fn new(name: &str, seconds: u32) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "latke-player-{}-{}.wav",
+                name,
+                std::process::id()
+            ));
+            write_wav(&path, seconds);
+            Self(path)
+        }
+
+        fn uri(&self) -> String {
+            gst::glib::filename_to_uri(&self.0, None)
+                .unwrap()
+                .to_string()
+        }
+    }
+
+    impl Drop for TestFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    async fn wait_for(events: &mut broadcast::Receiver<PlayerEvent>, wanted: PlayerEvent) {
+        let result = timeout(EVENT_TIMEOUT, async {
+            loop {
+                match events.recv().await {
+                    Ok(PlayerEvent::Error(e)) => panic!("Playback failed: {}", e),
+                    Ok(event) if event == wanted => return,
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => panic!("Player events closed"),
+                }
+            }
+        })
+        .await;
+        assert!(result.is_ok(), "Timed out waiting for {:?}", wanted);
+    }
+
+    /// Polls `query` until it passes, as queries lag a moment behind events
+    async fn eventually(mut query: impl FnMut() -> bool) -> bool {
+        for _ in 0..100 {
+            if query() {
+                return true;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn plays_to_the_end() {
+        let file = TestFile::new("eos", 1);
+        let player = Player::with_audio_sink("fakesink").unwrap();
+        let mut events = player.subscribe();
+
+        player.load(&file.uri()).unwrap();
+        player.play().unwrap();
+
+        wait_for(&mut events, PlayerEvent::StateChanged(PlayerState::Playing)).await;
+        assert_eq!(player.state(), PlayerState::Playing);
+        wait_for(&mut events, PlayerEvent::EndOfStream).await;
+    }
+
+    #[tokio::test]
+    async fn pauses_seeks_and_reports_position() {
+        let file = TestFile::new("seek", 3);
+        let player = Player::with_audio_sink("fakesink").unwrap();
+        let mut events = player.subscribe();
+
+        player.load(&file.uri()).unwrap();
+        player.play().unwrap();
+        wait_for(&mut events, PlayerEvent::StateChanged(PlayerState::Playing)).await;
+
+        player.pause().unwrap();
+        wait_for(&mut events, PlayerEvent::StateChanged(PlayerState::Paused)).await;
+        assert_eq!(player.state(), PlayerState::Paused);
+
+        let duration = player.duration().unwrap();
+        assert!(duration.abs_diff(Duration::from_secs(3)) < Duration::from_millis(100));
+
+        player.seek(Duration::from_secs(2)).unwrap();
+        let seeked = eventually(|| {
+            player
+                .position()
+                .is_some_and(|position| position >= Duration::from_millis(1900))
+        })
+        .await;
+        assert!(seeked, "Position after seek: {:?}", player.position());
+
+        player.toggle().unwrap();
+        wait_for(&mut events, PlayerEvent::StateChanged(PlayerState::Playing)).await;
+        wait_for(&mut events, PlayerEvent::EndOfStream).await;
+    }
+
+    #[tokio::test]
+    async fn stop_reports_stopped() {
+        let file = TestFile::new("stop", 2);
+        let player = Player::with_audio_sink("fakesink").unwrap();
+        let mut events = player.subscribe();
+
+        player.load(&file.uri()).unwrap();
+        player.play().unwrap();
+        wait_for(&mut events, PlayerEvent::StateChanged(PlayerState::Playing)).await;
+
+        player.stop().unwrap();
+        wait_for(&mut events, PlayerEvent::StateChanged(PlayerState::Stopped)).await;
+        assert_eq!(player.state(), PlayerState::Stopped);
+        assert_eq!(player.position(), None);
+    }
+
+    #[tokio::test]
+    async fn volume_is_clamped() {
+        let player = Player::with_audio_sink("fakesink").unwrap();
+
+        player.set_volume(0.5);
+        assert!((player.volume() - 0.5).abs() < 0.01);
+        player.set_volume(2.0);
+        assert!((player.volume() - 1.0).abs() < 0.01);
+        player.set_volume(-1.0);
+        assert!(player.volume().abs() < 0.01);
+    }
+
+    #[tokio::test]
+    async fn missing_sink_is_an_error() {
+        let result = Player::with_audio_sink("no-such-sink");
+
+        assert!(matches!(result, Err(PlayerError::MissingElement(_))));
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::utils;
+
+/// An iBroadcast account set up on this machine. Each has its own session
+/// and login in the keyring, library cache, downloads, queue and settings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    /// Names the profile's directories and keyring entries; never changes
+    pub id: String,
+    /// Shown in the account switcher
+    pub name: String,
+}
+
+impl Profile {
+    fn new(name: &str) -> Self {
+        Self {
+            id: format!("{:08x}", rand::random::<u32>()),
+            name: name.to_string(),
+        }
+    }
+
+    /// The profile's library cache, queue and unsent edits
+    pub fn data_dir(&self) -> PathBuf {
+        utils::data_dir().join("profiles").join(&self.id)
+    }
+
+    /// The profile's settings
+    pub fn config_dir(&self) -> PathBuf {
+        utils::config_dir().join("profiles").join(&self.id)
+    }
+
+    /// The profile's downloads and cached streams
+    pub fn cache_dir(&self) -> PathBuf {
+        utils::cache_dir().join("profiles").join(&self.id)
+    }
+
+    /// Keyring username under which one of the profile's secrets is stored
+    pub fn keyring_user(&self, key: &str) -> String {
+        format!("{}/{}", self.id, key)
+    }
+}
+
+/// The profiles set up on this machine and which one is in use, stored as
+/// JSON in the config directory. There is always at least one.
+///
+/// Switching or adding a profile only changes the list in memory; it is
+/// saved once the profile is logged in to, so the next start opens the
+/// last account that was actually used.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profiles {
+    profiles: Vec<Profile>,
+    current: String,
+}
+
+impl Default for Profiles {
+    fn default() -> Self {
+        let first = Profile::new("Account 1");
+        Self {
+            current: first.id.clone(),
+            profiles: vec![first],
+        }
+    }
+}
+
+impl Profiles {
+    /// Default location of the profiles file
+    pub fn default_path() -> PathBuf {
+        utils::config_dir().join("profiles.json")
+    }
+
+    /// Loads the profiles. On a fresh install the first profile is set up
+    /// and saved right away, taking over data from before profiles existed.
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut profiles: Self = match std::fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let profiles = Self::default();
+                adopt_legacy_data(profiles.current());
+                profiles.save(path)?;
+                return Ok(profiles);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // Edited by hand, perhaps
+        if profiles.profiles.is_empty() {
+            profiles = Self::default();
+        } else if !profiles.profiles.iter().any(|p| p.id == profiles.current) {
+            profiles.current = profiles.profiles[0].id.clone();
+        }
+        Ok(profiles)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temp = path.with_extension("json.tmp");
+        std::fs::write(&temp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    pub fn all(&self) -> &[Profile] {
+        &self.profiles
+    }
+
+    /// The profile in use
+    pub fn current(&self) -> &Profile {
+        self.profiles
+            .iter()
+            .find(|profile| profile.id == self.current)
+            .unwrap_or(&self.profiles[0])
+    }
+
+    /// Puts the profile with `id` in use. Returns it, or `None` if there
+    /// is no such profile.
+    pub fn switch_to(&mut self, id: &str) -> Option<Profile> {
+        let profile = self.profiles.iter().find(|profile| profile.id == id)?;
+        self.current = profile.id.clone();
+        Some(profile.clone())
+    }
+
+    /// Adds a profile named `name`, or "Account <n>" if it is blank
+    pub fn add(&mut self, name: &str) -> Profile {
+        let name = name.trim();
+        let profile = if name.is_empty() {
+            Profile::new(&format!("Account {}", self.profiles.len() + 1))
+        } else {
+            Profile::new(name)
+        };
+        self.profiles.push(profile.clone());
+        profile
+    }
+}
+
+/// Moves the files and secrets Latke kept before profiles existed into
+/// `profile`. Whatever fails to move is left where it was.
+fn adopt_legacy_data(profile: &Profile) {
+    let moves: [(PathBuf, PathBuf, &[&str]); 3] = [
+        (
+            utils::data_dir(),
+            profile.data_dir(),
+            &[
+                "library.db",
+                "library.db-wal",
+                "library.db-shm",
+                "queue.json",
+                "outbox.json",
+            ],
+        ),
+        (
+            utils::config_dir(),
+            profile.config_dir(),
+            &["settings.json"],
+        ),
+        (
+            utils::cache_dir(),
+            profile.cache_dir(),
+            &["stream", "tracks"],
+        ),
+    ];
+    for (from, to, names) in moves {
+        for name in names {
+            let source = from.join(name);
+            if !source.exists() {
+                continue;
+            }
+            let result =
+                std::fs::create_dir_all(&to).and_then(|()| std::fs::rename(&source, to.join(name)));
+            match result {
+                Ok(()) => log::info!("Moved {} into profile {}", name, profile.id),
+                Err(e) => log::warn!("Failed to move {} into profile: {}", source.display(), e),
+            }
+        }
+    }
+
+    if let Err(e) = utils::adopt_legacy_secrets(profile) {
+        log::warn!("Failed to move stored secrets into profile: {}", e);
+    }
+}
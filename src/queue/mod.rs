@@ -0,0 +1,575 @@
+use anyhow::Result;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::profiles::Profile;
+
+/// Number of played tracks kept for going back
+const MAX_HISTORY: usize = 200;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    One,
+    All,
+}
+
+/// A queued track. The same track may be queued several times, so entries
+/// carry their own id to stay distinguishable across shuffles and moves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueEntry {
+    pub uid: u64,
+    pub track_id: u64,
+}
+
+/// Play queue with shuffle, repeat and a back-history.
+///
+/// `entries` is always the playback order. While shuffled, the order the
+/// user built is kept in `unshuffled` so it can be restored.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Queue {
+    entries: Vec<QueueEntry>,
+    unshuffled: Option<Vec<u64>>,
+    current: Option<usize>,
+    repeat: RepeatMode,
+    history: Vec<QueueEntry>,
+    next_uid: u64,
+    /// Playback position within the current track, saved for restoring
+    #[serde(default)]
+    position_ms: u64,
+}
+
+impl Queue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Default location of the profile's persisted queue
+    pub fn default_path(profile: &Profile) -> PathBuf {
+        profile.data_dir().join("queue.json")
+    }
+
+    /// Loads a saved queue, starting empty if there is none
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(json) => {
+                let mut queue: Self = serde_json::from_str(&json)?;
+                if queue
+                    .current
+                    .is_some_and(|current| current >= queue.entries.len())
+                {
+                    queue.current = None;
+                }
+                Ok(queue)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the queue atomically so a crash never leaves a truncated file
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temp = path.with_extension("json.tmp");
+        std::fs::write(&temp, serde_json::to_vec(self)?)?;
+        std::fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    /// Entries in playback order
+    pub fn entries(&self) -> &[QueueEntry] {
+        &self.entries
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Index of the current entry in playback order
+    pub fn current_index(&self) -> Option<usize> {
+        self.current
+    }
+
+    pub fn current(&self) -> Option<QueueEntry> {
+        self.current
+            .and_then(|index| self.entries.get(index).copied())
+    }
+
+    /// Previously played entries, most recent last
+    #[allow(dead_code)]
+    pub fn history(&self) -> &[QueueEntry] {
+        &self.history
+    }
+
+    pub fn is_shuffled(&self) -> bool {
+        self.unshuffled.is_some()
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat
+    }
+
+    pub fn set_repeat(&mut self, repeat: RepeatMode) {
+        self.repeat = repeat;
+    }
+
+    pub fn position_ms(&self) -> u64 {
+        self.position_ms
+    }
+
+    pub fn set_position_ms(&mut self, position_ms: u64) {
+        self.position_ms = position_ms;
+    }
+
+    fn make_entries(&mut self, track_ids: &[u64]) -> Vec<QueueEntry> {
+        track_ids
+            .iter()
+            .map(|&track_id| {
+                self.next_uid += 1;
+                QueueEntry {
+                    uid: self.next_uid,
+                    track_id,
+                }
+            })
+            .collect()
+    }
+
+    /// Replaces the queue with `track_ids` and starts at `start`
+    pub fn replace(&mut self, track_ids: &[u64], start: usize) -> Option<QueueEntry> {
+        self.push_current_to_history();
+        let entries = self.make_entries(track_ids);
+        let shuffled = self.is_shuffled();
+
+        self.entries = entries;
+        self.unshuffled = None;
+        self.current = (start < self.entries.len()).then_some(start);
+        self.position_ms = 0;
+        if shuffled {
+            self.set_shuffle(true);
+        }
+        self.current()
+    }
+
+    /// Inserts tracks right after the current one and skips to the first
+    #[allow(dead_code)]
+    pub fn play_now(&mut self, track_ids: &[u64]) -> Option<QueueEntry> {
+        let Some(first) = self.insert_next(track_ids) else {
+            return self.current();
+        };
+        self.push_current_to_history();
+        self.current = Some(first);
+        self.position_ms = 0;
+        self.current()
+    }
+
+    /// Inserts tracks right after the current one
+    pub fn play_next(&mut self, track_ids: &[u64]) {
+        self.insert_next(track_ids);
+    }
+
+    /// Appends tracks to the end of the queue
+    pub fn add_to_end(&mut self, track_ids: &[u64]) {
+        let entries = self.make_entries(track_ids);
+        if let Some(unshuffled) = &mut self.unshuffled {
+            unshuffled.extend(entries.iter().map(|entry| entry.uid));
+        }
+        self.entries.extend(entries);
+    }
+
+    /// Inserts after the current entry in both orders, returning the index
+    /// of the first inserted entry
+    fn insert_next(&mut self, track_ids: &[u64]) -> Option<usize> {
+        if track_ids.is_empty() {
+            return None;
+        }
+        let entries = self.make_entries(track_ids);
+        let at = self.current.map_or(0, |current| current + 1);
+
+        if let Some(unshuffled) = &mut self.unshuffled {
+            let current_uid = self.current.map(|current| self.entries[current].uid);
+            let unshuffled_at = current_uid
+                .and_then(|uid| unshuffled.iter().position(|&u| u == uid))
+                .map_or(0, |position| position + 1);
+            unshuffled.splice(
+                unshuffled_at..unshuffled_at,
+                entries.iter().map(|entry| entry.uid),
+            );
+        }
+        self.entries.splice(at..at, entries);
+        Some(at)
+    }
+
+    /// Moves an entry within the playback order. The order shuffle off
+    /// restores is left alone.
+    pub fn move_entry(&mut self, from: usize, to: usize) {
+        if from >= self.entries.len() || to >= self.entries.len() || from == to {
+            return;
+        }
+        let current_uid = self.current().map(|entry| entry.uid);
+        let entry = self.entries.remove(from);
+        self.entries.insert(to, entry);
+        self.current = current_uid.and_then(|uid| self.index_of(uid));
+    }
+
+    /// Removes an entry. Removing the current entry makes the following one current.
+    pub fn remove(&mut self, index: usize) -> Option<QueueEntry> {
+        if index >= self.entries.len() {
+            return None;
+        }
+        let removed = self.entries.remove(index);
+        if let Some(unshuffled) = &mut self.unshuffled {
+            unshuffled.retain(|&uid| uid != removed.uid);
+        }
+        if self.current == Some(index) {
+            self.position_ms = 0;
+        }
+        self.current = match self.current {
+            Some(current) if current > index => Some(current - 1),
+            Some(current) if current == index && current >= self.entries.len() => None,
+            current => current,
+        };
+        Some(removed)
+    }
+
+    pub fn clear(&mut self) {
+        self.push_current_to_history();
+        self.entries.clear();
+        self.unshuffled = None;
+        self.current = None;
+        self.position_ms = 0;
+    }
+
+    /// Makes the entry at `index` current, e.g. when picked from the queue view
+    pub fn jump_to(&mut self, index: usize) -> Option<QueueEntry> {
+        if index >= self.entries.len() {
+            return None;
+        }
+        if self.current != Some(index) {
+            self.push_current_to_history();
+        }
+        self.current = Some(index);
+        self.position_ms = 0;
+        self.current()
+    }
+
+    /// Turns shuffle on or off. The current entry stays current, and turning
+    /// shuffle off restores the order the queue had before it was shuffled.
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        if shuffle == self.is_shuffled() {
+            return;
+        }
+        let current_uid = self.current().map(|entry| entry.uid);
+
+        if shuffle {
+            self.unshuffled = Some(self.entries.iter().map(|entry| entry.uid).collect());
+            // The current entry leads, followed by everything else in random order
+            let mut rest: Vec<QueueEntry> = self
+                .entries
+                .iter()
+                .copied()
+                .filter(|entry| Some(entry.uid) != current_uid)
+                .collect();
+            rest.shuffle(&mut rand::thread_rng());
+            self.entries = self.current().into_iter().chain(rest).collect();
+            self.current = current_uid.map(|_| 0);
+        } else if let Some(order) = self.unshuffled.take() {
+            let mut entries = Vec::with_capacity(self.entries.len());
+            for uid in order {
+                if let Some(entry) = self.entries.iter().find(|entry| entry.uid == uid) {
+                    entries.push(*entry);
+                }
+            }
+            self.entries = entries;
+            self.current = current_uid.and_then(|uid| self.index_of(uid));
+        }
+    }
+
+    /// Advances to the next entry. With `auto` set (the track finished on
+    /// its own) repeat-one replays the current entry; an explicit skip always
+    /// moves on.
+    pub fn next(&mut self, auto: bool) -> Option<QueueEntry> {
+        if auto && self.repeat == RepeatMode::One && self.current.is_some() {
+            self.position_ms = 0;
+            return self.current();
+        }
+
+        let next = match self.current {
+            Some(current) if current + 1 < self.entries.len() => Some(current + 1),
+            Some(_) | None if self.repeat != RepeatMode::Off && !self.entries.is_empty() => {
+                // Start the next round in a fresh random order
+                if self.is_shuffled() {
+                    self.push_current_to_history();
+                    self.current = None;
+                    self.set_shuffle(false);
+                    self.set_shuffle(true);
+                    self.current = Some(0);
+                    self.position_ms = 0;
+                    return self.current();
+                }
+                Some(0)
+            }
+            None if !self.entries.is_empty() => Some(0),
+            _ => None,
+        };
+
+        match next {
+            Some(next) => {
+                self.push_current_to_history();
+                self.current = Some(next);
+                self.position_ms = 0;
+                self.current()
+            }
+            None => None,
+        }
+    }
+
+    /// Goes back to the most recently played entry
+    pub fn previous(&mut self) -> Option<QueueEntry> {
+        let entry = self.history.pop()?;
+        let index = match self.index_of(entry.uid) {
+            Some(index) => index,
+            // Played before the queue was replaced: bring it back in front of the current entry
+            None => {
+                let at = self.current.unwrap_or(0);
+                self.entries.insert(at, entry);
+                if let Some(unshuffled) = &mut self.unshuffled {
+                    unshuffled.insert(0, entry.uid);
+                }
+                at
+            }
+        };
+        self.current = Some(index);
+        self.position_ms = 0;
+        self.current()
+    }
+
+    fn index_of(&self, uid: u64) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.uid == uid)
+    }
+
+    fn push_current_to_history(&mut self) {
+        if let Some(entry) = self.current() {
+            self.history.push(entry);
+            if self.history.len() > MAX_HISTORY {
+                self.history.remove(0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track_ids(queue: &Queue) -> Vec<u64> {
+        queue.entries().iter().map(|entry| entry.track_id).collect()
+    }
+
+    #[test]
+    fn shuffle_off_restores_the_original_order() {
+        let mut queue = Queue::new();
+        let tracks: Vec<u64> = (1..=20).collect();
+        queue.replace(&tracks, 4);
+
+        queue.set_shuffle(true);
+        assert!(queue.is_shuffled());
+        assert_eq!(queue.current_index(), Some(0));
+        assert_eq!(queue.current().unwrap().track_id, 5);
+        let mut shuffled = track_ids(&queue);
+        shuffled.sort_unstable();
+        assert_eq!(shuffled, tracks);
+
+        queue.set_shuffle(false);
+        assert!(!queue.is_shuffled());
+        assert_eq!(track_ids(&queue), tracks);
+        assert_eq!(queue.current_index(), Some(4));
+    }
+
+    #[test]
+    fn tracks_added_while_shuffled_keep_their_place() {
+        let mut queue = Queue::new();
+        queue.replace(&[1, 2, 3], 0);
+        queue.set_shuffle(true);
+
+        queue.play_next(&[10]);
+        queue.add_to_end(&[20]);
+        queue.set_shuffle(false);
+
+        assert_eq!(track_ids(&queue), vec![1, 10, 2, 3, 20]);
+    }
+
+    #[test]
+    fn moved_entries_keep_the_current_one_current() {
+        let mut queue = Queue::new();
+        queue.replace(&[1, 2, 3, 4, 5], 2);
+
+        queue.move_entry(2, 0);
+        assert_eq!(track_ids(&queue), vec![3, 1, 2, 4, 5]);
+        assert_eq!(queue.current_index(), Some(0));
+
+        queue.move_entry(4, 0);
+        assert_eq!(track_ids(&queue), vec![5, 3, 1, 2, 4]);
+        assert_eq!(queue.current_index(), Some(1));
+        assert_eq!(queue.current().unwrap().track_id, 3);
+    }
+
+    #[test]
+    fn moves_while_shuffled_keep_the_current_one_current() {
+        let mut queue = Queue::new();
+        queue.replace(&[1, 2, 3, 4, 5], 2);
+        queue.set_shuffle(true);
+        let shuffled = track_ids(&queue);
+
+        queue.move_entry(0, 4);
+        assert_eq!(queue.current_index(), Some(4));
+        assert_eq!(queue.current().unwrap().track_id, 3);
+
+        queue.move_entry(1, 4);
+        assert_eq!(track_ids(&queue)[3], 3);
+        assert_eq!(queue.current_index(), Some(3));
+        assert_ne!(track_ids(&queue), shuffled);
+
+        // Moves only change the shuffled order
+        queue.set_shuffle(false);
+        assert_eq!(track_ids(&queue), vec![1, 2, 3, 4, 5]);
+        assert_eq!(queue.current_index(), Some(2));
+    }
+
+    #[test]
+    fn removing_the_current_entry_moves_on_to_the_next() {
+        let mut queue = Queue::new();
+        queue.replace(&[1, 2, 3, 4], 2);
+        queue.set_position_ms(42_000);
+
+        assert_eq!(queue.remove(0).unwrap().track_id, 1);
+        assert_eq!(queue.current_index(), Some(1));
+        assert_eq!(queue.position_ms(), 42_000);
+
+        assert_eq!(queue.remove(1).unwrap().track_id, 3);
+        assert_eq!(queue.current().unwrap().track_id, 4);
+        assert_eq!(queue.position_ms(), 0);
+
+        queue.remove(1);
+        assert_eq!(queue.current(), None);
+        assert_eq!(queue.remove(5), None);
+    }
+
+    #[test]
+    fn removing_while_shuffled_drops_the_entry_from_both_orders() {
+        let mut queue = Queue::new();
+        queue.replace(&[1, 2, 3, 4], 0);
+        queue.set_shuffle(true);
+        let index = queue
+            .entries()
+            .iter()
+            .position(|entry| entry.track_id == 3)
+            .unwrap();
+
+        queue.remove(index);
+        assert_eq!(queue.current().unwrap().track_id, 1);
+        queue.set_shuffle(false);
+
+        assert_eq!(track_ids(&queue), vec![1, 2, 4]);
+        assert_eq!(queue.current_index(), Some(0));
+    }
+
+    #[test]
+    fn clear_empties_the_queue_but_keeps_history() {
+        let mut queue = Queue::new();
+        queue.replace(&[1, 2], 1);
+
+        queue.clear();
+
+        assert!(queue.is_empty());
+        assert_eq!(queue.current(), None);
+        assert_eq!(queue.history().last().unwrap().track_id, 2);
+    }
+
+    #[test]
+    fn repeat_one_replays_only_when_the_track_ends() {
+        let mut queue = Queue::new();
+        queue.replace(&[1, 2], 0);
+        queue.set_repeat(RepeatMode::One);
+
+        assert_eq!(queue.next(true).unwrap().track_id, 1);
+        assert_eq!(queue.next(false).unwrap().track_id, 2);
+    }
+
+    #[test]
+    fn repeat_all_wraps_around() {
+        let mut queue = Queue::new();
+        queue.replace(&[1, 2], 1);
+
+        assert_eq!(queue.next(true), None);
+
+        queue.jump_to(1);
+        queue.set_repeat(RepeatMode::All);
+        assert_eq!(queue.next(true).unwrap().track_id, 1);
+    }
+
+    #[test]
+    fn previous_walks_back_through_history() {
+        let mut queue = Queue::new();
+        queue.replace(&[1, 2, 3], 0);
+        queue.next(false);
+        queue.next(false);
+
+        assert_eq!(queue.previous().unwrap().track_id, 2);
+        assert_eq!(queue.previous().unwrap().track_id, 1);
+        assert_eq!(queue.previous(), None);
+    }
+
+    #[test]
+    fn history_is_capped() {
+        let mut queue = Queue::new();
+        let tracks: Vec<u64> = (0..MAX_HISTORY as u64 + 50).collect();
+        queue.replace(&tracks, 0);
+        while queue.next(false).is_some() {}
+
+        assert_eq!(queue.history().len(), MAX_HISTORY);
+        assert_eq!(queue.history().last().unwrap().track_id, *tracks.last().unwrap() - 1);
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let dir = std::env::temp_dir().join(format!("latke-queue-{}", std::process::id()));
+        let path = dir.join("queue.json");
+        let mut queue = Queue::new();
+        queue.replace(&[1, 2, 3], 1);
+        queue.set_shuffle(true);
+        queue.set_repeat(RepeatMode::All);
+        queue.set_position_ms(42_000);
+
+        queue.save(&path).unwrap();
+        let mut loaded = Queue::load(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded.entries(), queue.entries());
+        assert_eq!(loaded.current(), queue.current());
+        assert_eq!(loaded.repeat(), RepeatMode::All);
+        assert_eq!(loaded.position_ms(), 42_000);
+        assert_eq!(loaded.history(), queue.history());
+        loaded.set_shuffle(false);
+        assert_eq!(track_ids(&loaded), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn missing_file_loads_an_empty_queue() {
+        let path = std::env::temp_dir().join("latke-queue-missing/queue.json");
+
+        let queue = Queue::load(&path).unwrap();
+
+        assert!(queue.is_empty());
+        assert_eq!(queue.current(), None);
+    }
+}
@@ -0,0 +1,32 @@
+use std::future::Future;
+use std::sync::OnceLock;
+use tokio::runtime::Handle;
+
+static HANDLE: OnceLock<Handle> = OnceLock::new();
+
+/// Remembers the runtime created in `main` so code on the glib main loop,
+/// which isn't inside it, can hand work to it
+pub fn init(handle: Handle) {
+    if HANDLE.set(handle).is_err() {
+        log::warn!("Runtime handle was already set");
+    }
+}
+
+/// Runs a future on the tokio runtime and resolves with its output.
+///
+/// Awaited from a `glib::spawn_future_local` task, this keeps network work
+/// and response decoding off the UI thread, and lets several requests run
+/// at once. A panic in the future is resumed in the caller.
+pub async fn spawn<F>(future: F) -> F::Output
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let handle = HANDLE.get().cloned().unwrap_or_else(Handle::current);
+    match handle.spawn(future).await {
+        Ok(output) => output,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        // Tasks are only cancelled when the runtime shuts down with the app
+        Err(e) => panic!("Runtime task failed: {}", e),
+    }
+}
@@ -0,0 +1,58 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::profiles::Profile;
+
+/// User preferences, stored as JSON in the profile's config directory.
+///
+/// Missing fields fall back to their defaults, so settings files written by
+/// older versions keep loading.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Disk space for recently streamed tracks, in MiB. 0 disables the cache.
+    pub stream_cache_quota_mb: u64,
+    /// Stay off the network: browse the cached library, play only local
+    /// copies and queue playlist edits for later
+    pub work_offline: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            stream_cache_quota_mb: 1024,
+            work_offline: false,
+        }
+    }
+}
+
+impl Settings {
+    /// Default location of the profile's settings file
+    pub fn default_path(profile: &Profile) -> PathBuf {
+        profile.config_dir().join("settings.json")
+    }
+
+    /// Loads settings, using the defaults if the file doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(json) => Ok(serde_json::from_str(&json)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temp = path.with_extension("json.tmp");
+        std::fs::write(&temp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    pub fn stream_cache_quota_bytes(&self) -> u64 {
+        self.stream_cache_quota_mb * 1024 * 1024
+    }
+}
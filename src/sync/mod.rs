@@ -0,0 +1,495 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::api::{
+    Album, Artist, IBroadcastClient, IBroadcastError, Library, Playlist, Tag, Track, Trash,
+};
+use crate::db::LibraryCache;
+use crate::runtime;
+
+const EVENT_CHANNEL_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ItemKind {
+    Track,
+    Album,
+    Artist,
+    Playlist,
+    Tag,
+}
+
+/// A single library entry, as carried by a change
+#[derive(Debug, Clone, PartialEq)]
+pub enum LibraryItem {
+    Track(Track),
+    Album(Album),
+    Artist(Artist),
+    Playlist(Playlist),
+    Tag(Tag),
+}
+
+impl LibraryItem {
+    pub fn kind(&self) -> ItemKind {
+        match self {
+            Self::Track(_) => ItemKind::Track,
+            Self::Album(_) => ItemKind::Album,
+            Self::Artist(_) => ItemKind::Artist,
+            Self::Playlist(_) => ItemKind::Playlist,
+            Self::Tag(_) => ItemKind::Tag,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        match self {
+            Self::Track(track) => track.id,
+            Self::Album(album) => album.id,
+            Self::Artist(artist) => artist.id,
+            Self::Playlist(playlist) => playlist.id,
+            Self::Tag(tag) => tag.id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LibraryChange {
+    Added(LibraryItem),
+    Changed(LibraryItem),
+    Removed(ItemKind, u64),
+    TrashChanged(Trash),
+}
+
+#[derive(Debug, Clone)]
+pub enum SyncEvent {
+    Started,
+    /// The whole library was replaced, e.g. on the first sync with no cache
+    Reloaded(Arc<Library>),
+    /// Changes since the previous sync, in an order that adds artists and
+    /// albums before the tracks that refer to them
+    Changed(Arc<Vec<LibraryChange>>),
+    Finished {
+        changes: usize,
+        at: SystemTime,
+    },
+    Failed(String),
+    /// Stopped on request before anything was changed
+    Cancelled,
+}
+
+/// Keeps the local library in step with the server.
+///
+/// The API has no way to ask for changes since a point in time, so every
+/// sync fetches the full payload and diffs it against the last synced copy.
+/// Only the differences are written to the cache and published to the UI.
+#[derive(Clone)]
+pub struct SyncEngine {
+    client: IBroadcastClient,
+    cache: Option<Arc<Mutex<LibraryCache>>>,
+    /// Library as of the last sync, used as the base for the next diff
+    snapshot: Arc<Mutex<Option<Library>>>,
+    running: Arc<AtomicBool>,
+    events: broadcast::Sender<SyncEvent>,
+}
+
+impl SyncEngine {
+    pub fn new(client: IBroadcastClient, cache: Option<Arc<Mutex<LibraryCache>>>) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self {
+            client,
+            cache,
+            snapshot: Arc::new(Mutex::new(None)),
+            running: Arc::new(AtomicBool::new(false)),
+            events,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<SyncEvent> {
+        self.events.subscribe()
+    }
+
+    /// When the cache was last synced, if ever
+    pub async fn last_synced(&self) -> Option<SystemTime> {
+        let cache = self.cache.clone()?;
+        match tokio::task::spawn_blocking(move || cache.lock().unwrap().last_synced()).await {
+            Ok(Ok(at)) => at,
+            Ok(Err(e)) => {
+                log::warn!("Failed to read last sync time: {}", e);
+                None
+            }
+            Err(e) => {
+                log::warn!("Library cache task failed: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Loads the cached library and makes it the base for the next sync
+    pub async fn load_cached(&self) -> Option<Library> {
+        let cache = self.cache.clone()?;
+        let result =
+            tokio::task::spawn_blocking(move || cache.lock().unwrap().load_library()).await;
+        let library = match result {
+            Ok(Ok(library)) => library?,
+            Ok(Err(e)) => {
+                log::warn!("Failed to read library cache: {}", e);
+                return None;
+            }
+            Err(e) => {
+                log::warn!("Library cache task failed: {}", e);
+                return None;
+            }
+        };
+        self.snapshot.lock().unwrap().replace(library.clone());
+        Some(library)
+    }
+
+    /// Fetches the library, publishes what changed and updates the cache.
+    /// Returns the number of changes, or 0 if a sync was already running.
+    ///
+    /// Cancelling `cancel` stops the fetch. Once the library has arrived the
+    /// sync runs to the end, so the cache is never left partly updated.
+    pub async fn sync(&self, cancel: &CancellationToken) -> Result<usize> {
+        let Some(_running) = RunningGuard::acquire(&self.running) else {
+            log::debug!("Library sync already in progress");
+            return Ok(0);
+        };
+        let _ = self.events.send(SyncEvent::Started);
+
+        let result = self.run(cancel).await;
+        match &result {
+            Ok(changes) => {
+                let _ = self.events.send(SyncEvent::Finished {
+                    changes: *changes,
+                    at: SystemTime::now(),
+                });
+            }
+            Err(e) if matches!(e.downcast_ref(), Some(IBroadcastError::Cancelled)) => {
+                log::info!("Library sync cancelled");
+                let _ = self.events.send(SyncEvent::Cancelled);
+            }
+            Err(e) => {
+                log::error!("Library sync failed: {}", e);
+                let _ = self.events.send(SyncEvent::Failed(e.to_string()));
+            }
+        }
+        result
+    }
+
+    async fn run(&self, cancel: &CancellationToken) -> Result<usize> {
+        let client = self.client.with_cancellation(cancel.clone());
+        let fresh = runtime::spawn(async move { client.get_library().await }).await?;
+
+        let previous = self.snapshot.lock().unwrap().take();
+        let Some(previous) = previous else {
+            log::info!("No synced library yet, storing a full copy");
+            let fresh = Arc::new(fresh);
+            let stored = fresh.clone();
+            self.write_cache(move |cache| cache.store_library(&stored))
+                .await?;
+            self.snapshot.lock().unwrap().replace((*fresh).clone());
+            // Everything is new, so count every track as a change
+            let changes = fresh.tracks.len();
+            let _ = self.events.send(SyncEvent::Reloaded(fresh));
+            return Ok(changes);
+        };
+
+        let changes = Arc::new(diff(&previous, &fresh));
+        log::info!("Library sync found {} changes", changes.len());
+
+        let cached = changes.clone();
+        if let Err(e) = self
+            .write_cache(move |cache| cache.apply_changes(&cached))
+            .await
+        {
+            // Keep the old base so the next sync finds the same changes again
+            self.snapshot.lock().unwrap().replace(previous);
+            return Err(e);
+        }
+        self.snapshot.lock().unwrap().replace(fresh);
+
+        if !changes.is_empty() {
+            let _ = self.events.send(SyncEvent::Changed(changes.clone()));
+        }
+        Ok(changes.len())
+    }
+
+    async fn write_cache<F>(&self, write: F) -> Result<()>
+    where
+        F: FnOnce(&mut LibraryCache) -> Result<()> + Send + 'static,
+    {
+        let Some(cache) = self.cache.clone() else {
+            return Ok(());
+        };
+        tokio::task::spawn_blocking(move || write(&mut cache.lock().unwrap()))
+            .await
+            .map_err(|e| anyhow!("Library cache task failed: {}", e))?
+    }
+}
+
+/// Marks a sync as running until dropped, so a sync future that is dropped
+/// halfway doesn't block every later one
+struct RunningGuard<'a>(&'a AtomicBool);
+
+impl<'a> RunningGuard<'a> {
+    /// Returns `None` if a sync is already running
+    fn acquire(running: &'a AtomicBool) -> Option<Self> {
+        (!running.swap(true, Ordering::SeqCst)).then_some(Self(running))
+    }
+}
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Lists the changes that turn `old` into `new`
+pub fn diff(old: &Library, new: &Library) -> Vec<LibraryChange> {
+    let mut changes = Vec::new();
+    diff_items(
+        &old.artists,
+        &new.artists,
+        ItemKind::Artist,
+        LibraryItem::Artist,
+        &mut changes,
+    );
+    diff_items(
+        &old.albums,
+        &new.albums,
+        ItemKind::Album,
+        LibraryItem::Album,
+        &mut changes,
+    );
+    diff_items(
+        &old.tracks,
+        &new.tracks,
+        ItemKind::Track,
+        LibraryItem::Track,
+        &mut changes,
+    );
+    diff_items(
+        &old.playlists,
+        &new.playlists,
+        ItemKind::Playlist,
+        LibraryItem::Playlist,
+        &mut changes,
+    );
+    diff_items(
+        &old.tags,
+        &new.tags,
+        ItemKind::Tag,
+        LibraryItem::Tag,
+        &mut changes,
+    );
+    if old.trash != new.trash {
+        changes.push(LibraryChange::TrashChanged(new.trash.clone()));
+    }
+    changes
+}
+
+fn diff_items<T: Clone + PartialEq>(
+    old: &BTreeMap<u64, T>,
+    new: &BTreeMap<u64, T>,
+    kind: ItemKind,
+    wrap: fn(T) -> LibraryItem,
+    changes: &mut Vec<LibraryChange>,
+) {
+    for (id, item) in new {
+        match old.get(id) {
+            None => changes.push(LibraryChange::Added(wrap(item.clone()))),
+            Some(previous) if previous != item => {
+                changes.push(LibraryChange::Changed(wrap(item.clone())))
+            }
+            Some(_) => {}
+        }
+    }
+    for id in old.keys().filter(|id| !new.contains_key(id)) {
+        changes.push(LibraryChange::Removed(kind, *id));
+    }
+}
+
+/// Applies changes from a sync to an in-memory library
+pub fn apply_changes(library: &mut Library, changes: &[LibraryChange]) {
+    for change in changes {
+        match change {
+            LibraryChange::Added(item) | LibraryChange::Changed(item) => match item.clone() {
+                LibraryItem::Track(track) => {
+                    library.tracks.insert(track.id, track);
+                }
+                LibraryItem::Album(album) => {
+                    library.albums.insert(album.id, album);
+                }
+                LibraryItem::Artist(artist) => {
+                    library.artists.insert(artist.id, artist);
+                }
+                LibraryItem::Playlist(playlist) => {
+                    library.playlists.insert(playlist.id, playlist);
+                }
+                LibraryItem::Tag(tag) => {
+                    library.tags.insert(tag.id, tag);
+                }
+            },
+            LibraryChange::Removed(kind, id) => match kind {
+                ItemKind::Track => {
+                    library.tracks.remove(id);
+                }
+                ItemKind::Album => {
+                    library.albums.remove(id);
+                }
+                ItemKind::Artist => {
+                    library.artists.remove(id);
+                }
+                ItemKind::Playlist => {
+                    library.playlists.remove(id);
+                }
+                ItemKind::Tag => {
+                    library.tags.remove(id);
+                }
+            },
+            LibraryChange::TrashChanged(trash) => library.trash = trash.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(id: u64, title: &str) -> Track {
+        Track {
+            id,
+            title: title.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn album(id: u64, name: &str, tracks: Vec<u64>) -> Album {
+        Album {
+            id,
+            name: name.to_string(),
+            tracks,
+            ..Default::default()
+        }
+    }
+
+    fn playlist(id: u64, name: &str, tracks: Vec<u64>) -> Playlist {
+        Playlist {
+            id,
+            name: name.to_string(),
+            tracks,
+            ..Default::default()
+        }
+    }
+
+    fn library() -> Library {
+        let mut library = Library::default();
+        for track in [track(1, "One"), track(2, "Two"), track(3, "Three")] {
+            library.tracks.insert(track.id, track);
+        }
+        library.albums.insert(10, album(10, "First", vec![1, 2]));
+        library.albums.insert(11, album(11, "Second", vec![3]));
+        library
+            .playlists
+            .insert(20, playlist(20, "Mix", vec![1, 3]));
+        library.playlists.insert(21, playlist(21, "Old", vec![2]));
+        library
+    }
+
+    /// A library with every kind of change from `library()`
+    fn changed_library() -> Library {
+        let mut library = library();
+        library.tracks.insert(4, track(4, "Four"));
+        library.tracks.get_mut(&2).unwrap().title = "Two (Live)".to_string();
+        library.tracks.remove(&3);
+        library.albums.insert(12, album(12, "Third", vec![4]));
+        library.albums.get_mut(&10).unwrap().tracks = vec![1];
+        library.albums.remove(&11);
+        library.playlists.insert(22, playlist(22, "New", vec![4]));
+        library.playlists.get_mut(&20).unwrap().tracks = vec![1, 4];
+        library.playlists.remove(&21);
+        library.trash.tracks = vec![3];
+        library
+    }
+
+    fn assert_same(a: &Library, b: &Library) {
+        assert_eq!(a.tracks, b.tracks);
+        assert_eq!(a.albums, b.albums);
+        assert_eq!(a.artists, b.artists);
+        assert_eq!(a.playlists, b.playlists);
+        assert_eq!(a.tags, b.tags);
+        assert_eq!(a.trash, b.trash);
+    }
+
+    #[test]
+    fn unchanged_library_has_no_changes() {
+        assert!(diff(&library(), &library()).is_empty());
+    }
+
+    #[test]
+    fn diff_lists_added_changed_and_removed_items() {
+        let new = changed_library();
+
+        let changes = diff(&library(), &new);
+
+        let expected = [
+            LibraryChange::Added(LibraryItem::Album(new.albums[&12].clone())),
+            LibraryChange::Changed(LibraryItem::Album(new.albums[&10].clone())),
+            LibraryChange::Removed(ItemKind::Album, 11),
+            LibraryChange::Added(LibraryItem::Track(new.tracks[&4].clone())),
+            LibraryChange::Changed(LibraryItem::Track(new.tracks[&2].clone())),
+            LibraryChange::Removed(ItemKind::Track, 3),
+            LibraryChange::Added(LibraryItem::Playlist(new.playlists[&22].clone())),
+            LibraryChange::Changed(LibraryItem::Playlist(new.playlists[&20].clone())),
+            LibraryChange::Removed(ItemKind::Playlist, 21),
+            LibraryChange::TrashChanged(Trash { tracks: vec![3] }),
+        ];
+        assert_eq!(changes.len(), expected.len());
+        for change in &expected {
+            assert!(changes.contains(change), "Missing {:?}", change);
+        }
+    }
+
+    #[test]
+    fn trash_changes_alone_are_reported() {
+        let mut new = library();
+        new.trash.tracks = vec![2];
+
+        let changes = diff(&library(), &new);
+
+        assert_eq!(
+            changes,
+            vec![LibraryChange::TrashChanged(Trash { tracks: vec![2] })]
+        );
+    }
+
+    #[test]
+    fn applying_the_diff_turns_old_into_new() {
+        let old = library();
+        let new = changed_library();
+
+        let mut applied = old.clone();
+        apply_changes(&mut applied, &diff(&old, &new));
+        assert_same(&applied, &new);
+
+        // And back again
+        apply_changes(&mut applied, &diff(&new, &old));
+        assert_same(&applied, &old);
+    }
+
+    #[test]
+    fn running_guard_allows_one_sync_at_a_time() {
+        let running = AtomicBool::new(false);
+
+        let guard = RunningGuard::acquire(&running);
+        assert!(guard.is_some());
+        assert!(RunningGuard::acquire(&running).is_none());
+
+        drop(guard);
+        assert!(!running.load(Ordering::SeqCst));
+        assert!(RunningGuard::acquire(&running).is_some());
+    }
+}
@@ -0,0 +1,117 @@
+use adw::prelude::*;
+use gtk::{Box as GtkBox, Button, CheckButton, Label};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Confirms logging out and asks whether offline data goes too
+#[derive(Clone)]
+pub struct LogoutDialog {
+    window: adw::Window,
+    wipe_check: CheckButton,
+    on_confirm: Rc<RefCell<Option<Rc<dyn Fn(bool)>>>>,
+}
+
+impl LogoutDialog {
+    pub fn new(parent: &impl IsA<gtk::Window>) -> Self {
+        let window = adw::Window::builder()
+            .transient_for(parent)
+            .modal(true)
+            .title("Log Out")
+            .default_width(360)
+            .resizable(false)
+            .build();
+
+        let box_ = GtkBox::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .spacing(12)
+            .margin_top(12)
+            .margin_bottom(12)
+            .margin_start(12)
+            .margin_end(12)
+            .build();
+
+        let title_label = Label::builder()
+            .label("Log out of iBroadcast?")
+            .css_classes(vec!["title-2"])
+            .build();
+
+        let body_label = Label::builder()
+            .label("Playback, sync and downloads stop, and the saved session and login are removed.")
+            .wrap(true)
+            .wrap_mode(gtk::pango::WrapMode::Word)
+            .build();
+
+        let wipe_check = CheckButton::builder()
+            .label("Also remove downloads and the cached library")
+            .build();
+
+        let wipe_hint = Label::builder()
+            .label("Keep them to listen offline after logging in again.")
+            .wrap(true)
+            .xalign(0.0)
+            .css_classes(vec!["dim-label"])
+            .build();
+
+        let cancel_button = Button::builder().label("Cancel").build();
+
+        let logout_button = Button::builder()
+            .label("Log Out")
+            .css_classes(vec!["destructive-action"])
+            .build();
+
+        let buttons = GtkBox::builder()
+            .orientation(gtk::Orientation::Horizontal)
+            .spacing(12)
+            .halign(gtk::Align::End)
+            .build();
+        buttons.append(&cancel_button);
+        buttons.append(&logout_button);
+
+        box_.append(&title_label);
+        box_.append(&body_label);
+        box_.append(&wipe_check);
+        box_.append(&wipe_hint);
+        box_.append(&buttons);
+
+        let content = GtkBox::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        content.append(&adw::HeaderBar::new());
+        content.append(&box_);
+        window.set_content(Some(&content));
+        window.set_default_widget(Some(&cancel_button));
+
+        let dialog = Self {
+            window,
+            wipe_check,
+            on_confirm: Rc::new(RefCell::new(None)),
+        };
+
+        let this = dialog.clone();
+        cancel_button.connect_clicked(move |_| this.window.close());
+
+        let this = dialog.clone();
+        logout_button.connect_clicked(move |_| {
+            this.window.close();
+            let callback = this.on_confirm.borrow().clone();
+            if let Some(callback) = callback {
+                callback(this.wipe_check.is_active());
+            }
+        });
+
+        dialog
+    }
+
+    pub fn show(&self) {
+        self.window.present();
+    }
+
+    /// Sets the callback invoked when the user confirms, with whether to
+    /// remove offline data as well
+    pub fn connect_confirm<F>(&self, callback: F)
+    where
+        F: Fn(bool) + 'static,
+    {
+        self.on_confirm.replace(Some(Rc::new(callback)));
+    }
+}
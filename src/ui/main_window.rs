@@ -0,0 +1,2082 @@
+use adw::prelude::*;
+use gtk::{gdk, Application, Box as GtkBox, ColumnView, ColumnViewColumn, Label, ScrolledWindow, Spinner};
+use glib::BoxedAnyObject;
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+
+use crate::api::{Album, Artist, IBroadcastClient, Library, Playlist, Track};
+use crate::cache::StreamCache;
+use crate::db::LibraryCache;
+use crate::downloads::{DownloadEvent, DownloadManager, Pin, DEFAULT_CONCURRENCY};
+use crate::network::NetworkWatcher;
+use crate::outbox::{apply_edit, FlushReport, Outbox, PlaylistEdit, PlaylistRef};
+use crate::player::{Player, PlayerEvent, PlayerState};
+use crate::profiles::Profile;
+use crate::queue::Queue;
+use crate::runtime;
+use crate::settings::Settings;
+use crate::sync::{self, ItemKind, LibraryChange, SyncEngine, SyncEvent};
+use crate::utils;
+use super::logout_dialog::LogoutDialog;
+use super::player_bar::{PlayerBar, PlayerBarAction};
+use super::preferences::PreferencesWindow;
+
+/// How often the library is synced in the background while the window is open
+const SYNC_INTERVAL_SECS: u32 = 15 * 60;
+
+/// Where the account switcher goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProfileChoice {
+    /// The profile with this id
+    Existing(String),
+    /// A new profile with this name, which may be blank
+    New(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackRow {
+    pub id: u64,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub track_number: Option<u32>,
+    pub year: Option<u32>,
+    pub genre: String,
+    pub length: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AlbumRow {
+    pub id: u64,
+    pub name: String,
+    pub artist: String,
+    pub year: Option<u32>,
+    pub track_count: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArtistRow {
+    pub id: u64,
+    pub name: String,
+    pub track_count: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct PlaylistRow {
+    pub id: u64,
+    pub name: String,
+    pub description: String,
+    pub track_count: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueueRow {
+    pub index: usize,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub length: Option<u32>,
+    pub current: bool,
+}
+
+/// One sidebar page: a sortable column view over a list store of rows
+#[derive(Clone)]
+struct BrowserPage {
+    store: gio::ListStore,
+    view: ColumnView,
+    /// Rows can be dragged onto each other to move them
+    reorderable: bool,
+    on_move: Rc<RefCell<Option<Rc<dyn Fn(u32, u32)>>>>,
+}
+
+impl BrowserPage {
+    fn new() -> Self {
+        let store = gio::ListStore::new::<BoxedAnyObject>();
+        let view = ColumnView::builder()
+            .show_row_separators(true)
+            .reorderable(false)
+            .build();
+
+        // Rows are only created for the visible range, so large stores scroll smoothly
+        let sorted = gtk::SortListModel::new(Some(store.clone()), view.sorter());
+        view.set_model(Some(&gtk::MultiSelection::new(Some(sorted))));
+
+        Self {
+            store,
+            view,
+            reorderable: false,
+            on_move: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    /// A page whose rows the user can reorder by dragging
+    fn reorderable() -> Self {
+        Self {
+            reorderable: true,
+            ..Self::new()
+        }
+    }
+
+    fn widget(&self) -> ScrolledWindow {
+        ScrolledWindow::builder()
+            .hscrollbar_policy(gtk::PolicyType::Never)
+            .vexpand(true)
+            .child(&self.view)
+            .build()
+    }
+
+    /// Adds a text column whose header sorts the rows with `compare`
+    fn add_column<T: 'static>(
+        &self,
+        title: &str,
+        expand: bool,
+        text: impl Fn(&T) -> String + 'static,
+        compare: impl Fn(&T, &T) -> Ordering + 'static,
+    ) -> ColumnViewColumn {
+        let factory = gtk::SignalListItemFactory::new();
+        let on_move = self.reorderable.then(|| self.on_move.clone());
+        factory.connect_setup(move |_, list_item| {
+            let list_item = list_item
+                .downcast_ref::<gtk::ListItem>()
+                .expect("Needs to be a ListItem");
+            let label = Label::builder()
+                .xalign(0.0)
+                .ellipsize(gtk::pango::EllipsizeMode::End)
+                .build();
+            if let Some(on_move) = &on_move {
+                Self::add_drag_controllers(&label, list_item, on_move);
+            }
+            list_item.set_child(Some(&label));
+        });
+
+        factory.connect_bind(move |_, list_item| {
+            let list_item = list_item
+                .downcast_ref::<gtk::ListItem>()
+                .expect("Needs to be a ListItem");
+            let (Some(item), Some(label)) = (
+                list_item.item().and_downcast::<BoxedAnyObject>(),
+                list_item.child().and_downcast::<Label>(),
+            ) else {
+                return;
+            };
+            label.set_text(&text(&item.borrow::<T>()));
+        });
+
+        let sorter = gtk::CustomSorter::new(move |a, b| {
+            let a = a.downcast_ref::<BoxedAnyObject>().expect("Needs to be a BoxedAnyObject");
+            let b = b.downcast_ref::<BoxedAnyObject>().expect("Needs to be a BoxedAnyObject");
+            compare(&a.borrow::<T>(), &b.borrow::<T>()).into()
+        });
+
+        let column = ColumnViewColumn::builder()
+            .title(title)
+            .factory(&factory)
+            .sorter(&sorter)
+            .expand(expand)
+            .resizable(true)
+            .build();
+        self.view.append_column(&column);
+        column
+    }
+
+    /// Lets a row be dragged by `cell` and other rows be dropped on it. The
+    /// drag carries the row's position in the sorted model.
+    fn add_drag_controllers(
+        cell: &Label,
+        list_item: &gtk::ListItem,
+        on_move: &Rc<RefCell<Option<Rc<dyn Fn(u32, u32)>>>>,
+    ) {
+        let drag = gtk::DragSource::new();
+        drag.set_actions(gdk::DragAction::MOVE);
+        let item = list_item.downgrade();
+        drag.connect_prepare(move |_, _, _| {
+            let position = item.upgrade()?.position();
+            Some(gdk::ContentProvider::for_value(&position.to_value()))
+        });
+        cell.add_controller(drag);
+
+        let drop = gtk::DropTarget::new(u32::static_type(), gdk::DragAction::MOVE);
+        let item = list_item.downgrade();
+        let on_move = on_move.clone();
+        drop.connect_drop(move |_, value, _, _| {
+            let (Some(item), Ok(from)) = (item.upgrade(), value.get::<u32>()) else {
+                return false;
+            };
+            let callback = on_move.borrow().clone();
+            let Some(callback) = callback else {
+                return false;
+            };
+            callback(from, item.position());
+            true
+        });
+        cell.add_controller(drop);
+    }
+
+    /// Calls `callback` with the dragged row and the row it was dropped on
+    fn connect_row_moved<T: Clone + 'static>(&self, callback: impl Fn(T, T) + 'static) {
+        // Weak, as the cells hold this callback and the view holds the cells
+        let view = self.view.downgrade();
+        let row_at = move |position: u32| {
+            let item = view
+                .upgrade()?
+                .model()
+                .and_then(|model| model.item(position))
+                .and_downcast::<BoxedAnyObject>()?;
+            let row = item.borrow::<T>().clone();
+            Some(row)
+        };
+        self.on_move.replace(Some(Rc::new(move |from, to| {
+            if let (Some(from), Some(to)) = (row_at(from), row_at(to)) {
+                callback(from, to);
+            }
+        })));
+    }
+
+    /// Calls `callback` with the row the user activates (double-click or Enter)
+    fn connect_row_activated<T: Clone + 'static>(&self, callback: impl Fn(T) + 'static) {
+        self.view.connect_activate(move |view, position| {
+            let Some(item) = view
+                .model()
+                .and_then(|model| model.item(position))
+                .and_downcast::<BoxedAnyObject>()
+            else {
+                return;
+            };
+            let row = item.borrow::<T>().clone();
+            callback(row);
+        });
+    }
+
+    /// Returns all rows in the order currently shown
+    fn visible_rows<T: Clone + 'static>(&self) -> Vec<T> {
+        let Some(model) = self.view.model() else {
+            return Vec::new();
+        };
+        (0..model.n_items())
+            .filter_map(|position| model.item(position).and_downcast::<BoxedAnyObject>())
+            .map(|item| item.borrow::<T>().clone())
+            .collect()
+    }
+
+    /// Returns the selected rows in the order currently shown
+    fn selected_rows<T: Clone + 'static>(&self) -> Vec<T> {
+        let Some(model) = self.view.model() else {
+            return Vec::new();
+        };
+        (0..model.n_items())
+            .filter(|&position| model.is_selected(position))
+            .filter_map(|position| model.item(position).and_downcast::<BoxedAnyObject>())
+            .map(|item| item.borrow::<T>().clone())
+            .collect()
+    }
+
+    fn replace_rows<T: 'static>(&self, rows: Vec<T>) {
+        let items: Vec<BoxedAnyObject> = rows.into_iter().map(BoxedAnyObject::new).collect();
+        self.store.splice(0, self.store.n_items(), &items);
+    }
+
+    /// Replaces, removes or adds the rows for `ids` without touching the
+    /// rest. `row_for` returns `None` for ids that should no longer be shown.
+    fn update_rows<T: 'static>(
+        &self,
+        ids: &BTreeSet<u64>,
+        row_for: impl Fn(u64) -> Option<T>,
+        id_of: impl Fn(&T) -> u64,
+    ) {
+        if ids.is_empty() {
+            return;
+        }
+
+        let mut positions = HashMap::new();
+        for position in 0..self.store.n_items() {
+            if let Some(item) = self.store.item(position).and_downcast::<BoxedAnyObject>() {
+                let id = id_of(&item.borrow::<T>());
+                if ids.contains(&id) {
+                    positions.insert(id, position);
+                }
+            }
+        }
+
+        let mut removed = Vec::new();
+        let mut added = Vec::new();
+        for &id in ids {
+            match (positions.get(&id), row_for(id)) {
+                (Some(&position), Some(row)) => {
+                    // Splicing (rather than mutating the boxed row) lets the
+                    // sort model and bound cells see the change
+                    self.store.splice(position, 1, &[BoxedAnyObject::new(row)]);
+                }
+                (Some(&position), None) => removed.push(position),
+                (None, Some(row)) => added.push(BoxedAnyObject::new(row)),
+                (None, None) => {}
+            }
+        }
+
+        removed.sort_unstable();
+        for position in removed.into_iter().rev() {
+            self.store.remove(position);
+        }
+        self.store.extend_from_slice(&added);
+    }
+}
+
+#[derive(Clone)]
+pub struct MainWindow {
+    window: adw::ApplicationWindow,
+    status_label: Label,
+    spinner: Spinner,
+    download_label: Label,
+    stack: gtk::Stack,
+    artists: BrowserPage,
+    albums: BrowserPage,
+    tracks: BrowserPage,
+    playlists: BrowserPage,
+    queue_page: BrowserPage,
+    player_bar: PlayerBar,
+    client: IBroadcastClient,
+    player: Player,
+    library: Rc<RefCell<Library>>,
+    sync: SyncEngine,
+    sync_button: gtk::Button,
+    /// Shown in place of the sync button while a sync runs
+    cancel_sync_button: gtk::Button,
+    /// Cancelled by the cancel button, then replaced for the next sync
+    sync_cancel: Rc<RefCell<CancellationToken>>,
+    /// `sync_library` runs still going, as edits may start one mid-sync
+    syncs_running: Rc<Cell<usize>>,
+    offline_button: gtk::ToggleButton,
+    /// Shown while offline, whether by choice or for lack of a network
+    connection_label: Label,
+    network: NetworkWatcher,
+    /// Set while the user chose to work offline
+    work_offline: Rc<Cell<bool>>,
+    /// Playlist edits not yet sent. `None` if the outbox couldn't be loaded.
+    outbox: Option<Outbox>,
+    /// `None` if the download directory couldn't be set up
+    downloads: Option<DownloadManager>,
+    /// Bytes downloaded and expected for each queued or running download
+    download_progress: Rc<RefCell<HashMap<u64, (u64, Option<u64>)>>>,
+    /// `None` if the cache directory couldn't be set up
+    stream_cache: Option<StreamCache>,
+    settings: Rc<RefCell<Settings>>,
+    queue: Rc<RefCell<Queue>>,
+    /// Position to seek to once the loaded track starts playing
+    pending_seek: Rc<Cell<Option<Duration>>>,
+    logout_button: gtk::Button,
+    on_logout: Rc<RefCell<Option<Rc<dyn Fn()>>>>,
+    /// Account whose data the window shows
+    profile: Profile,
+    on_switch_profile: Rc<RefCell<Option<Rc<dyn Fn(ProfileChoice)>>>>,
+    /// Cancelled when the window closes. The player and network watcher
+    /// outlive the window after a logout, so watching them has to stop.
+    closed: CancellationToken,
+}
+
+impl MainWindow {
+    /// Opens the window for `profile`. `profiles` are offered in the
+    /// account switcher.
+    pub fn new(
+        app: &Application,
+        client: IBroadcastClient,
+        network: NetworkWatcher,
+        player: Player,
+        profile: Profile,
+        profiles: &[Profile],
+    ) -> Self {
+        let window = adw::ApplicationWindow::builder()
+            .application(app)
+            .title("Latke")
+            .default_width(1100)
+            .default_height(700)
+            .build();
+
+        let spinner = Spinner::new();
+        let status_label = Label::builder()
+            .label("")
+            .css_classes(vec!["dim-label"])
+            .build();
+
+        let play_next_button = gtk::Button::builder()
+            .label("Play Next")
+            .tooltip_text("Play the selected tracks after the current one")
+            .build();
+        let add_to_queue_button = gtk::Button::builder()
+            .label("Add to Queue")
+            .tooltip_text("Add the selected tracks to the end of the queue")
+            .build();
+
+        let sync_button = gtk::Button::builder()
+            .icon_name("view-refresh-symbolic")
+            .tooltip_text("Sync Library")
+            .build();
+        let cancel_sync_button = gtk::Button::builder()
+            .icon_name("process-stop-symbolic")
+            .tooltip_text("Cancel Sync")
+            .visible(false)
+            .build();
+
+        let download_button = gtk::Button::builder()
+            .label("Download for Offline")
+            .css_classes(vec!["flat"])
+            .build();
+        let remove_download_button = gtk::Button::builder()
+            .label("Remove Download")
+            .css_classes(vec!["flat"])
+            .build();
+        let offline_box = GtkBox::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        offline_box.append(&download_button);
+        offline_box.append(&remove_download_button);
+        let offline_popover = gtk::Popover::builder().child(&offline_box).build();
+        let offline_button = gtk::MenuButton::builder()
+            .icon_name("folder-download-symbolic")
+            .tooltip_text("Offline")
+            .popover(&offline_popover)
+            .build();
+
+        let download_label = Label::builder()
+            .label("")
+            .css_classes(vec!["dim-label"])
+            .build();
+
+        let new_playlist_entry = gtk::Entry::builder()
+            .placeholder_text("New playlist name")
+            .build();
+        let create_playlist_button = gtk::Button::builder().label("Create").build();
+        let create_box = GtkBox::builder()
+            .orientation(gtk::Orientation::Horizontal)
+            .spacing(6)
+            .build();
+        create_box.append(&new_playlist_entry);
+        create_box.append(&create_playlist_button);
+        let playlist_choice = gtk::DropDown::from_strings(&[]);
+        let add_to_playlist_button = gtk::Button::builder()
+            .label("Add Selected Tracks")
+            .css_classes(vec!["flat"])
+            .build();
+        let remove_from_playlist_button = gtk::Button::builder()
+            .label("Remove Selected Tracks")
+            .css_classes(vec!["flat"])
+            .build();
+        let delete_playlist_button = gtk::Button::builder()
+            .label("Delete Selected Playlists")
+            .css_classes(vec!["flat"])
+            .build();
+        let playlist_box = GtkBox::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .spacing(6)
+            .build();
+        playlist_box.append(&create_box);
+        playlist_box.append(&gtk::Separator::new(gtk::Orientation::Horizontal));
+        playlist_box.append(&playlist_choice);
+        playlist_box.append(&add_to_playlist_button);
+        playlist_box.append(&remove_from_playlist_button);
+        playlist_box.append(&gtk::Separator::new(gtk::Orientation::Horizontal));
+        playlist_box.append(&delete_playlist_button);
+        let playlist_popover = gtk::Popover::builder().child(&playlist_box).build();
+        let playlist_button = gtk::MenuButton::builder()
+            .icon_name("view-list-symbolic")
+            .tooltip_text("Edit Playlists")
+            .popover(&playlist_popover)
+            .build();
+
+        let connection_label = Label::builder()
+            .label("Offline")
+            .css_classes(vec!["warning"])
+            .visible(false)
+            .build();
+
+        let offline_mode_button = gtk::ToggleButton::builder()
+            .icon_name("network-offline-symbolic")
+            .tooltip_text("Work Offline")
+            .build();
+
+        let preferences_button = gtk::Button::builder()
+            .icon_name("preferences-system-symbolic")
+            .tooltip_text("Preferences")
+            .build();
+
+        let logout_button = gtk::Button::builder()
+            .icon_name("system-log-out-symbolic")
+            .tooltip_text("Log Out")
+            .build();
+
+        let profile_box = GtkBox::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .spacing(6)
+            .build();
+        let mut profile_buttons = Vec::new();
+        for other in profiles.iter().filter(|other| other.id != profile.id) {
+            let button = gtk::Button::builder()
+                .label(other.name.as_str())
+                .css_classes(vec!["flat"])
+                .build();
+            profile_box.append(&button);
+            profile_buttons.push((other.id.clone(), button));
+        }
+        if !profile_buttons.is_empty() {
+            profile_box.append(&gtk::Separator::new(gtk::Orientation::Horizontal));
+        }
+        let new_profile_entry = gtk::Entry::builder()
+            .placeholder_text("New account name")
+            .build();
+        let add_profile_button = gtk::Button::builder().label("Add").build();
+        let add_profile_box = GtkBox::builder()
+            .orientation(gtk::Orientation::Horizontal)
+            .spacing(6)
+            .build();
+        add_profile_box.append(&new_profile_entry);
+        add_profile_box.append(&add_profile_button);
+        profile_box.append(&add_profile_box);
+        let profile_popover = gtk::Popover::builder().child(&profile_box).build();
+        let profile_button = gtk::MenuButton::builder()
+            .label(profile.name.as_str())
+            .tooltip_text("Switch Account")
+            .popover(&profile_popover)
+            .build();
+
+        let header = adw::HeaderBar::new();
+        header.pack_start(&play_next_button);
+        header.pack_start(&add_to_queue_button);
+        header.pack_start(&offline_button);
+        header.pack_start(&playlist_button);
+        header.pack_end(&logout_button);
+        header.pack_end(&profile_button);
+        header.pack_end(&preferences_button);
+        header.pack_end(&offline_mode_button);
+        header.pack_end(&connection_label);
+        header.pack_end(&sync_button);
+        header.pack_end(&cancel_sync_button);
+        header.pack_end(&download_label);
+        header.pack_end(&spinner);
+        header.pack_end(&status_label);
+
+        let artists = BrowserPage::new();
+        let name = artists.add_column("Artist", true, |row: &ArtistRow| row.name.clone(), |a, b| {
+            compare_text(&a.name, &b.name)
+        });
+        artists.add_column("Tracks", false, |row: &ArtistRow| row.track_count.to_string(), |a, b| {
+            a.track_count.cmp(&b.track_count)
+        });
+        artists.view.sort_by_column(Some(&name), gtk::SortType::Ascending);
+
+        let albums = BrowserPage::new();
+        let name = albums.add_column("Album", true, |row: &AlbumRow| row.name.clone(), |a, b| {
+            compare_text(&a.name, &b.name)
+        });
+        albums.add_column("Artist", true, |row: &AlbumRow| row.artist.clone(), |a, b| {
+            compare_text(&a.artist, &b.artist).then_with(|| compare_text(&a.name, &b.name))
+        });
+        albums.add_column("Year", false, |row: &AlbumRow| format_optional(row.year), |a, b| {
+            a.year.cmp(&b.year)
+        });
+        albums.add_column("Tracks", false, |row: &AlbumRow| row.track_count.to_string(), |a, b| {
+            a.track_count.cmp(&b.track_count)
+        });
+        albums.view.sort_by_column(Some(&name), gtk::SortType::Ascending);
+
+        let tracks = BrowserPage::new();
+        tracks.add_column("#", false, |row: &TrackRow| format_optional(row.track_number), |a, b| {
+            a.track_number.cmp(&b.track_number)
+        });
+        tracks.add_column("Title", true, |row: &TrackRow| row.title.clone(), |a, b| {
+            compare_text(&a.title, &b.title)
+        });
+        let artist = tracks.add_column("Artist", true, |row: &TrackRow| row.artist.clone(), |a, b| {
+            compare_text(&a.artist, &b.artist)
+                .then_with(|| compare_text(&a.album, &b.album))
+                .then_with(|| a.track_number.cmp(&b.track_number))
+        });
+        tracks.add_column("Album", true, |row: &TrackRow| row.album.clone(), |a, b| {
+            compare_text(&a.album, &b.album).then_with(|| a.track_number.cmp(&b.track_number))
+        });
+        tracks.add_column("Year", false, |row: &TrackRow| format_optional(row.year), |a, b| {
+            a.year.cmp(&b.year)
+        });
+        tracks.add_column("Genre", false, |row: &TrackRow| row.genre.clone(), |a, b| {
+            compare_text(&a.genre, &b.genre)
+        });
+        tracks.add_column("Length", false, |row: &TrackRow| format_length(row.length), |a, b| {
+            a.length.cmp(&b.length)
+        });
+        tracks.view.sort_by_column(Some(&artist), gtk::SortType::Ascending);
+
+        let playlists = BrowserPage::new();
+        let name = playlists.add_column("Playlist", true, |row: &PlaylistRow| row.name.clone(), |a, b| {
+            compare_text(&a.name, &b.name)
+        });
+        playlists.add_column("Description", true, |row: &PlaylistRow| row.description.clone(), |a, b| {
+            compare_text(&a.description, &b.description)
+        });
+        playlists.add_column("Tracks", false, |row: &PlaylistRow| row.track_count.to_string(), |a, b| {
+            a.track_count.cmp(&b.track_count)
+        });
+        playlists.view.sort_by_column(Some(&name), gtk::SortType::Ascending);
+
+        // The queue is shown in playback order, so every column sorts by position
+        let queue_page = BrowserPage::reorderable();
+        let by_index = |a: &QueueRow, b: &QueueRow| a.index.cmp(&b.index);
+        queue_page.add_column(
+            "",
+            false,
+            |row: &QueueRow| {
+                if row.current {
+                    "▶".to_string()
+                } else {
+                    String::new()
+                }
+            },
+            by_index,
+        );
+        queue_page.add_column("Title", true, |row: &QueueRow| row.title.clone(), by_index);
+        queue_page.add_column("Artist", true, |row: &QueueRow| row.artist.clone(), by_index);
+        queue_page.add_column("Album", true, |row: &QueueRow| row.album.clone(), by_index);
+        queue_page.add_column("Length", false, |row: &QueueRow| format_length(row.length), by_index);
+
+        let remove_from_queue_button = gtk::Button::builder()
+            .label("Remove")
+            .tooltip_text("Remove the selected tracks from the queue")
+            .build();
+        let clear_queue_button = gtk::Button::builder()
+            .label("Clear Queue")
+            .build();
+        let queue_actions = gtk::ActionBar::new();
+        queue_actions.pack_start(&remove_from_queue_button);
+        queue_actions.pack_end(&clear_queue_button);
+        let queue_box = GtkBox::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        queue_box.append(&queue_page.widget());
+        queue_box.append(&queue_actions);
+
+        let stack = gtk::Stack::builder()
+            .hexpand(true)
+            .transition_type(gtk::StackTransitionType::Crossfade)
+            .build();
+        stack.add_titled(&artists.widget(), Some("artists"), "Artists");
+        stack.add_titled(&albums.widget(), Some("albums"), "Albums");
+        stack.add_titled(&tracks.widget(), Some("tracks"), "Tracks");
+        stack.add_titled(&playlists.widget(), Some("playlists"), "Playlists");
+        stack.add_titled(&queue_box, Some("queue"), "Queue");
+
+        let sidebar = gtk::StackSidebar::builder()
+            .stack(&stack)
+            .width_request(180)
+            .build();
+
+        let body = GtkBox::builder()
+            .orientation(gtk::Orientation::Horizontal)
+            .vexpand(true)
+            .build();
+        body.append(&sidebar);
+        body.append(&gtk::Separator::new(gtk::Orientation::Vertical));
+        body.append(&stack);
+
+        let player_bar = PlayerBar::new(player.clone());
+
+        let content = GtkBox::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        content.append(&header);
+        content.append(&body);
+        content.append(&gtk::Separator::new(gtk::Orientation::Horizontal));
+        content.append(player_bar.widget());
+        window.set_content(Some(&content));
+
+        let queue = match Queue::load(&Queue::default_path(&profile)) {
+            Ok(queue) => queue,
+            Err(e) => {
+                log::warn!("Failed to load saved queue: {}", e);
+                Queue::new()
+            }
+        };
+        player_bar.set_shuffle(queue.is_shuffled());
+        player_bar.set_repeat(queue.repeat());
+
+        let cache = match LibraryCache::open(&LibraryCache::default_path(&profile)) {
+            Ok(cache) => Some(Arc::new(Mutex::new(cache))),
+            Err(e) => {
+                log::warn!("Failed to open library cache: {}", e);
+                None
+            }
+        };
+        let sync = SyncEngine::new(client.clone(), cache);
+
+        let settings = match Settings::load(&Settings::default_path(&profile)) {
+            Ok(settings) => settings,
+            Err(e) => {
+                log::warn!("Failed to load settings: {}", e);
+                Settings::default()
+            }
+        };
+        let stream_cache = match StreamCache::open(
+            StreamCache::default_dir(&profile),
+            settings.stream_cache_quota_bytes(),
+        ) {
+            Ok(stream_cache) => Some(stream_cache),
+            Err(e) => {
+                log::warn!("Failed to open stream cache: {}", e);
+                None
+            }
+        };
+
+        let downloads = match DownloadManager::new(
+            client.clone(),
+            DownloadManager::default_dir(&profile),
+            DEFAULT_CONCURRENCY,
+        ) {
+            Ok(downloads) => Some(downloads),
+            Err(e) => {
+                log::warn!("Failed to set up downloads: {}", e);
+                None
+            }
+        };
+
+        let outbox = match Outbox::load(&Outbox::default_path(&profile)) {
+            Ok(outbox) => Some(outbox),
+            Err(e) => {
+                log::warn!("Failed to load playlist edit outbox: {}", e);
+                None
+            }
+        };
+        playlist_button.set_sensitive(outbox.is_some());
+        offline_mode_button.set_active(settings.work_offline);
+
+        let main_window = Self {
+            window,
+            status_label,
+            spinner,
+            download_label,
+            stack,
+            artists,
+            albums,
+            tracks,
+            playlists,
+            queue_page,
+            player_bar,
+            client,
+            player,
+            library: Rc::new(RefCell::new(Library::default())),
+            sync,
+            sync_button,
+            cancel_sync_button,
+            sync_cancel: Rc::new(RefCell::new(CancellationToken::new())),
+            syncs_running: Rc::new(Cell::new(0)),
+            offline_button: offline_mode_button,
+            connection_label,
+            network,
+            work_offline: Rc::new(Cell::new(settings.work_offline)),
+            outbox,
+            downloads,
+            download_progress: Rc::new(RefCell::new(HashMap::new())),
+            stream_cache,
+            settings: Rc::new(RefCell::new(settings)),
+            queue: Rc::new(RefCell::new(queue)),
+            pending_seek: Rc::new(Cell::new(None)),
+            logout_button,
+            on_logout: Rc::new(RefCell::new(None)),
+            profile,
+            on_switch_profile: Rc::new(RefCell::new(None)),
+            closed: CancellationToken::new(),
+        };
+
+        // Activating a track plays the visible list from that track on
+        let this = main_window.clone();
+        main_window
+            .tracks
+            .connect_row_activated(move |row: TrackRow| {
+                let rows = this.tracks.visible_rows::<TrackRow>();
+                let start = rows
+                    .iter()
+                    .position(|other| other.id == row.id)
+                    .unwrap_or(0);
+                let ids: Vec<u64> = rows.iter().map(|row| row.id).collect();
+                this.play_tracks(&ids, start);
+            });
+
+        let this = main_window.clone();
+        main_window
+            .albums
+            .connect_row_activated(move |row: AlbumRow| {
+                let ids: Vec<u64> = this
+                    .library
+                    .borrow()
+                    .album_tracks(row.id)
+                    .iter()
+                    .map(|track| track.id)
+                    .collect();
+                this.play_tracks(&ids, 0);
+            });
+
+        let this = main_window.clone();
+        main_window
+            .artists
+            .connect_row_activated(move |row: ArtistRow| {
+                let ids = this.artist_track_ids(row.id);
+                this.play_tracks(&ids, 0);
+            });
+
+        let this = main_window.clone();
+        main_window
+            .playlists
+            .connect_row_activated(move |row: PlaylistRow| {
+                let ids: Vec<u64> = this
+                    .library
+                    .borrow()
+                    .playlist_tracks(row.id)
+                    .iter()
+                    .map(|track| track.id)
+                    .collect();
+                this.play_tracks(&ids, 0);
+            });
+
+        let this = main_window.clone();
+        main_window
+            .queue_page
+            .connect_row_activated(move |row: QueueRow| {
+                let entry = this.queue.borrow_mut().jump_to(row.index);
+                if entry.is_some() {
+                    this.play_current(None);
+                }
+            });
+
+        let this = main_window.clone();
+        main_window
+            .queue_page
+            .connect_row_moved(move |from: QueueRow, to: QueueRow| {
+                this.queue.borrow_mut().move_entry(from.index, to.index);
+                this.queue_changed();
+            });
+
+        let this = main_window.clone();
+        remove_from_queue_button.connect_clicked(move |_| {
+            this.remove_selected_from_queue();
+        });
+
+        let this = main_window.clone();
+        let queue_keys = gtk::EventControllerKey::new();
+        queue_keys.connect_key_pressed(move |_, key, _, _| {
+            if key == gdk::Key::Delete {
+                this.remove_selected_from_queue();
+                glib::Propagation::Stop
+            } else {
+                glib::Propagation::Proceed
+            }
+        });
+        main_window.queue_page.view.add_controller(queue_keys);
+
+        let this = main_window.clone();
+        clear_queue_button.connect_clicked(move |_| {
+            this.queue.borrow_mut().clear();
+            this.play_current(None);
+        });
+
+        let this = main_window.clone();
+        play_next_button.connect_clicked(move |_| {
+            let ids: Vec<u64> = this
+                .tracks
+                .selected_rows::<TrackRow>()
+                .iter()
+                .map(|row| row.id)
+                .collect();
+            this.queue.borrow_mut().play_next(&ids);
+            this.queue_changed();
+        });
+
+        let this = main_window.clone();
+        add_to_queue_button.connect_clicked(move |_| {
+            let ids: Vec<u64> = this
+                .tracks
+                .selected_rows::<TrackRow>()
+                .iter()
+                .map(|row| row.id)
+                .collect();
+            this.queue.borrow_mut().add_to_end(&ids);
+            this.queue_changed();
+        });
+
+        let this = main_window.clone();
+        main_window.player_bar.connect_action(move |action| {
+            this.handle_player_action(action);
+        });
+
+        // Remember where playback stopped so the next launch can resume there
+        let this = main_window.clone();
+        main_window.window.connect_close_request(move |_| {
+            if let Some(position) = this.player.position() {
+                this.queue
+                    .borrow_mut()
+                    .set_position_ms(position.as_millis() as u64);
+            }
+            this.save_queue();
+            this.closed.cancel();
+            glib::Propagation::Proceed
+        });
+
+        let this = main_window.clone();
+        main_window
+            .sync_button
+            .connect_clicked(move |_| this.sync_library());
+
+        let this = main_window.clone();
+        main_window
+            .cancel_sync_button
+            .connect_clicked(move |_| this.cancel_sync());
+
+        let this = main_window.clone();
+        let popover = offline_popover.clone();
+        download_button.connect_clicked(move |_| {
+            popover.popdown();
+            this.pin_selection(true);
+        });
+
+        let this = main_window.clone();
+        remove_download_button.connect_clicked(move |_| {
+            offline_popover.popdown();
+            this.pin_selection(false);
+        });
+
+        let this = main_window.clone();
+        main_window.offline_button.connect_toggled(move |button| {
+            let offline = button.is_active();
+            // Also fires when set_work_offline updates the button
+            if offline == this.work_offline.get() {
+                return;
+            }
+            {
+                let mut settings = this.settings.borrow_mut();
+                settings.work_offline = offline;
+                if let Err(e) = settings.save(&Settings::default_path(&this.profile)) {
+                    log::error!("Failed to save settings: {}", e);
+                }
+            }
+            this.set_work_offline(offline);
+        });
+
+        // The playlist choice lists the current playlists each time it opens
+        let playlist_ids: Rc<RefCell<Vec<u64>>> = Rc::new(RefCell::new(Vec::new()));
+        let this = main_window.clone();
+        let ids = playlist_ids.clone();
+        let choice = playlist_choice.clone();
+        playlist_popover.connect_show(move |_| {
+            let library = this.library.borrow();
+            let mut playlists: Vec<&Playlist> = library.playlists.values().collect();
+            playlists.sort_by(|a, b| compare_text(&a.name, &b.name));
+            let names: Vec<&str> = playlists
+                .iter()
+                .map(|playlist| playlist.name.as_str())
+                .collect();
+            choice.set_model(Some(&gtk::StringList::new(&names)));
+            ids.replace(playlists.iter().map(|playlist| playlist.id).collect());
+        });
+
+        let this = main_window.clone();
+        let popover = playlist_popover.clone();
+        create_playlist_button.connect_clicked(move |_| {
+            let name = new_playlist_entry.text().trim().to_string();
+            let Some(outbox) = &this.outbox else {
+                return;
+            };
+            if name.is_empty() {
+                return;
+            }
+            popover.popdown();
+            new_playlist_entry.set_text("");
+            this.edit_playlists(vec![PlaylistEdit::Create {
+                local_id: outbox.next_local_id(),
+                name,
+            }]);
+        });
+
+        let this = main_window.clone();
+        let popover = playlist_popover.clone();
+        let ids = playlist_ids.clone();
+        let choice = playlist_choice.clone();
+        add_to_playlist_button.connect_clicked(move |_| {
+            let Some(&playlist_id) = ids.borrow().get(choice.selected() as usize) else {
+                return;
+            };
+            popover.popdown();
+            let playlist = PlaylistRef::from_id(playlist_id);
+            let edits = this
+                .tracks
+                .selected_rows::<TrackRow>()
+                .iter()
+                .map(|row| PlaylistEdit::AddTrack {
+                    playlist,
+                    track_id: row.id,
+                })
+                .collect();
+            this.edit_playlists(edits);
+        });
+
+        let this = main_window.clone();
+        let popover = playlist_popover.clone();
+        remove_from_playlist_button.connect_clicked(move |_| {
+            let Some(&playlist_id) = playlist_ids
+                .borrow()
+                .get(playlist_choice.selected() as usize)
+            else {
+                return;
+            };
+            popover.popdown();
+            let playlist = PlaylistRef::from_id(playlist_id);
+            let edits = this
+                .tracks
+                .selected_rows::<TrackRow>()
+                .iter()
+                .map(|row| PlaylistEdit::RemoveTrack {
+                    playlist,
+                    track_id: row.id,
+                })
+                .collect();
+            this.edit_playlists(edits);
+        });
+
+        let this = main_window.clone();
+        delete_playlist_button.connect_clicked(move |_| {
+            playlist_popover.popdown();
+            let edits = {
+                let library = this.library.borrow();
+                this.playlists
+                    .selected_rows::<PlaylistRow>()
+                    .iter()
+                    .filter_map(|row| library.playlists.get(&row.id))
+                    .map(|playlist| PlaylistEdit::Delete {
+                        playlist: PlaylistRef::from_id(playlist.id),
+                        tracks: playlist.tracks.clone(),
+                    })
+                    .collect()
+            };
+            this.edit_playlists(edits);
+        });
+
+        let this = main_window.clone();
+        preferences_button.connect_clicked(move |_| {
+            PreferencesWindow::new(
+                &this.window,
+                this.settings.clone(),
+                Settings::default_path(&this.profile),
+                this.stream_cache.clone(),
+            )
+            .show();
+        });
+
+        for (id, button) in profile_buttons {
+            let this = main_window.clone();
+            let popover = profile_popover.clone();
+            button.connect_clicked(move |_| {
+                popover.popdown();
+                this.switch_profile(ProfileChoice::Existing(id.clone()));
+            });
+        }
+
+        let this = main_window.clone();
+        let entry = new_profile_entry.clone();
+        add_profile_button.connect_clicked(move |_| {
+            profile_popover.popdown();
+            this.switch_profile(ProfileChoice::New(entry.text().to_string()));
+        });
+
+        let button = add_profile_button.clone();
+        new_profile_entry.connect_activate(move |_| button.emit_clicked());
+
+        let this = main_window.clone();
+        main_window
+            .logout_button
+            .connect_clicked(move |_| this.confirm_logout());
+
+        main_window.show_connectivity();
+        main_window.watch_player();
+        main_window.watch_sync();
+        main_window.watch_downloads();
+        main_window.watch_network();
+        main_window
+    }
+
+    pub fn show(&self) {
+        self.window.present();
+    }
+
+    /// Sets the callback invoked when the user picks another account in the
+    /// switcher, just before the window closes
+    pub fn connect_switch_profile<F>(&self, callback: F)
+    where
+        F: Fn(ProfileChoice) + 'static,
+    {
+        self.on_switch_profile.replace(Some(Rc::new(callback)));
+    }
+
+    /// Sets the callback invoked once the user has logged out, just before
+    /// the window closes
+    pub fn connect_logout<F>(&self, callback: F)
+    where
+        F: Fn() + 'static,
+    {
+        self.on_logout.replace(Some(Rc::new(callback)));
+    }
+
+    /// Shows the cached library right away, then syncs it with the server
+    /// now and periodically
+    pub fn load_library(&self) {
+        self.spinner.set_spinning(true);
+        self.status_label.set_text("Loading library...");
+
+        let this = self.clone();
+        glib::spawn_future_local(async move {
+            if let Some(library) = this.sync.load_cached().await {
+                this.set_library(&library);
+            }
+            this.update_sync_tooltip().await;
+            if this.is_offline() {
+                this.spinner.set_spinning(false);
+                this.status_label.set_text("");
+            } else {
+                this.sync_library();
+            }
+        });
+
+        let this = self.clone();
+        glib::timeout_add_seconds_local(SYNC_INTERVAL_SECS, move || {
+            if this.closed.is_cancelled() {
+                return glib::ControlFlow::Break;
+            }
+            this.sync_library();
+            glib::ControlFlow::Continue
+        });
+    }
+
+    /// Sends queued playlist edits, then syncs the library. Does nothing
+    /// while offline.
+    fn sync_library(&self) {
+        if self.is_offline() || self.closed.is_cancelled() {
+            return;
+        }
+        let cancel = self.sync_cancel.borrow().clone();
+        self.syncs_running.set(self.syncs_running.get() + 1);
+        self.cancel_sync_button.set_visible(true);
+        let this = self.clone();
+        glib::spawn_future_local(async move {
+            let report = match &this.outbox {
+                Some(outbox) => {
+                    let outbox = outbox.clone();
+                    let client = this.client.with_cancellation(cancel.clone());
+                    runtime::spawn(async move { outbox.flush(&client).await }).await
+                }
+                None => FlushReport::default(),
+            };
+            if let Some(conflict) = report.conflicts.first() {
+                this.status_label.set_text(&format!(
+                    "{} playlist edits were dropped: {}",
+                    report.conflicts.len(),
+                    conflict.reason
+                ));
+            }
+            if report.remaining > 0 {
+                log::info!("{} playlist edits still queued", report.remaining);
+            }
+
+            // Failures are reported through the sync events
+            let synced = this.sync.sync(&cancel).await.is_ok();
+            let running = this.syncs_running.get() - 1;
+            this.syncs_running.set(running);
+            this.cancel_sync_button.set_visible(running > 0);
+
+            // Sync only reports server-side changes, so edits that were sent
+            // or dropped are reconciled by reloading from the updated cache
+            if synced && (report.applied > 0 || !report.conflicts.is_empty()) {
+                if let Some(library) = this.sync.load_cached().await {
+                    this.set_library(&library);
+                }
+            }
+        });
+    }
+
+    /// Stops the running sync, including sending playlist edits. Edits not
+    /// yet sent stay queued for the next sync.
+    fn cancel_sync(&self) {
+        self.sync_cancel.replace(CancellationToken::new()).cancel();
+        self.cancel_sync_button.set_visible(false);
+    }
+
+    /// Asks whether to keep offline data, then logs out
+    fn confirm_logout(&self) {
+        let dialog = LogoutDialog::new(&self.window);
+        let this = self.clone();
+        dialog.connect_confirm(move |wipe| this.logout(wipe));
+        dialog.show();
+    }
+
+    /// Stops playback, sync and downloads, ends the session and removes it
+    /// from the credential store, then hands over to the login window. With
+    /// `wipe`, the offline data goes as well.
+    fn logout(&self, wipe: bool) {
+        self.logout_button.set_sensitive(false);
+        self.spinner.set_spinning(true);
+        self.status_label.set_text("Logging out...");
+        self.stop_activity();
+
+        let this = self.clone();
+        glib::spawn_future_local(async move {
+            let client = this.client.clone();
+            runtime::spawn(async move { client.logout().await }).await;
+            if let Err(e) = utils::clear_session(&this.profile) {
+                log::warn!("Failed to clear stored session: {}", e);
+            }
+            if let Err(e) = utils::forget_login(&this.profile) {
+                log::warn!("Failed to forget saved login: {}", e);
+            }
+
+            // The login window opens first, so the app doesn't quit for
+            // lack of windows
+            let callback = this.on_logout.borrow().clone();
+            if let Some(callback) = callback {
+                callback();
+            }
+            this.window.close();
+            // After closing, which saves the queue
+            if wipe {
+                this.wipe_offline_data();
+            }
+        });
+    }
+
+    /// Leaves this account for `choice`. Its session stays stored, so
+    /// switching back doesn't need a new login.
+    fn switch_profile(&self, choice: ProfileChoice) {
+        self.stop_activity();
+        let callback = self.on_switch_profile.borrow().clone();
+        if let Some(callback) = callback {
+            callback(choice);
+        }
+        self.window.close();
+    }
+
+    /// Stops playback, sync and downloads, as the account is about to go.
+    /// Requests still out for it are cancelled, so none goes out under the
+    /// next account's session.
+    fn stop_activity(&self) {
+        self.sync_button.set_sensitive(false);
+        self.cancel_sync();
+        if let Some(downloads) = &self.downloads {
+            downloads.stop();
+        }
+        if let Err(e) = self.player.stop() {
+            log::warn!("Failed to stop playback: {}", e);
+        }
+    }
+
+    /// Deletes downloads, cached streams, the library cache, unsent
+    /// playlist edits and the saved queue
+    fn wipe_offline_data(&self) {
+        if let Some(stream_cache) = &self.stream_cache {
+            stream_cache.clear();
+        }
+        if let Err(e) = LibraryCache::remove(&LibraryCache::default_path(&self.profile)) {
+            log::warn!("Failed to remove library cache: {}", e);
+        }
+        for path in [
+            Outbox::default_path(&self.profile),
+            Queue::default_path(&self.profile),
+        ] {
+            if let Err(e) = std::fs::remove_file(&path) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("Failed to remove {}: {}", path.display(), e);
+                }
+            }
+        }
+        if let Err(e) = std::fs::remove_dir_all(DownloadManager::default_dir(&self.profile)) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log::warn!("Failed to remove downloads: {}", e);
+            }
+        }
+        log::info!("Removed offline data");
+    }
+
+    /// Offline by choice or because there is no network
+    fn is_offline(&self) -> bool {
+        self.work_offline.get() || !self.network.is_online()
+    }
+
+    /// Switches between working offline and online by choice
+    pub fn set_work_offline(&self, offline: bool) {
+        if self.work_offline.replace(offline) == offline {
+            return;
+        }
+        log::info!("Working {}", if offline { "offline" } else { "online" });
+        self.offline_button.set_active(offline);
+        self.connectivity_changed();
+    }
+
+    fn watch_network(&self) {
+        let this = self.clone();
+        let mut events = self.network.subscribe();
+        glib::spawn_future_local(async move {
+            loop {
+                let event = tokio::select! {
+                    event = events.recv() => event,
+                    _ = this.closed.cancelled() => break,
+                };
+                match event {
+                    Ok(_) | Err(RecvError::Lagged(_)) => this.connectivity_changed(),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Coming back online sends queued playlist edits, syncs and resumes
+    /// downloads
+    fn connectivity_changed(&self) {
+        if !self.show_connectivity() {
+            self.sync_library();
+            // Retries downloads that failed when the network went away
+            self.sync_downloads();
+        }
+    }
+
+    /// Updates the offline indicator and pauses or resumes downloads.
+    /// Returns whether offline.
+    fn show_connectivity(&self) -> bool {
+        let offline = self.is_offline();
+        self.sync_button.set_sensitive(!offline);
+        if let Some(downloads) = &self.downloads {
+            downloads.set_paused(offline);
+        }
+
+        self.connection_label.set_visible(offline);
+        let tooltip = if self.work_offline.get() {
+            "Working offline. Playlist edits are sent when you go back online."
+        } else {
+            "No network connection. Sync and downloads resume when it is back."
+        };
+        self.connection_label.set_tooltip_text(Some(tooltip));
+        offline
+    }
+
+    /// Applies playlist edits locally right away and queues them for the
+    /// server, sending them now unless working offline
+    fn edit_playlists(&self, edits: Vec<PlaylistEdit>) {
+        let Some(outbox) = &self.outbox else {
+            return;
+        };
+        if edits.is_empty() {
+            return;
+        }
+
+        let mut playlist_ids = BTreeSet::new();
+        {
+            let mut library = self.library.borrow_mut();
+            for edit in edits {
+                playlist_ids.insert(apply_edit(&edit, &mut library));
+                if let Err(e) = outbox.push(edit) {
+                    log::error!("Failed to queue playlist edit: {}", e);
+                    self.status_label
+                        .set_text(&format!("Failed to save playlist edit: {}", e));
+                }
+            }
+            self.playlists.update_rows(
+                &playlist_ids,
+                |id| library.playlists.get(&id).map(playlist_row),
+                |row: &PlaylistRow| row.id,
+            );
+        }
+        self.sync_downloads();
+        self.sync_library();
+    }
+
+    async fn update_sync_tooltip(&self) {
+        let tooltip = match self.sync.last_synced().await {
+            Some(at) => {
+                let ago = at.elapsed().unwrap_or_default().as_secs() / 60;
+                format!("Sync Library (last synced {} min ago)", ago)
+            }
+            None => "Sync Library".to_string(),
+        };
+        self.sync_button.set_tooltip_text(Some(&tooltip));
+    }
+
+    /// Applies sync results to the browser as they arrive
+    fn watch_sync(&self) {
+        let this = self.clone();
+        let mut events = self.sync.subscribe();
+        glib::spawn_future_local(async move {
+            loop {
+                let event = tokio::select! {
+                    event = events.recv() => event,
+                    _ = this.closed.cancelled() => break,
+                };
+                match event {
+                    Ok(event) => this.handle_sync_event(event).await,
+                    Err(RecvError::Lagged(skipped)) => {
+                        // Missed changes can't be replayed, so start over from the cache
+                        log::warn!("Main window skipped {} sync events", skipped);
+                        if let Some(library) = this.sync.load_cached().await {
+                            this.set_library(&library);
+                        }
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    async fn handle_sync_event(&self, event: SyncEvent) {
+        match event {
+            SyncEvent::Started => {
+                self.spinner.set_spinning(true);
+                self.sync_button.set_sensitive(false);
+                self.status_label.set_text("Syncing library...");
+            }
+            SyncEvent::Reloaded(library) => self.set_library(&library),
+            SyncEvent::Changed(changes) => self.apply_changes(&changes),
+            SyncEvent::Finished { changes, .. } => {
+                self.spinner.set_spinning(false);
+                self.sync_button.set_sensitive(!self.is_offline());
+                self.status_label.set_text("");
+                log::info!("Library synced with {} changes", changes);
+                self.update_sync_tooltip().await;
+            }
+            SyncEvent::Failed(message) => {
+                self.spinner.set_spinning(false);
+                self.sync_button.set_sensitive(!self.is_offline());
+                self.status_label
+                    .set_text(&format!("Failed to sync library: {}", message));
+            }
+            SyncEvent::Cancelled => {
+                self.spinner.set_spinning(false);
+                self.sync_button.set_sensitive(!self.is_offline());
+                self.status_label.set_text("Sync cancelled");
+            }
+        }
+    }
+
+    /// Updates only the rows affected by `changes`, including rows that show
+    /// the name of a changed artist or album
+    fn apply_changes(&self, changes: &[LibraryChange]) {
+        let mut library = self.library.borrow_mut();
+        sync::apply_changes(&mut library, changes);
+
+        let mut track_ids = BTreeSet::new();
+        let mut album_ids = BTreeSet::new();
+        let mut artist_ids = BTreeSet::new();
+        let mut playlist_ids = BTreeSet::new();
+        for change in changes {
+            let (kind, id) = match change {
+                LibraryChange::Added(item) | LibraryChange::Changed(item) => {
+                    (item.kind(), item.id())
+                }
+                LibraryChange::Removed(kind, id) => (*kind, *id),
+                LibraryChange::TrashChanged(_) => continue,
+            };
+            match kind {
+                ItemKind::Track => track_ids.insert(id),
+                ItemKind::Album => album_ids.insert(id),
+                ItemKind::Artist => artist_ids.insert(id),
+                ItemKind::Playlist => playlist_ids.insert(id),
+                ItemKind::Tag => false,
+            };
+        }
+
+        // Server changes must not undo edits that haven't been sent yet
+        if let Some(outbox) = &self.outbox {
+            playlist_ids.extend(outbox.apply_pending(&mut library));
+        }
+
+        let renamed = |id: Option<u64>, ids: &BTreeSet<u64>| id.is_some_and(|id| ids.contains(&id));
+        if !album_ids.is_empty() || !artist_ids.is_empty() {
+            track_ids.extend(
+                library
+                    .tracks
+                    .values()
+                    .filter(|track| {
+                        renamed(track.album_id, &album_ids) || renamed(track.artist_id, &artist_ids)
+                    })
+                    .map(|track| track.id),
+            );
+        }
+        if !artist_ids.is_empty() {
+            album_ids.extend(
+                library
+                    .albums
+                    .values()
+                    .filter(|album| renamed(album.artist_id, &artist_ids))
+                    .map(|album| album.id),
+            );
+        }
+
+        self.tracks.update_rows(
+            &track_ids,
+            |id| {
+                library
+                    .tracks
+                    .get(&id)
+                    .filter(|track| !track.trashed)
+                    .map(|track| track_row(&library, track))
+            },
+            |row: &TrackRow| row.id,
+        );
+        self.albums.update_rows(
+            &album_ids,
+            |id| {
+                library
+                    .albums
+                    .get(&id)
+                    .filter(|album| !album.trashed)
+                    .map(|album| album_row(&library, album))
+            },
+            |row: &AlbumRow| row.id,
+        );
+        self.artists.update_rows(
+            &artist_ids,
+            |id| {
+                library
+                    .artists
+                    .get(&id)
+                    .filter(|artist| !artist.trashed)
+                    .map(artist_row)
+            },
+            |row: &ArtistRow| row.id,
+        );
+        self.playlists.update_rows(
+            &playlist_ids,
+            |id| library.playlists.get(&id).map(playlist_row),
+            |row: &PlaylistRow| row.id,
+        );
+        drop(library);
+
+        self.refresh_queue_page();
+        self.sync_downloads();
+        log::debug!("Applied {} library changes", changes.len());
+    }
+
+    /// Pins or unpins whatever is selected on the visible page. Albums and
+    /// playlists are pinned as a whole so tracks added later follow.
+    fn pin_selection(&self, pin: bool) {
+        let Some(downloads) = &self.downloads else {
+            return;
+        };
+        let pins: Vec<Pin> = match self.stack.visible_child_name().as_deref() {
+            Some("albums") => self
+                .albums
+                .selected_rows::<AlbumRow>()
+                .iter()
+                .map(|row| Pin::Album(row.id))
+                .collect(),
+            Some("playlists") => self
+                .playlists
+                .selected_rows::<PlaylistRow>()
+                .iter()
+                .map(|row| Pin::Playlist(row.id))
+                .collect(),
+            Some("artists") => self
+                .artists
+                .selected_rows::<ArtistRow>()
+                .iter()
+                .flat_map(|row| self.artist_track_ids(row.id))
+                .map(Pin::Track)
+                .collect(),
+            Some("queue") => {
+                let queue = self.queue.borrow();
+                self.queue_page
+                    .selected_rows::<QueueRow>()
+                    .iter()
+                    .filter_map(|row| queue.entries().get(row.index))
+                    .map(|entry| Pin::Track(entry.track_id))
+                    .collect()
+            }
+            _ => self
+                .tracks
+                .selected_rows::<TrackRow>()
+                .iter()
+                .map(|row| Pin::Track(row.id))
+                .collect(),
+        };
+        if pins.is_empty() {
+            return;
+        }
+
+        let library = self.library.borrow();
+        if pin {
+            downloads.pin(&pins, &library);
+        } else {
+            downloads.unpin(&pins, &library);
+        }
+    }
+
+    fn sync_downloads(&self) {
+        if let Some(downloads) = &self.downloads {
+            downloads.sync_pins(&self.library.borrow());
+        }
+    }
+
+    fn watch_downloads(&self) {
+        let Some(downloads) = &self.downloads else {
+            return;
+        };
+        let this = self.clone();
+        let mut events = downloads.subscribe();
+        glib::spawn_future_local(async move {
+            loop {
+                let event = tokio::select! {
+                    event = events.recv() => event,
+                    _ = this.closed.cancelled() => break,
+                };
+                match event {
+                    Ok(event) => this.handle_download_event(event),
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::debug!("Main window skipped {} download events", skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    fn handle_download_event(&self, event: DownloadEvent) {
+        {
+            let mut progress = self.download_progress.borrow_mut();
+            match event {
+                DownloadEvent::Queued(track_id) => {
+                    progress.insert(track_id, (0, None));
+                }
+                DownloadEvent::Progress {
+                    track_id,
+                    downloaded,
+                    total,
+                } => {
+                    progress.insert(track_id, (downloaded, total));
+                }
+                DownloadEvent::Finished(track_id) | DownloadEvent::Removed(track_id) => {
+                    progress.remove(&track_id);
+                }
+                DownloadEvent::Failed(track_id, message) => {
+                    progress.remove(&track_id);
+                    let (title, _) = self.track_labels(track_id);
+                    self.status_label
+                        .set_text(&format!("Failed to download {}: {}", title, message));
+                }
+            }
+        }
+
+        let progress = self.download_progress.borrow();
+        if progress.is_empty() {
+            self.download_label.set_text("");
+            return;
+        }
+        let (downloaded, total) = progress
+            .values()
+            .filter_map(|(downloaded, total)| total.map(|total| (*downloaded, total)))
+            .fold((0, 0), |(a, b), (downloaded, total)| {
+                (a + downloaded, b + total)
+            });
+        let text = if total > 0 {
+            format!(
+                "Downloading {} tracks ({}%)",
+                progress.len(),
+                downloaded * 100 / total
+            )
+        } else {
+            format!("Downloading {} tracks", progress.len())
+        };
+        self.download_label.set_text(&text);
+    }
+
+    /// Replaces the queue with `track_ids` and plays from `start`
+    pub fn play_tracks(&self, track_ids: &[u64], start: usize) {
+        if track_ids.is_empty() {
+            return;
+        }
+        self.queue.borrow_mut().replace(track_ids, start);
+        self.play_current(None);
+    }
+
+    /// Resolves the current queue entry's stream URL and starts playing it,
+    /// optionally resuming at `start_at`
+    pub fn play_current(&self, start_at: Option<Duration>) {
+        self.queue_changed();
+
+        let current = self.queue.borrow().current();
+        let Some(entry) = current else {
+            if let Err(e) = self.player.stop() {
+                log::warn!("Failed to stop playback: {}", e);
+            }
+            self.player_bar.clear_now_playing();
+            return;
+        };
+        let (title, artist) = self.track_labels(entry.track_id);
+        self.player_bar.set_now_playing(&title, &artist);
+        self.pending_seek
+            .set(start_at.filter(|position| !position.is_zero()));
+
+        let this = self.clone();
+        glib::spawn_future_local(async move {
+            // Prefer a downloaded copy, then a cached stream, over streaming
+            let local = match &this.downloads {
+                Some(downloads) => downloads.verified_path(entry.track_id).await,
+                None => None,
+            }
+            .or_else(|| {
+                this.stream_cache
+                    .as_ref()
+                    .and_then(|cache| cache.lookup(entry.track_id))
+            });
+            let result = match local {
+                Some(path) => Ok((gio::File::for_path(path).uri().to_string(), false)),
+                None if this.is_offline() => Err("not available offline".to_string()),
+                None => {
+                    let client = this.client.clone();
+                    let track_id = entry.track_id.to_string();
+                    runtime::spawn(async move { client.get_stream_url(&track_id).await })
+                        .await
+                        .map(|playback| (playback.stream_url, true))
+                        .map_err(|e| e.to_string())
+                }
+            };
+
+            // The user may have skipped elsewhere while the URL was resolving
+            if this.queue.borrow().current() != Some(entry) {
+                return;
+            }
+
+            let started = match result {
+                Ok((uri, streamed)) => {
+                    // Streams are fetched by the player so they can be cached as they play
+                    let writer = this
+                        .stream_cache
+                        .as_ref()
+                        .filter(|_| streamed)
+                        .and_then(|cache| cache.writer(entry.track_id));
+                    match writer {
+                        Some(writer) => this.player.load_teed(&uri, Box::new(writer)),
+                        None => this.player.load(&uri),
+                    }
+                    .and_then(|_| this.player.play())
+                    .map_err(|e| e.to_string())
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = started {
+                log::error!("Failed to play track {}: {}", entry.track_id, e);
+                this.status_label
+                    .set_text(&format!("Failed to play {}: {}", title, e));
+            }
+        });
+    }
+
+    fn handle_player_action(&self, action: PlayerBarAction) {
+        match action {
+            PlayerBarAction::Play => {
+                let (current, position) = {
+                    let queue = self.queue.borrow();
+                    (queue.current(), queue.position_ms())
+                };
+                if current.is_some() {
+                    self.play_current(Some(Duration::from_millis(position)));
+                } else if self.queue.borrow_mut().next(false).is_some() {
+                    self.play_current(None);
+                }
+            }
+            PlayerBarAction::Previous => {
+                // Like most players, "previous" restarts the track unless it just began
+                if self
+                    .player
+                    .position()
+                    .is_some_and(|position| position > Duration::from_secs(3))
+                {
+                    if let Err(e) = self.player.seek(Duration::ZERO) {
+                        log::warn!("Failed to restart track: {}", e);
+                    }
+                } else if self.queue.borrow_mut().previous().is_some() {
+                    self.play_current(None);
+                }
+            }
+            PlayerBarAction::Next => {
+                self.queue.borrow_mut().next(false);
+                self.play_current(None);
+            }
+            PlayerBarAction::Shuffle(shuffle) => {
+                self.queue.borrow_mut().set_shuffle(shuffle);
+                self.queue_changed();
+            }
+            PlayerBarAction::Repeat(repeat) => {
+                self.queue.borrow_mut().set_repeat(repeat);
+                self.queue_changed();
+            }
+        }
+    }
+
+    /// Advances the queue when a track ends and applies pending seeks
+    fn watch_player(&self) {
+        let this = self.clone();
+        let mut events = self.player.subscribe();
+        glib::spawn_future_local(async move {
+            loop {
+                let event = tokio::select! {
+                    event = events.recv() => event,
+                    _ = this.closed.cancelled() => break,
+                };
+                match event {
+                    Ok(PlayerEvent::EndOfStream) => {
+                        let next = this.queue.borrow_mut().next(true);
+                        if next.is_some() {
+                            this.play_current(None);
+                        } else {
+                            this.queue_changed();
+                            if let Err(e) = this.player.stop() {
+                                log::warn!("Failed to stop playback: {}", e);
+                            }
+                        }
+                    }
+                    Ok(PlayerEvent::StateChanged(PlayerState::Playing)) => {
+                        if let Some(position) = this.pending_seek.take() {
+                            if let Err(e) = this.player.seek(position) {
+                                log::warn!("Failed to resume at {:?}: {}", position, e);
+                            }
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Persists the queue and refreshes the queue view
+    fn queue_changed(&self) {
+        self.save_queue();
+        self.refresh_queue_page();
+    }
+
+    /// Removes the selected queue entries. If the playing one goes, playback
+    /// moves on to the entry that follows it.
+    fn remove_selected_from_queue(&self) {
+        let mut indices: Vec<usize> = self
+            .queue_page
+            .selected_rows::<QueueRow>()
+            .iter()
+            .map(|row| row.index)
+            .collect();
+        if indices.is_empty() {
+            return;
+        }
+        indices.sort_unstable();
+
+        let current_removed = {
+            let mut queue = self.queue.borrow_mut();
+            let current = queue.current();
+            // From the back, so the remaining indices stay valid
+            for index in indices.into_iter().rev() {
+                queue.remove(index);
+            }
+            queue.current() != current
+        };
+        if !current_removed {
+            self.queue_changed();
+        } else if self.player.state() == PlayerState::Playing {
+            self.play_current(None);
+        } else {
+            if let Err(e) = self.player.stop() {
+                log::warn!("Failed to stop playback: {}", e);
+            }
+            self.player_bar.clear_now_playing();
+            self.queue_changed();
+        }
+    }
+
+    fn save_queue(&self) {
+        let path = Queue::default_path(&self.profile);
+        if let Err(e) = self.queue.borrow().save(&path) {
+            log::warn!("Failed to save queue: {}", e);
+        }
+    }
+
+    fn refresh_queue_page(&self) {
+        let library = self.library.borrow();
+        let queue = self.queue.borrow();
+        let current = queue.current_index();
+        let rows = queue
+            .entries()
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                let track = library.tracks.get(&entry.track_id);
+                QueueRow {
+                    index,
+                    title: track.map(|track| track.title.clone()).unwrap_or_default(),
+                    artist: library
+                        .artist_name(track.and_then(|track| track.artist_id))
+                        .unwrap_or_default()
+                        .to_string(),
+                    album: library
+                        .album_name(track.and_then(|track| track.album_id))
+                        .unwrap_or_default()
+                        .to_string(),
+                    length: track.and_then(|track| track.length),
+                    current: current == Some(index),
+                }
+            })
+            .collect();
+        self.queue_page.replace_rows(rows);
+    }
+
+    fn track_labels(&self, track_id: u64) -> (String, String) {
+        let library = self.library.borrow();
+        match library.tracks.get(&track_id) {
+            Some(track) => (
+                track.title.clone(),
+                library
+                    .artist_name(track.artist_id)
+                    .unwrap_or_default()
+                    .to_string(),
+            ),
+            None => (format!("Track {}", track_id), String::new()),
+        }
+    }
+
+    /// Tracks by an artist, ordered by album and track number
+    fn artist_track_ids(&self, artist_id: u64) -> Vec<u64> {
+        let library = self.library.borrow();
+        let mut tracks: Vec<_> = library
+            .tracks
+            .values()
+            .filter(|track| track.artist_id == Some(artist_id) && !track.trashed)
+            .collect();
+        tracks.sort_by(|a, b| {
+            compare_text(
+                library.album_name(a.album_id).unwrap_or_default(),
+                library.album_name(b.album_id).unwrap_or_default(),
+            )
+            .then_with(|| a.track_number.cmp(&b.track_number))
+        });
+        tracks.iter().map(|track| track.id).collect()
+    }
+
+    /// Replaces the browser contents with the given library, plus any
+    /// playlist edits not yet sent
+    pub fn set_library(&self, library: &Library) {
+        let mut library = library.clone();
+        if let Some(outbox) = &self.outbox {
+            outbox.apply_pending(&mut library);
+        }
+        self.artists.replace_rows(artist_rows(&library));
+        self.albums.replace_rows(album_rows(&library));
+        self.tracks.replace_rows(track_rows(&library));
+        self.playlists.replace_rows(playlist_rows(&library));
+        log::info!(
+            "Library loaded: {} tracks, {} albums, {} artists, {} playlists",
+            library.tracks.len(),
+            library.albums.len(),
+            library.artists.len(),
+            library.playlists.len()
+        );
+        self.library.replace(library);
+        self.refresh_queue_page();
+        self.sync_downloads();
+
+        // Show the restored queue's track so Play resumes it
+        if self.player.uri().is_none() {
+            if let Some(entry) = self.queue.borrow().current() {
+                let (title, artist) = self.track_labels(entry.track_id);
+                self.player_bar.set_now_playing(&title, &artist);
+            }
+        }
+    }
+}
+
+pub fn track_rows(library: &Library) -> Vec<TrackRow> {
+    library
+        .tracks
+        .values()
+        .filter(|track| !track.trashed)
+        .map(|track| track_row(library, track))
+        .collect()
+}
+
+fn track_row(library: &Library, track: &Track) -> TrackRow {
+    TrackRow {
+        id: track.id,
+        title: track.title.clone(),
+        artist: library.artist_name(track.artist_id).unwrap_or_default().to_string(),
+        album: library.album_name(track.album_id).unwrap_or_default().to_string(),
+        track_number: track.track_number,
+        year: track.year,
+        genre: track.genre.clone().unwrap_or_default(),
+        length: track.length,
+    }
+}
+
+pub fn album_rows(library: &Library) -> Vec<AlbumRow> {
+    library
+        .albums
+        .values()
+        .filter(|album| !album.trashed)
+        .map(|album| album_row(library, album))
+        .collect()
+}
+
+fn album_row(library: &Library, album: &Album) -> AlbumRow {
+    AlbumRow {
+        id: album.id,
+        name: album.name.clone(),
+        artist: library.artist_name(album.artist_id).unwrap_or_default().to_string(),
+        year: album.year,
+        track_count: album.tracks.len(),
+    }
+}
+
+pub fn artist_rows(library: &Library) -> Vec<ArtistRow> {
+    library
+        .artists
+        .values()
+        .filter(|artist| !artist.trashed)
+        .map(artist_row)
+        .collect()
+}
+
+fn artist_row(artist: &Artist) -> ArtistRow {
+    ArtistRow {
+        id: artist.id,
+        name: artist.name.clone(),
+        track_count: artist.tracks.len(),
+    }
+}
+
+pub fn playlist_rows(library: &Library) -> Vec<PlaylistRow> {
+    library.playlists.values().map(playlist_row).collect()
+}
+
+fn playlist_row(playlist: &Playlist) -> PlaylistRow {
+    PlaylistRow {
+        id: playlist.id,
+        name: playlist.name.clone(),
+        description: playlist.description.clone().unwrap_or_default(),
+        track_count: playlist.tracks.len(),
+    }
+}
+
+/// Case-insensitive comparison that doesn't allocate, since sorters run it
+/// many times per sort
+fn compare_text(a: &str, b: &str) -> Ordering {
+    a.chars()
+        .flat_map(char::to_lowercase)
+        .cmp(b.chars().flat_map(char::to_lowercase))
+}
+
+fn format_optional(value: Option<u32>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn format_length(seconds: Option<u32>) -> String {
+    seconds
+        .map(|seconds| format!("{}:{:02}", seconds / 60, seconds % 60))
+        .unwrap_or_default()
+}
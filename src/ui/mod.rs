@@ -1,30 +1,80 @@
 use adw::prelude::*;
-use gtk::{Application, Button, Label, Box as GtkBox, Spinner, Entry};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use gtk::{Application, Button, CheckButton, Entry, Label, Box as GtkBox, PasswordEntry, Spinner};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 use glib::timeout_add_local;
 use glib::ControlFlow;
+use tokio_util::sync::CancellationToken;
 
-use crate::api::IBroadcastClient;
+use crate::api::{IBroadcastClient, IBroadcastError};
+use crate::profiles::Profile;
+use crate::runtime;
+use crate::utils;
+
+mod logout_dialog;
+mod main_window;
+mod passphrase_window;
+mod player_bar;
+mod preferences;
+
+pub use main_window::{MainWindow, ProfileChoice};
+pub use passphrase_window::{PassphrasePrompt, PassphraseWindow};
+
+/// How often the pending device code is checked for approval
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Lifetime assumed when the server doesn't send `expires_in`
+const DEFAULT_CODE_LIFETIME: Duration = Duration::from_secs(300);
+/// Shown when logging in again to an account other than the expired one
+const WRONG_ACCOUNT_MESSAGE: &str =
+    "That is a different account. Log in to the account whose session expired, or switch accounts instead.";
 
 #[derive(Clone)]
 pub struct LoginWindow {
     window: adw::Window,
-    device_code_entry: Entry,
+    title_label: Label,
+    device_code_label: Label,
+    expiry_label: Label,
     status_label: Label,
     spinner: Spinner,
-    submit_button: Button,
-    #[allow(dead_code)]
-    client: Arc<Mutex<IBroadcastClient>>,
-    #[allow(dead_code)]
-    app: Application,
+    new_code_button: Button,
+    cancel_code_button: Button,
+    email_entry: Entry,
+    password_entry: PasswordEntry,
+    remember_check: CheckButton,
+    email_error_label: Label,
+    email_spinner: Spinner,
+    login_button: Button,
+    cancel_login_button: Button,
+    client: IBroadcastClient,
+    /// Account whose remembered login the form uses
+    profile: Profile,
+    on_login: Rc<RefCell<Option<Rc<dyn Fn()>>>>,
+    on_cancel: Rc<RefCell<Option<Rc<dyn Fn()>>>>,
+    logged_in: Rc<Cell<bool>>,
+    /// Bumped whenever a new code is requested or the window closes, so
+    /// polling and countdown loops for an older code stop on their own
+    generation: Rc<Cell<u32>>,
+    /// Cancels requests for the current device code
+    code_cancel: Rc<RefCell<CancellationToken>>,
+    /// Cancels the email login in progress
+    login_cancel: Rc<RefCell<CancellationToken>>,
+    /// When logging in again, the account whose session was lost
+    expected_user_id: Rc<RefCell<Option<String>>>,
+}
+
+/// Cancels everything started with the current token and hands out a fresh one
+fn replace_token(token: &RefCell<CancellationToken>) -> CancellationToken {
+    let fresh = CancellationToken::new();
+    token.replace(fresh.clone()).cancel();
+    fresh
 }
 
 impl LoginWindow {
-    pub fn new(app: &Application, client: Arc<Mutex<IBroadcastClient>>) -> Self {
+    pub fn new(app: &Application, client: IBroadcastClient, profile: Profile) -> Self {
         let window = adw::Window::new();
         window.set_application(Some(app));
-        window.set_title(Some("Latke - Login"));
+        window.set_title(Some(&format!("Latke - Login ({})", profile.name)));
         window.set_default_size(400, 300);
 
         let box_ = GtkBox::builder()
@@ -36,113 +86,522 @@ impl LoginWindow {
             .margin_end(12)
             .build();
 
-        let title = Label::builder()
+        let title_label = Label::builder()
             .label("Welcome to Latke")
             .css_classes(vec!["title-1"])
             .build();
 
         let instructions = Label::builder()
-            .label("Please enter the device code from your iBroadcast account settings:")
+            .label("Enter this code in the Apps section of your iBroadcast account settings:")
             .wrap(true)
             .wrap_mode(gtk::pango::WrapMode::Word)
             .build();
 
-        let device_code_entry = Entry::builder()
-            .placeholder_text("Enter device code")
+        let device_code_label = Label::builder()
+            .label("")
+            .selectable(true)
+            .css_classes(vec!["title-1", "monospace"])
+            .build();
+
+        let expiry_label = Label::builder()
+            .label("")
+            .css_classes(vec!["dim-label"])
             .build();
 
         let status_label = Label::builder()
             .label("")
+            .wrap(true)
             .build();
 
         let spinner = Spinner::builder()
             .spinning(false)
             .build();
 
-        let submit_button = Button::builder()
-            .label("Submit")
+        let new_code_button = Button::builder()
+            .label("Get New Code")
+            .visible(false)
+            .build();
+
+        let cancel_code_button = Button::builder()
+            .label("Cancel")
+            .visible(false)
             .build();
 
-        box_.append(&title);
+        box_.append(&title_label);
         box_.append(&instructions);
-        box_.append(&device_code_entry);
+        box_.append(&device_code_label);
+        box_.append(&expiry_label);
         box_.append(&status_label);
         box_.append(&spinner);
-        box_.append(&submit_button);
+        box_.append(&new_code_button);
+        box_.append(&cancel_code_button);
+
+        let email_box = GtkBox::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .spacing(12)
+            .margin_top(12)
+            .margin_bottom(12)
+            .margin_start(12)
+            .margin_end(12)
+            .build();
+
+        let email_title = Label::builder()
+            .label("Log in to iBroadcast")
+            .css_classes(vec!["title-1"])
+            .build();
+
+        let email_entry = Entry::builder()
+            .placeholder_text("Email")
+            .input_purpose(gtk::InputPurpose::Email)
+            .build();
+
+        let password_entry = PasswordEntry::builder()
+            .placeholder_text("Password")
+            .show_peek_icon(true)
+            .activates_default(true)
+            .build();
+
+        let remember_check = CheckButton::builder()
+            .label("Remember me")
+            .build();
+
+        let email_error_label = Label::builder()
+            .label("")
+            .wrap(true)
+            .visible(false)
+            .css_classes(vec!["error"])
+            .build();
+
+        let email_spinner = Spinner::builder()
+            .spinning(false)
+            .build();
+
+        let login_button = Button::builder()
+            .label("Log In")
+            .css_classes(vec!["suggested-action"])
+            .build();
+
+        let cancel_login_button = Button::builder()
+            .label("Cancel")
+            .visible(false)
+            .build();
+
+        email_box.append(&email_title);
+        email_box.append(&email_entry);
+        email_box.append(&password_entry);
+        email_box.append(&remember_check);
+        email_box.append(&email_error_label);
+        email_box.append(&email_spinner);
+        email_box.append(&login_button);
+        email_box.append(&cancel_login_button);
+
+        let stack = adw::ViewStack::new();
+        stack
+            .add_titled(&box_, Some("device-code"), "Device Code")
+            .set_icon_name(Some("phone-symbolic"));
+        stack
+            .add_titled(&email_box, Some("email"), "Email")
+            .set_icon_name(Some("mail-unread-symbolic"));
+
+        let switcher = adw::ViewSwitcher::builder()
+            .stack(&stack)
+            .policy(adw::ViewSwitcherPolicy::Wide)
+            .build();
 
-        window.set_content(Some(&box_));
+        let header = adw::HeaderBar::builder()
+            .title_widget(&switcher)
+            .build();
+
+        let content = GtkBox::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        content.append(&header);
+        content.append(&stack);
+
+        window.set_content(Some(&content));
+        window.set_default_widget(Some(&login_button));
 
-        Self {
+        // Pre-fill the form from credentials saved with "Remember me"
+        match utils::remembered_login(&profile) {
+            Ok(Some((email, password))) => {
+                email_entry.set_text(&email);
+                password_entry.set_text(&password);
+                remember_check.set_active(true);
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to load remembered login: {}", e),
+        }
+
+        let login_window = Self {
             window,
-            device_code_entry,
+            title_label,
+            device_code_label,
+            expiry_label,
             status_label,
             spinner,
-            submit_button,
+            new_code_button,
+            cancel_code_button,
+            email_entry,
+            password_entry,
+            remember_check,
+            email_error_label,
+            email_spinner,
+            login_button,
+            cancel_login_button,
             client,
-            app: app.clone(),
-        }
+            profile,
+            on_login: Rc::new(RefCell::new(None)),
+            on_cancel: Rc::new(RefCell::new(None)),
+            logged_in: Rc::new(Cell::new(false)),
+            generation: Rc::new(Cell::new(0)),
+            code_cancel: Rc::new(RefCell::new(CancellationToken::new())),
+            login_cancel: Rc::new(RefCell::new(CancellationToken::new())),
+            expected_user_id: Rc::new(RefCell::new(None)),
+        };
+
+        let this = login_window.clone();
+        login_window.new_code_button.connect_clicked(move |_| {
+            this.request_device_code();
+        });
+
+        let this = login_window.clone();
+        login_window.cancel_code_button.connect_clicked(move |_| {
+            this.cancel_device_code();
+        });
+
+        let this = login_window.clone();
+        login_window.login_button.connect_clicked(move |_| {
+            this.submit_email_login();
+        });
+
+        let this = login_window.clone();
+        login_window.cancel_login_button.connect_clicked(move |_| {
+            replace_token(&this.login_cancel);
+        });
+
+        let this = login_window.clone();
+        login_window.email_entry.connect_activate(move |_| {
+            this.password_entry.grab_focus();
+        });
+
+        let this = login_window.clone();
+        login_window.email_entry.connect_changed(move |_| {
+            this.clear_email_error();
+        });
+
+        let this = login_window.clone();
+        login_window.password_entry.connect_changed(move |_| {
+            this.clear_email_error();
+        });
+
+        let this = login_window.clone();
+        login_window.window.connect_close_request(move |_| {
+            this.generation.set(this.generation.get().wrapping_add(1));
+            this.code_cancel.borrow().cancel();
+            this.login_cancel.borrow().cancel();
+            if !this.logged_in.get() {
+                let callback = this.on_cancel.borrow().clone();
+                if let Some(callback) = callback {
+                    callback();
+                }
+            }
+            glib::Propagation::Proceed
+        });
+
+        login_window
     }
 
+    /// Presents the window and requests a device code
     pub fn show(&self) {
         self.window.present();
+        self.request_device_code();
     }
 
+    /// Presents the window as a dialog over `parent`, to log in again after
+    /// the session of `user_id` was lost. Whatever `parent` shows stays as it
+    /// is, so only that account is accepted.
+    pub fn show_reauthentication(&self, parent: &impl IsA<gtk::Window>, user_id: Option<String>) {
+        self.expected_user_id.replace(user_id);
+        self.window.set_title(Some("Latke - Session Expired"));
+        self.title_label.set_text("Your session has expired");
+        self.window.set_transient_for(Some(parent));
+        self.window.set_modal(true);
+        self.show();
+    }
+
+    /// Sets the callback invoked once the user has authenticated
     pub fn connect_login<F>(&self, callback: F)
     where
         F: Fn() + 'static,
     {
-        let client = self.client.clone();
-        let device_code_entry = self.device_code_entry.clone();
-        let status_label = self.status_label.clone();
-        let spinner = self.spinner.clone();
-        let submit_button = self.submit_button.clone();
-        let callback = std::sync::Arc::new(callback);
-
-        let submit_button_clone = submit_button.clone();
-        submit_button.connect_clicked(move |_| {
-            let device_code = device_code_entry.text().to_string();
-            if device_code.is_empty() {
-                status_label.set_text("Please enter a device code");
+        self.on_login.replace(Some(Rc::new(callback)));
+    }
+
+    /// Sets the callback invoked if the window closes before logging in
+    pub fn connect_cancel<F>(&self, callback: F)
+    where
+        F: Fn() + 'static,
+    {
+        self.on_cancel.replace(Some(Rc::new(callback)));
+    }
+
+    /// Asks the server for a fresh device code and starts waiting for approval
+    fn request_device_code(&self) {
+        let generation = self.generation.get().wrapping_add(1);
+        self.generation.set(generation);
+
+        self.device_code_label.set_text("");
+        self.expiry_label.set_text("");
+        self.status_label.set_text("Requesting a device code...");
+        self.spinner.set_spinning(true);
+        self.new_code_button.set_visible(false);
+        self.cancel_code_button.set_visible(true);
+
+        // A new code supersedes any request still out for the old one
+        let client = self.client.with_cancellation(replace_token(&self.code_cancel));
+        let this = self.clone();
+        glib::spawn_future_local(async move {
+            let requesting = client.clone();
+            let result = runtime::spawn(async move { requesting.get_device_code().await }).await;
+            if this.generation.get() != generation {
                 return;
             }
 
-            // Start spinner and disable input
-            spinner.set_spinning(true);
-            submit_button_clone.set_sensitive(false);
-            device_code_entry.set_sensitive(false);
-            status_label.set_text("Authenticating...");
-
-            let client = client.clone();
-            let device_code = device_code.clone();
-            let status_label = status_label.clone();
-            let spinner = spinner.clone();
-            let submit_button = submit_button_clone.clone();
-            let device_code_entry = device_code_entry.clone();
-            let callback = callback.clone();
-
-            glib::spawn_future_local(async move {
-                let mut client = client.lock().unwrap();
-                match client.poll_device_code(&device_code).await {
-                    Ok(response) => {
-                        if response.authenticated && response.result {
-                            status_label.set_text("Authentication successful!");
-                            spinner.set_spinning(false);
-                            callback();
+            match result {
+                Ok(response) => match response.device_code {
+                    Some(device_code) if response.result => {
+                        let lifetime = response
+                            .expires_in
+                            .filter(|&secs| secs > 0)
+                            .map(|secs| Duration::from_secs(secs as u64))
+                            .unwrap_or(DEFAULT_CODE_LIFETIME);
+                        let deadline = Instant::now() + lifetime;
+
+                        this.device_code_label.set_text(&device_code);
+                        this.status_label.set_text("Waiting for approval...");
+                        this.start_countdown(deadline, generation);
+                        this.start_polling(client, device_code, deadline, generation);
+                    }
+                    _ => this.show_failure(&format!("Could not get a device code: {}", response.message)),
+                },
+                Err(IBroadcastError::Cancelled) => {}
+                Err(e) => this.show_failure(&format!("Error: {}", e)),
+            }
+        });
+    }
+
+    /// Stops waiting for the current code, leaving the window ready for a new one
+    fn cancel_device_code(&self) {
+        replace_token(&self.code_cancel);
+        self.generation.set(self.generation.get().wrapping_add(1));
+        self.spinner.set_spinning(false);
+        self.device_code_label.set_text("");
+        self.expiry_label.set_text("");
+        self.status_label.set_text("Cancelled.");
+        self.cancel_code_button.set_visible(false);
+        self.new_code_button.set_visible(true);
+    }
+
+    /// Updates the expiry label every second until the code expires
+    fn start_countdown(&self, deadline: Instant, generation: u32) {
+        let this = self.clone();
+        this.update_expiry_label(deadline);
+        timeout_add_local(Duration::from_secs(1), move || {
+            if this.generation.get() != generation {
+                return ControlFlow::Break;
+            }
+            if Instant::now() >= deadline {
+                this.show_expired();
+                return ControlFlow::Break;
+            }
+            this.update_expiry_label(deadline);
+            ControlFlow::Continue
+        });
+    }
+
+    fn update_expiry_label(&self, deadline: Instant) {
+        let remaining = deadline.saturating_duration_since(Instant::now()).as_secs();
+        self.expiry_label
+            .set_text(&format!("Code expires in {}:{:02}", remaining / 60, remaining % 60));
+    }
+
+    /// Polls the server in the background until the code is approved or expires
+    fn start_polling(&self, client: IBroadcastClient, device_code: String, deadline: Instant, generation: u32) {
+        let this = self.clone();
+        glib::spawn_future_local(async move {
+            loop {
+                glib::timeout_future(POLL_INTERVAL).await;
+                if this.generation.get() != generation || Instant::now() >= deadline {
+                    return;
+                }
+
+                let polling = client.clone();
+                let code = device_code.clone();
+                let result = runtime::spawn(async move { polling.poll_device_code(&code).await }).await;
+                if this.generation.get() != generation {
+                    return;
+                }
+
+                match result {
+                    Ok(response) if response.authenticated && response.result => {
+                        if this.is_expected_account() {
+                            this.finish_login();
                         } else {
-                            status_label.set_text("Invalid device code. Please try again.");
-                            spinner.set_spinning(false);
-                            submit_button.set_sensitive(true);
-                            device_code_entry.set_sensitive(true);
+                            this.show_failure(WRONG_ACCOUNT_MESSAGE);
                         }
+                        return;
+                    }
+                    Ok(response) if !response.result => {
+                        log::info!("Device code rejected: {}", response.message);
+                        this.show_expired();
+                        return;
+                    }
+                    Ok(_) => {
+                        this.status_label.set_text("Waiting for approval...");
                     }
                     Err(e) => {
-                        status_label.set_text(&format!("Error: {}", e));
-                        spinner.set_spinning(false);
-                        submit_button.set_sensitive(true);
-                        device_code_entry.set_sensitive(true);
+                        log::warn!("Failed to poll device code: {}", e);
+                        this.status_label.set_text("Connection problem, still trying...");
+                    }
+                }
+            }
+        });
+    }
+
+    /// Whether the account just logged in to is the one expected. If it
+    /// isn't, the requests held for the lost session fail, and the other
+    /// account's session is dropped rather than kept for this profile.
+    fn is_expected_account(&self) -> bool {
+        let Some(expected) = self.expected_user_id.borrow().clone() else {
+            return true;
+        };
+        let user_id = self.client.session().and_then(|session| session.user_id);
+        if user_id.is_none() || user_id.as_deref() == Some(expected.as_str()) {
+            return true;
+        }
+        log::warn!("Logged in again to another account");
+        self.client.abandon_reauthentication();
+        self.client.forget_session();
+        false
+    }
+
+    fn finish_login(&self) {
+        self.logged_in.set(true);
+        self.generation.set(self.generation.get().wrapping_add(1));
+        self.spinner.set_spinning(false);
+        self.cancel_code_button.set_visible(false);
+        self.status_label.set_text("Authentication successful!");
+
+        // Clone the callback out so it may replace or drop this window's handlers
+        let callback = self.on_login.borrow().clone();
+        if let Some(callback) = callback {
+            callback();
+        }
+        self.window.close();
+    }
+
+    /// Logs in with the email and password from the form
+    fn submit_email_login(&self) {
+        let email = self.email_entry.text().trim().to_string();
+        let password = self.password_entry.text().to_string();
+
+        if email.is_empty() {
+            self.show_email_error("Please enter your email address", Some(self.email_entry.upcast_ref()));
+            return;
+        }
+        if password.is_empty() {
+            self.show_email_error("Please enter your password", Some(self.password_entry.upcast_ref()));
+            return;
+        }
+
+        self.clear_email_error();
+        self.set_email_form_busy(true);
+
+        let client = self.client.with_cancellation(replace_token(&self.login_cancel));
+        let this = self.clone();
+        glib::spawn_future_local(async move {
+            let (login_email, login_password) = (email.clone(), password.clone());
+            let result = runtime::spawn(async move { client.login(&login_email, &login_password).await }).await;
+            this.set_email_form_busy(false);
+
+            match result {
+                Ok(()) if !this.is_expected_account() => {
+                    this.show_email_error(WRONG_ACCOUNT_MESSAGE, Some(this.email_entry.upcast_ref()));
+                }
+                Ok(()) => {
+                    let remembered = if this.remember_check.is_active() {
+                        utils::remember_login(&this.profile, &email, &password)
+                    } else {
+                        utils::forget_login(&this.profile)
+                    };
+                    if let Err(e) = remembered {
+                        log::warn!("Failed to update remembered login: {}", e);
                     }
+                    this.finish_login();
+                }
+                Err(IBroadcastError::Authentication(message)) => {
+                    let lowered = message.to_lowercase();
+                    let field: Option<&gtk::Widget> = if lowered.contains("password") {
+                        Some(this.password_entry.upcast_ref())
+                    } else if lowered.contains("email") || lowered.contains("user") {
+                        Some(this.email_entry.upcast_ref())
+                    } else {
+                        None
+                    };
+                    this.show_email_error(&message, field);
+                }
+                Err(IBroadcastError::Network(e)) => {
+                    log::warn!("Login request failed: {}", e);
+                    this.show_email_error("Could not reach iBroadcast. Check your connection and try again.", None);
                 }
-            });
+                Err(IBroadcastError::Offline) => {
+                    this.show_email_error("You are offline. Connect to the internet and try again.", None);
+                }
+                Err(IBroadcastError::Cancelled) => log::info!("Login cancelled"),
+                Err(e) => this.show_email_error(&format!("Error: {}", e), None),
+            }
         });
     }
-} 
\ No newline at end of file
+
+    fn set_email_form_busy(&self, busy: bool) {
+        self.email_spinner.set_spinning(busy);
+        self.email_entry.set_sensitive(!busy);
+        self.password_entry.set_sensitive(!busy);
+        self.remember_check.set_sensitive(!busy);
+        self.login_button.set_sensitive(!busy);
+        self.cancel_login_button.set_visible(busy);
+    }
+
+    /// Shows an inline error under the form, highlighting the offending field
+    fn show_email_error(&self, message: &str, field: Option<&gtk::Widget>) {
+        self.email_error_label.set_text(message);
+        self.email_error_label.set_visible(true);
+        if let Some(field) = field {
+            field.add_css_class("error");
+            field.grab_focus();
+        }
+    }
+
+    fn clear_email_error(&self) {
+        self.email_error_label.set_visible(false);
+        self.email_entry.remove_css_class("error");
+        self.password_entry.remove_css_class("error");
+    }
+
+    fn show_expired(&self) {
+        self.generation.set(self.generation.get().wrapping_add(1));
+        self.spinner.set_spinning(false);
+        self.device_code_label.set_text("");
+        self.expiry_label.set_text("");
+        self.status_label.set_text("This code has expired.");
+        self.cancel_code_button.set_visible(false);
+        self.new_code_button.set_visible(true);
+    }
+
+    fn show_failure(&self, message: &str) {
+        self.spinner.set_spinning(false);
+        self.status_label.set_text(message);
+        self.cancel_code_button.set_visible(false);
+        self.new_code_button.set_visible(true);
+    }
+}
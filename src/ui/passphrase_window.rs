@@ -0,0 +1,268 @@
+use adw::prelude::*;
+use gtk::{Application, Box as GtkBox, Button, Label, PasswordEntry, Spinner};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::credentials::FileStore;
+use crate::runtime;
+
+/// Shortest passphrase accepted for a new credential file
+const MIN_PASSPHRASE_LEN: usize = 8;
+
+/// Why the passphrase is asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PassphrasePrompt {
+    /// There is no keyring, so secrets are kept in the file
+    Unlock,
+    /// The keyring works again, and the file's secrets can move into it
+    MoveToKeyring,
+}
+
+/// Asks for the passphrase of the encrypted credential file, or for a new
+/// one if the file doesn't exist yet
+#[derive(Clone)]
+pub struct PassphraseWindow {
+    window: adw::Window,
+    passphrase_entry: PasswordEntry,
+    confirm_entry: PasswordEntry,
+    error_label: Label,
+    spinner: Spinner,
+    submit_button: Button,
+    store: Arc<FileStore>,
+    /// The file is being created, so the passphrase is typed twice
+    creating: bool,
+    on_unlocked: Rc<RefCell<Option<Rc<dyn Fn()>>>>,
+    on_cancel: Rc<RefCell<Option<Rc<dyn Fn()>>>>,
+    unlocked: Rc<Cell<bool>>,
+}
+
+impl PassphraseWindow {
+    pub fn new(app: &Application, store: Arc<FileStore>, prompt: PassphrasePrompt) -> Self {
+        let creating = prompt == PassphrasePrompt::Unlock && !store.exists();
+        let (title, body, submit) = match (prompt, creating) {
+            (PassphrasePrompt::Unlock, true) => (
+                "Choose a Passphrase",
+                "No system keyring is available, so Latke keeps your logins in a file encrypted with a passphrase. You will be asked for it each time Latke starts.",
+                "Create",
+            ),
+            (PassphrasePrompt::Unlock, false) => (
+                "Unlock Saved Logins",
+                "No system keyring is available, so your logins are kept in a file encrypted with a passphrase.",
+                "Unlock",
+            ),
+            (PassphrasePrompt::MoveToKeyring, _) => (
+                "Move Saved Logins",
+                "The system keyring is available. Enter the passphrase of the file your logins were kept in to move them to the keyring, or close this window to leave them for now.",
+                "Move",
+            ),
+        };
+
+        let window = adw::Window::new();
+        window.set_application(Some(app));
+        window.set_title(Some("Latke"));
+        window.set_default_size(400, -1);
+        window.set_resizable(false);
+
+        let box_ = GtkBox::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .spacing(12)
+            .margin_top(12)
+            .margin_bottom(12)
+            .margin_start(12)
+            .margin_end(12)
+            .build();
+
+        let title_label = Label::builder()
+            .label(title)
+            .css_classes(vec!["title-2"])
+            .build();
+
+        let body_label = Label::builder()
+            .label(body)
+            .wrap(true)
+            .wrap_mode(gtk::pango::WrapMode::Word)
+            .build();
+
+        let passphrase_entry = PasswordEntry::builder()
+            .placeholder_text("Passphrase")
+            .show_peek_icon(true)
+            .activates_default(!creating)
+            .build();
+
+        let confirm_entry = PasswordEntry::builder()
+            .placeholder_text("Repeat passphrase")
+            .show_peek_icon(true)
+            .activates_default(true)
+            .visible(creating)
+            .build();
+
+        let error_label = Label::builder()
+            .label("")
+            .wrap(true)
+            .visible(false)
+            .css_classes(vec!["error"])
+            .build();
+
+        let spinner = Spinner::builder().spinning(false).build();
+
+        let submit_button = Button::builder()
+            .label(submit)
+            .css_classes(vec!["suggested-action"])
+            .halign(gtk::Align::End)
+            .build();
+
+        box_.append(&title_label);
+        box_.append(&body_label);
+        box_.append(&passphrase_entry);
+        box_.append(&confirm_entry);
+        box_.append(&error_label);
+        box_.append(&spinner);
+        box_.append(&submit_button);
+
+        let content = GtkBox::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        content.append(&adw::HeaderBar::new());
+        content.append(&box_);
+        window.set_content(Some(&content));
+        window.set_default_widget(Some(&submit_button));
+
+        let passphrase_window = Self {
+            window,
+            passphrase_entry,
+            confirm_entry,
+            error_label,
+            spinner,
+            submit_button,
+            store,
+            creating,
+            on_unlocked: Rc::new(RefCell::new(None)),
+            on_cancel: Rc::new(RefCell::new(None)),
+            unlocked: Rc::new(Cell::new(false)),
+        };
+
+        let this = passphrase_window.clone();
+        passphrase_window.submit_button.connect_clicked(move |_| {
+            this.submit();
+        });
+
+        if creating {
+            let this = passphrase_window.clone();
+            passphrase_window
+                .passphrase_entry
+                .connect_activate(move |_| {
+                    this.confirm_entry.grab_focus();
+                });
+        }
+
+        let this = passphrase_window.clone();
+        passphrase_window
+            .passphrase_entry
+            .connect_changed(move |_| {
+                this.clear_error();
+            });
+
+        let this = passphrase_window.clone();
+        passphrase_window.confirm_entry.connect_changed(move |_| {
+            this.clear_error();
+        });
+
+        let this = passphrase_window.clone();
+        passphrase_window.window.connect_close_request(move |_| {
+            if !this.unlocked.get() {
+                let callback = this.on_cancel.borrow().clone();
+                if let Some(callback) = callback {
+                    callback();
+                }
+            }
+            glib::Propagation::Proceed
+        });
+
+        passphrase_window
+    }
+
+    pub fn show(&self) {
+        self.window.present();
+    }
+
+    /// Sets the callback invoked once the file is unlocked
+    pub fn connect_unlocked<F>(&self, callback: F)
+    where
+        F: Fn() + 'static,
+    {
+        self.on_unlocked.replace(Some(Rc::new(callback)));
+    }
+
+    /// Sets the callback invoked if the window closes before unlocking
+    pub fn connect_cancel<F>(&self, callback: F)
+    where
+        F: Fn() + 'static,
+    {
+        self.on_cancel.replace(Some(Rc::new(callback)));
+    }
+
+    fn submit(&self) {
+        let passphrase = self.passphrase_entry.text().to_string();
+        if passphrase.is_empty() {
+            self.show_error("Please enter the passphrase");
+            return;
+        }
+        if self.creating {
+            if passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+                self.show_error(&format!("Use at least {} characters", MIN_PASSPHRASE_LEN));
+                return;
+            }
+            if passphrase != self.confirm_entry.text() {
+                self.show_error("The passphrases don't match");
+                return;
+            }
+        }
+
+        self.clear_error();
+        self.set_busy(true);
+
+        let store = self.store.clone();
+        let this = self.clone();
+        glib::spawn_future_local(async move {
+            // Deriving the key takes a moment, on purpose
+            let result = runtime::spawn(async move {
+                tokio::task::spawn_blocking(move || store.unlock(&passphrase)).await
+            })
+            .await;
+            this.set_busy(false);
+
+            match result {
+                Ok(Ok(())) => {
+                    this.unlocked.set(true);
+                    let callback = this.on_unlocked.borrow().clone();
+                    if let Some(callback) = callback {
+                        callback();
+                    }
+                    this.window.close();
+                }
+                Ok(Err(e)) => {
+                    this.show_error(&e.to_string());
+                    this.passphrase_entry.grab_focus();
+                }
+                Err(e) => this.show_error(&format!("Error: {}", e)),
+            }
+        });
+    }
+
+    fn set_busy(&self, busy: bool) {
+        self.spinner.set_spinning(busy);
+        self.passphrase_entry.set_sensitive(!busy);
+        self.confirm_entry.set_sensitive(!busy);
+        self.submit_button.set_sensitive(!busy);
+    }
+
+    fn show_error(&self, message: &str) {
+        self.error_label.set_text(message);
+        self.error_label.set_visible(true);
+    }
+
+    fn clear_error(&self) {
+        self.error_label.set_visible(false);
+    }
+}
@@ -0,0 +1,322 @@
+use adw::prelude::*;
+use gtk::{Box as GtkBox, Button, Label, Scale, ToggleButton};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::Duration;
+use glib::timeout_add_local;
+use glib::ControlFlow;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::player::{Player, PlayerEvent, PlayerState};
+use crate::queue::RepeatMode;
+
+/// How often the position slider follows the pipeline
+const POSITION_UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Requests from the transport controls that need the queue to resolve
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayerBarAction {
+    /// Play was pressed with nothing loaded in the player
+    Play,
+    Previous,
+    Next,
+    Shuffle(bool),
+    Repeat(RepeatMode),
+}
+
+/// Transport controls shown along the bottom of the main window
+#[derive(Clone)]
+pub struct PlayerBar {
+    root: GtkBox,
+    play_button: Button,
+    shuffle_button: ToggleButton,
+    repeat_button: Button,
+    repeat: Rc<Cell<RepeatMode>>,
+    on_action: Rc<RefCell<Option<Rc<dyn Fn(PlayerBarAction)>>>>,
+    title_label: Label,
+    artist_label: Label,
+    position_scale: Scale,
+    time_label: Label,
+    player: Player,
+    /// Set while the position slider is updated from the pipeline, so the
+    /// update isn't mistaken for the user seeking
+    updating_position: Rc<Cell<bool>>,
+}
+
+impl PlayerBar {
+    pub fn new(player: Player) -> Self {
+        let root = GtkBox::builder()
+            .orientation(gtk::Orientation::Horizontal)
+            .spacing(12)
+            .margin_top(6)
+            .margin_bottom(6)
+            .margin_start(12)
+            .margin_end(12)
+            .build();
+
+        let previous_button = Button::builder()
+            .icon_name("media-skip-backward-symbolic")
+            .tooltip_text("Previous")
+            .css_classes(vec!["flat"])
+            .build();
+
+        let play_button = Button::builder()
+            .icon_name("media-playback-start-symbolic")
+            .tooltip_text("Play")
+            .css_classes(vec!["circular"])
+            .build();
+
+        let next_button = Button::builder()
+            .icon_name("media-skip-forward-symbolic")
+            .tooltip_text("Next")
+            .css_classes(vec!["flat"])
+            .build();
+
+        let shuffle_button = ToggleButton::builder()
+            .icon_name("media-playlist-shuffle-symbolic")
+            .tooltip_text("Shuffle")
+            .css_classes(vec!["flat"])
+            .build();
+
+        let repeat_button = Button::builder()
+            .icon_name("media-playlist-consecutive-symbolic")
+            .tooltip_text("Repeat: Off")
+            .css_classes(vec!["flat"])
+            .build();
+
+        let title_label = Label::builder()
+            .label("")
+            .xalign(0.0)
+            .ellipsize(gtk::pango::EllipsizeMode::End)
+            .css_classes(vec!["heading"])
+            .build();
+
+        let artist_label = Label::builder()
+            .label("")
+            .xalign(0.0)
+            .ellipsize(gtk::pango::EllipsizeMode::End)
+            .css_classes(vec!["dim-label"])
+            .build();
+
+        let labels = GtkBox::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .valign(gtk::Align::Center)
+            .width_request(200)
+            .build();
+        labels.append(&title_label);
+        labels.append(&artist_label);
+
+        let position_scale = Scale::builder()
+            .orientation(gtk::Orientation::Horizontal)
+            .adjustment(&gtk::Adjustment::new(0.0, 0.0, 1.0, 1.0, 10.0, 0.0))
+            .hexpand(true)
+            .sensitive(false)
+            .build();
+
+        let time_label = Label::builder()
+            .label("0:00 / 0:00")
+            .css_classes(vec!["numeric", "dim-label"])
+            .build();
+
+        let volume_scale = Scale::builder()
+            .orientation(gtk::Orientation::Horizontal)
+            .adjustment(&gtk::Adjustment::new(player.volume(), 0.0, 1.0, 0.05, 0.1, 0.0))
+            .width_request(120)
+            .tooltip_text("Volume")
+            .build();
+
+        root.append(&previous_button);
+        root.append(&play_button);
+        root.append(&next_button);
+        root.append(&labels);
+        root.append(&position_scale);
+        root.append(&time_label);
+        root.append(&shuffle_button);
+        root.append(&repeat_button);
+        root.append(&gtk::Image::from_icon_name("audio-volume-high-symbolic"));
+        root.append(&volume_scale);
+
+        let bar = Self {
+            root,
+            play_button,
+            shuffle_button,
+            repeat_button,
+            repeat: Rc::new(Cell::new(RepeatMode::Off)),
+            on_action: Rc::new(RefCell::new(None)),
+            title_label,
+            artist_label,
+            position_scale,
+            time_label,
+            player,
+            updating_position: Rc::new(Cell::new(false)),
+        };
+
+        let this = bar.clone();
+        bar.play_button.connect_clicked(move |_| {
+            if this.player.uri().is_none() {
+                this.emit(PlayerBarAction::Play);
+            } else if let Err(e) = this.player.toggle() {
+                log::error!("Failed to toggle playback: {}", e);
+            }
+        });
+
+        let this = bar.clone();
+        previous_button.connect_clicked(move |_| this.emit(PlayerBarAction::Previous));
+
+        let this = bar.clone();
+        next_button.connect_clicked(move |_| this.emit(PlayerBarAction::Next));
+
+        let this = bar.clone();
+        bar.shuffle_button.connect_toggled(move |button| {
+            this.emit(PlayerBarAction::Shuffle(button.is_active()));
+        });
+
+        let this = bar.clone();
+        bar.repeat_button.connect_clicked(move |_| {
+            let repeat = match this.repeat.get() {
+                RepeatMode::Off => RepeatMode::All,
+                RepeatMode::All => RepeatMode::One,
+                RepeatMode::One => RepeatMode::Off,
+            };
+            this.set_repeat(repeat);
+            this.emit(PlayerBarAction::Repeat(repeat));
+        });
+
+        let player = bar.player.clone();
+        let updating_position = bar.updating_position.clone();
+        bar.position_scale.connect_value_changed(move |scale| {
+            if updating_position.get() {
+                return;
+            }
+            if let Err(e) = player.seek(Duration::from_secs_f64(scale.value().max(0.0))) {
+                log::warn!("Failed to seek: {}", e);
+            }
+        });
+
+        let player = bar.player.clone();
+        volume_scale.connect_value_changed(move |scale| {
+            player.set_volume(scale.value());
+        });
+
+        bar.watch_player();
+        bar
+    }
+
+    pub fn widget(&self) -> &GtkBox {
+        &self.root
+    }
+
+    /// Sets the handler for queue-related requests from the controls
+    pub fn connect_action<F>(&self, callback: F)
+    where
+        F: Fn(PlayerBarAction) + 'static,
+    {
+        self.on_action.replace(Some(Rc::new(callback)));
+    }
+
+    fn emit(&self, action: PlayerBarAction) {
+        let callback = self.on_action.borrow().clone();
+        if let Some(callback) = callback {
+            callback(action);
+        }
+    }
+
+    /// Shows what is currently loaded in the player
+    pub fn set_now_playing(&self, title: &str, artist: &str) {
+        self.title_label.set_text(title);
+        self.artist_label.set_text(artist);
+        self.position_scale.set_sensitive(true);
+    }
+
+    /// Clears the track display, e.g. when the queue runs out
+    pub fn clear_now_playing(&self) {
+        self.title_label.set_text("");
+        self.artist_label.set_text("");
+        self.position_scale.set_sensitive(false);
+    }
+
+    /// Reflects the queue's shuffle state without emitting an action
+    pub fn set_shuffle(&self, shuffle: bool) {
+        let callback = self.on_action.take();
+        self.shuffle_button.set_active(shuffle);
+        self.on_action.replace(callback);
+    }
+
+    /// Reflects the queue's repeat mode without emitting an action
+    pub fn set_repeat(&self, repeat: RepeatMode) {
+        self.repeat.set(repeat);
+        let (icon, tooltip) = match repeat {
+            RepeatMode::Off => ("media-playlist-consecutive-symbolic", "Repeat: Off"),
+            RepeatMode::All => ("media-playlist-repeat-symbolic", "Repeat: All"),
+            RepeatMode::One => ("media-playlist-repeat-song-symbolic", "Repeat: One"),
+        };
+        self.repeat_button.set_icon_name(icon);
+        self.repeat_button.set_tooltip_text(Some(tooltip));
+    }
+
+    /// Reacts to player events and keeps the position slider in sync
+    fn watch_player(&self) {
+        let this = self.clone();
+        let mut events = self.player.subscribe();
+        glib::spawn_future_local(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => this.handle_event(event),
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::debug!("Player bar skipped {} events", skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+
+        let this = self.clone();
+        timeout_add_local(POSITION_UPDATE_INTERVAL, move || {
+            if this.player.state() == PlayerState::Playing {
+                this.update_position();
+            }
+            ControlFlow::Continue
+        });
+    }
+
+    fn handle_event(&self, event: PlayerEvent) {
+        match event {
+            PlayerEvent::StateChanged(PlayerState::Playing) => {
+                self.play_button.set_icon_name("media-playback-pause-symbolic");
+                self.play_button.set_tooltip_text(Some("Pause"));
+            }
+            PlayerEvent::StateChanged(_) => {
+                self.play_button.set_icon_name("media-playback-start-symbolic");
+                self.play_button.set_tooltip_text(Some("Play"));
+                self.update_position();
+            }
+            PlayerEvent::DurationChanged(_) => self.update_position(),
+            PlayerEvent::EndOfStream => {
+                self.play_button.set_icon_name("media-playback-start-symbolic");
+                self.play_button.set_tooltip_text(Some("Play"));
+            }
+            PlayerEvent::Error(message) => {
+                self.artist_label.set_text(&format!("Playback error: {}", message));
+            }
+            PlayerEvent::Buffering(_) => {}
+        }
+    }
+
+    fn update_position(&self) {
+        let position = self.player.position().unwrap_or_default();
+        let duration = self.player.duration().unwrap_or_default();
+
+        self.updating_position.set(true);
+        self.position_scale.set_range(0.0, duration.as_secs_f64().max(1.0));
+        self.position_scale.set_value(position.as_secs_f64());
+        self.updating_position.set(false);
+
+        self.time_label
+            .set_text(&format!("{} / {}", format_time(position), format_time(duration)));
+    }
+}
+
+fn format_time(time: Duration) -> String {
+    let seconds = time.as_secs();
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
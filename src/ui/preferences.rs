@@ -0,0 +1,97 @@
+use adw::prelude::*;
+use gtk::{Button, SpinButton};
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::cache::StreamCache;
+use crate::settings::Settings;
+
+/// Largest stream cache offered, in MiB
+const MAX_STREAM_CACHE_MB: f64 = 100.0 * 1024.0;
+
+/// Preferences dialog. Changes apply and save as soon as they are made.
+pub struct PreferencesWindow {
+    window: adw::PreferencesWindow,
+}
+
+impl PreferencesWindow {
+    pub fn new(
+        parent: &impl IsA<gtk::Window>,
+        settings: Rc<RefCell<Settings>>,
+        settings_path: PathBuf,
+        stream_cache: Option<StreamCache>,
+    ) -> Self {
+        let window = adw::PreferencesWindow::builder()
+            .transient_for(parent)
+            .modal(true)
+            .build();
+
+        let storage = adw::PreferencesGroup::builder()
+            .title("Storage")
+            .description("Pinned downloads are not counted against the stream cache")
+            .build();
+
+        let quota = SpinButton::with_range(0.0, MAX_STREAM_CACHE_MB, 256.0);
+        quota.set_valign(gtk::Align::Center);
+        quota.set_value(settings.borrow().stream_cache_quota_mb as f64);
+        let quota_row = adw::ActionRow::builder()
+            .title("Stream Cache Size (MiB)")
+            .subtitle("Recently played tracks kept for replay; 0 turns the cache off")
+            .build();
+        quota_row.add_suffix(&quota);
+        quota_row.set_activatable_widget(Some(&quota));
+        storage.add(&quota_row);
+
+        let clear_button = Button::builder()
+            .label("Clear")
+            .valign(gtk::Align::Center)
+            .sensitive(stream_cache.is_some())
+            .build();
+        let clear_row = adw::ActionRow::builder()
+            .title("Cached Streams")
+            .subtitle(usage_text(stream_cache.as_ref()))
+            .build();
+        clear_row.add_suffix(&clear_button);
+        storage.add(&clear_row);
+
+        let page = adw::PreferencesPage::new();
+        page.add(&storage);
+        window.add(&page);
+
+        let cache = stream_cache.clone();
+        let row = clear_row.clone();
+        quota.connect_value_changed(move |quota| {
+            let quota_mb = quota.value() as u64;
+            let mut settings = settings.borrow_mut();
+            settings.stream_cache_quota_mb = quota_mb;
+            if let Err(e) = settings.save(&settings_path) {
+                log::error!("Failed to save settings: {}", e);
+            }
+            if let Some(cache) = &cache {
+                cache.set_quota(settings.stream_cache_quota_bytes());
+            }
+            row.set_subtitle(&usage_text(cache.as_ref()));
+        });
+
+        clear_button.connect_clicked(move |_| {
+            if let Some(cache) = &stream_cache {
+                cache.clear();
+            }
+            clear_row.set_subtitle(&usage_text(stream_cache.as_ref()));
+        });
+
+        Self { window }
+    }
+
+    pub fn show(&self) {
+        self.window.present();
+    }
+}
+
+fn usage_text(stream_cache: Option<&StreamCache>) -> String {
+    match stream_cache {
+        Some(cache) => format!("{:.1} MiB in use", cache.usage() as f64 / (1024.0 * 1024.0)),
+        None => "Unavailable".to_string(),
+    }
+}
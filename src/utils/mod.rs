@@ -1,27 +1,241 @@
 use anyhow::Result;
-use keyring::Entry;
 use log::info;
+use std::path::PathBuf;
+
+use crate::api::{redact, Session};
+use crate::credentials::{self, CredentialStore};
+use crate::profiles::Profile;
+
+/// Keyring service name under which Latke stores its secrets
+pub const KEYRING_SERVICE: &str = "com.github.latke";
+const SESSION_KEY: &str = "session";
+const REMEMBERED_EMAIL_KEY: &str = "remembered-email";
+
+/// Directory for persistent application data (XDG data dir)
+pub fn data_dir() -> PathBuf {
+    glib::user_data_dir().join("latke")
+}
+
+/// Directory for user preferences (XDG config dir)
+pub fn config_dir() -> PathBuf {
+    glib::user_config_dir().join("latke")
+}
+
+/// Directory for data that can be rebuilt, like downloaded tracks (XDG cache dir)
+pub fn cache_dir() -> PathBuf {
+    glib::user_cache_dir().join("latke")
+}
 
-#[allow(dead_code)]
 pub fn save_credentials(service: &str, username: &str, password: &str) -> Result<()> {
-    let entry = Entry::new(service, username)?;
-    entry.set_password(password)?;
-    info!("Credentials saved for user: {}", username);
+    credentials::store().set(service, username, password)?;
+    info!("Credentials saved for user: {}", redact::email(username));
     Ok(())
 }
 
-#[allow(dead_code)]
-pub fn get_credentials(service: &str, username: &str) -> Result<String> {
-    let entry = Entry::new(service, username)?;
-    let password = entry.get_password()?;
-    info!("Retrieved credentials for user: {}", username);
+/// Returns the stored password, or `None` if there is none
+pub fn get_credentials(service: &str, username: &str) -> Result<Option<String>> {
+    let password = credentials::store().get(service, username)?;
+    if password.is_some() {
+        info!("Retrieved credentials for user: {}", redact::email(username));
+    }
     Ok(password)
 }
 
-#[allow(dead_code)]
 pub fn delete_credentials(service: &str, username: &str) -> Result<()> {
-    let entry = Entry::new(service, username)?;
-    entry.delete_password()?;
-    info!("Deleted credentials for user: {}", username);
+    credentials::store().delete(service, username)?;
+    info!("Deleted credentials for user: {}", redact::email(username));
+    Ok(())
+}
+
+/// Stores the profile's session in the credential store so it survives
+/// restarts
+pub fn save_session(profile: &Profile, session: &Session) -> Result<()> {
+    let json = serde_json::to_string(session)?;
+    credentials::store().set(KEYRING_SERVICE, &profile.keyring_user(SESSION_KEY), &json)?;
+    info!("Session saved");
+    Ok(())
+}
+
+/// Loads the profile's stored session, returning `None` if there is none
+pub fn load_session(profile: &Profile) -> Result<Option<Session>> {
+    match credentials::store().get(KEYRING_SERVICE, &profile.keyring_user(SESSION_KEY))? {
+        Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+        None => Ok(None),
+    }
+}
+
+/// Removes the profile's stored session, if any
+pub fn clear_session(profile: &Profile) -> Result<()> {
+    credentials::store().delete(KEYRING_SERVICE, &profile.keyring_user(SESSION_KEY))?;
+    info!("Session cleared");
+    Ok(())
+}
+
+/// Saves email and password for the login form's "Remember me" option
+pub fn remember_login(profile: &Profile, email: &str, password: &str) -> Result<()> {
+    // Drop credentials saved for a different email first
+    if let Some((previous, _)) = remembered_login(profile)? {
+        if previous != email {
+            delete_credentials(KEYRING_SERVICE, &profile.keyring_user(&previous))?;
+        }
+    }
+    save_credentials(KEYRING_SERVICE, &profile.keyring_user(email), password)?;
+    let email_key = profile.keyring_user(REMEMBERED_EMAIL_KEY);
+    save_credentials(KEYRING_SERVICE, &email_key, email)
+}
+
+/// Returns the profile's remembered email and password, if any
+pub fn remembered_login(profile: &Profile) -> Result<Option<(String, String)>> {
+    let email_key = profile.keyring_user(REMEMBERED_EMAIL_KEY);
+    let Some(email) = get_credentials(KEYRING_SERVICE, &email_key)? else {
+        return Ok(None);
+    };
+    let password = get_credentials(KEYRING_SERVICE, &profile.keyring_user(&email))?;
+    Ok(password.map(|password| (email, password)))
+}
+
+/// Removes the profile's remembered email and password, if any
+pub fn forget_login(profile: &Profile) -> Result<()> {
+    if let Some((email, _)) = remembered_login(profile)? {
+        delete_credentials(KEYRING_SERVICE, &profile.keyring_user(&email))?;
+        delete_credentials(KEYRING_SERVICE, &profile.keyring_user(REMEMBERED_EMAIL_KEY))?;
+    }
     Ok(())
-} 
\ No newline at end of file
+}
+
+/// Moves the session and remembered login stored before profiles existed
+/// over to `profile`
+pub fn adopt_legacy_secrets(profile: &Profile) -> Result<()> {
+    let store = credentials::store();
+    move_secret(&*store, SESSION_KEY, &profile.keyring_user(SESSION_KEY))?;
+    let email_key = profile.keyring_user(REMEMBERED_EMAIL_KEY);
+    if let Some(email) = move_secret(&*store, REMEMBERED_EMAIL_KEY, &email_key)? {
+        move_secret(&*store, &email, &profile.keyring_user(&email))?;
+    }
+    Ok(())
+}
+
+/// Moves a secret to another username, returning it if there was one
+fn move_secret(store: &dyn CredentialStore, from: &str, to: &str) -> Result<Option<String>> {
+    let Some(secret) = store.get(KEYRING_SERVICE, from)? else {
+        return Ok(None);
+    };
+    store.set(KEYRING_SERVICE, to, &secret)?;
+    store.delete(KEYRING_SERVICE, from)?;
+    Ok(Some(secret))
+}
+
+/// Moves the sessions and remembered logins of `profiles` from one
+/// credential store to another, returning how many secrets moved. Nothing
+/// is deleted from `from` until everything has been copied.
+pub fn move_secrets(
+    from: &dyn CredentialStore,
+    to: &dyn CredentialStore,
+    profiles: &[Profile],
+) -> Result<usize> {
+    let mut moved = Vec::new();
+    for profile in profiles {
+        let email_key = profile.keyring_user(REMEMBERED_EMAIL_KEY);
+        let email = from.get(KEYRING_SERVICE, &email_key)?;
+        let mut users = vec![profile.keyring_user(SESSION_KEY), email_key];
+        users.extend(email.map(|email| profile.keyring_user(&email)));
+        for user in users {
+            if let Some(secret) = from.get(KEYRING_SERVICE, &user)? {
+                to.set(KEYRING_SERVICE, &user, &secret)?;
+                moved.push(user);
+            }
+        }
+    }
+    for user in &moved {
+        from.delete(KEYRING_SERVICE, user)?;
+    }
+    Ok(moved.len())
+}
+
+/// Where the body of a `206 Partial Content` reply starts, and the full
+/// length if the server knows it, from the `Content-Range` header
+pub fn content_range(response: &reqwest::Response) -> Option<(u64, Option<u64>)> {
+    let value = response.headers().get(reqwest::header::CONTENT_RANGE)?.to_str().ok()?;
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _end) = range.split_once('-')?;
+    Some((start.trim().parse().ok()?, total.trim().parse().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::FileStore;
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+
+    /// Stands in for the keyring
+    #[derive(Default)]
+    struct MemoryStore(Mutex<BTreeMap<(String, String), String>>);
+
+    impl CredentialStore for MemoryStore {
+        fn get(&self, service: &str, user: &str) -> Result<Option<String>> {
+            let secrets = self.0.lock().unwrap();
+            Ok(secrets.get(&(service.to_string(), user.to_string())).cloned())
+        }
+
+        fn set(&self, service: &str, user: &str, secret: &str) -> Result<()> {
+            let mut secrets = self.0.lock().unwrap();
+            secrets.insert((service.to_string(), user.to_string()), secret.to_string());
+            Ok(())
+        }
+
+        fn delete(&self, service: &str, user: &str) -> Result<()> {
+            self.0.lock().unwrap().remove(&(service.to_string(), user.to_string()));
+            Ok(())
+        }
+    }
+
+    fn profile(id: &str) -> Profile {
+        Profile { id: id.to_string(), name: id.to_string() }
+    }
+
+    /// Gives `profile` a session and a remembered login in `store`
+    fn store_secrets(store: &dyn CredentialStore, profile: &Profile, email: &str) {
+        store.set(KEYRING_SERVICE, &profile.keyring_user(SESSION_KEY), "{}").unwrap();
+        store.set(KEYRING_SERVICE, &profile.keyring_user(REMEMBERED_EMAIL_KEY), email).unwrap();
+        store.set(KEYRING_SERVICE, &profile.keyring_user(email), "hunter2").unwrap();
+    }
+
+    fn secrets(store: &dyn CredentialStore, profile: &Profile, email: &str) -> Vec<Option<String>> {
+        [SESSION_KEY, REMEMBERED_EMAIL_KEY, email]
+            .iter()
+            .map(|key| store.get(KEYRING_SERVICE, &profile.keyring_user(key)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn secrets_move_between_the_keyring_and_the_file() {
+        let dir = TempDir::new().unwrap();
+        let file = FileStore::new(dir.path().join("secrets.json"));
+        file.unlock("correct horse").unwrap();
+        let keyring = MemoryStore::default();
+        let profiles = [profile("first"), profile("second")];
+        store_secrets(&keyring, &profiles[0], "first@example.com");
+        // The second profile only has a session
+        keyring.set(KEYRING_SERVICE, &profiles[1].keyring_user(SESSION_KEY), "{}").unwrap();
+
+        let to_file = move_secrets(&keyring, &file, &profiles).unwrap();
+        let in_file = secrets(&file, &profiles[0], "first@example.com");
+        let left_in_keyring = keyring.0.lock().unwrap().len();
+
+        let back = move_secrets(&file, &keyring, &profiles).unwrap();
+        let in_keyring = secrets(&keyring, &profiles[0], "first@example.com");
+        let left_in_file = secrets(&file, &profiles[0], "first@example.com");
+        let second = keyring.get(KEYRING_SERVICE, &profiles[1].keyring_user(SESSION_KEY)).unwrap();
+
+        let expected = ["{}", "first@example.com", "hunter2"].map(|secret| Some(secret.to_string()));
+        assert_eq!(to_file, 4);
+        assert_eq!(in_file, expected);
+        assert_eq!(left_in_keyring, 0);
+        assert_eq!(back, 4);
+        assert_eq!(in_keyring, expected);
+        assert_eq!(left_in_file, [None, None, None]);
+        assert_eq!(second.as_deref(), Some("{}"));
+    }
+}
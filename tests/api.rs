@@ -0,0 +1,324 @@
+//! The API client against the in-process mock server
+
+use latke::api::mock::MockServer;
+use latke::api::{IBroadcastClient, IBroadcastError, RetryPolicy, Session, SessionEvent, Track};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const EMAIL: &str = "listener@example.com";
+const PASSWORD: &str = "hunter2";
+
+async fn start() -> (MockServer, IBroadcastClient) {
+    let server = MockServer::start().await.unwrap();
+    server.add_account(EMAIL, PASSWORD);
+    let client = IBroadcastClient::with_base_url(server.url());
+    (server, client)
+}
+
+async fn logged_in() -> (MockServer, IBroadcastClient) {
+    let (server, client) = start().await;
+    client.login(EMAIL, PASSWORD).await.unwrap();
+    (server, client)
+}
+
+fn track(id: u64, title: &str) -> Track {
+    Track {
+        id,
+        title: title.to_string(),
+        length: Some(180),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn login_starts_a_session() {
+    let (server, client) = start().await;
+    assert!(client.session().is_none());
+
+    client.login(EMAIL, PASSWORD).await.unwrap();
+
+    let session = client.session().unwrap();
+    assert_eq!(session.user_id.as_deref(), Some(EMAIL));
+    assert!(session.expires_at.is_some());
+    let request = &server.requests()[0];
+    assert_eq!(request["mode"], "login");
+    assert_eq!(request["app"], "Latke");
+    assert!(!request.contains_key("token"));
+}
+
+#[tokio::test]
+async fn login_with_a_wrong_password_fails() {
+    let (_server, client) = start().await;
+
+    let result = client.login(EMAIL, "wrong").await;
+
+    assert!(matches!(result, Err(IBroadcastError::Authentication(_))));
+    assert!(client.session().is_none());
+}
+
+#[tokio::test]
+async fn device_code_logs_in_once_approved() {
+    let (server, client) = start().await;
+
+    let code = client.get_device_code().await.unwrap();
+    let device_code = code.device_code.unwrap();
+    assert!(code.expires_in.is_some());
+
+    let pending = client.poll_device_code(&device_code).await.unwrap();
+    assert!(pending.result && !pending.authenticated);
+    assert!(client.session().is_none());
+
+    server.approve_device_code(&device_code);
+    let approved = client.poll_device_code(&device_code).await.unwrap();
+    assert!(approved.authenticated);
+    assert!(client.session().is_some());
+}
+
+#[tokio::test]
+async fn expired_device_code_is_refused() {
+    let (server, client) = start().await;
+    let device_code = client.get_device_code().await.unwrap().device_code.unwrap();
+
+    server.expire_device_code(&device_code);
+    let response = client.poll_device_code(&device_code).await.unwrap();
+
+    assert!(!response.result && !response.authenticated);
+    assert!(client.session().is_none());
+}
+
+#[tokio::test]
+async fn a_new_login_drops_the_old_tokens_expiry() {
+    let (server, client) = start().await;
+    client.restore_session(Session {
+        token: "stale".to_string(),
+        user_id: None,
+        expires_at: Some(1),
+    });
+
+    let device_code = client.get_device_code().await.unwrap().device_code.unwrap();
+    server.approve_device_code(&device_code);
+    client.poll_device_code(&device_code).await.unwrap();
+
+    assert_eq!(client.session().unwrap().expires_at, None);
+    client.get_library().await.unwrap();
+    assert_eq!(count(&server, "refresh"), 0);
+}
+
+#[tokio::test]
+async fn requests_need_a_session() {
+    let (_server, client) = start().await;
+
+    let result = client.get_library().await;
+
+    assert!(matches!(result, Err(IBroadcastError::NotLoggedIn)));
+}
+
+#[tokio::test]
+async fn library_round_trips_through_the_columnar_format() {
+    let (server, client) = logged_in().await;
+    server.add_track(track(1, "First"), Vec::new());
+    server.add_track(track(2, "Second"), Vec::new());
+
+    let library = client.get_library().await.unwrap();
+
+    assert_eq!(library.tracks.len(), 2);
+    assert_eq!(library.tracks[&2].title, "Second");
+    assert_eq!(library.tracks[&2].length, Some(180));
+}
+
+#[tokio::test]
+async fn stream_url_serves_the_track() {
+    let (server, client) = logged_in().await;
+    let audio: Vec<u8> = (0..=255).collect();
+    server.add_track(track(7, "Song"), audio.clone());
+
+    let playback = client.get_stream_url("7").await.unwrap();
+    assert_eq!(playback.duration, 180);
+
+    let http = reqwest::Client::new();
+    let body = http.get(&playback.stream_url).send().await.unwrap().bytes().await.unwrap();
+    assert_eq!(body.as_ref(), audio.as_slice());
+
+    let partial = http
+        .get(&playback.stream_url)
+        .header(reqwest::header::RANGE, "bytes=200-")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(partial.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+    let etag = partial.headers()[reqwest::header::ETAG].clone();
+    assert_eq!(partial.bytes().await.unwrap().as_ref(), &audio[200..]);
+
+    // A copy of a file that has since changed gets the whole new file
+    server.add_track(track(7, "Song"), audio[..100].to_vec());
+    let stale = http
+        .get(&playback.stream_url)
+        .header(reqwest::header::RANGE, "bytes=50-")
+        .header(reqwest::header::IF_RANGE, etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(stale.status(), reqwest::StatusCode::OK);
+    assert_eq!(stale.bytes().await.unwrap().as_ref(), &audio[..100]);
+}
+
+#[tokio::test]
+async fn unknown_track_has_no_stream() {
+    let (_server, client) = logged_in().await;
+
+    let result = client.get_stream_url("404").await;
+
+    assert!(matches!(result, Err(IBroadcastError::Api(_))));
+}
+
+#[tokio::test]
+async fn playlists_can_be_created_edited_and_deleted() {
+    let (server, client) = logged_in().await;
+    server.add_track(track(1, "First"), Vec::new());
+    server.add_track(track(2, "Second"), Vec::new());
+
+    let id = client.create_playlist("Mix").await.unwrap();
+    let playlist = client.get_playlists().await.unwrap();
+    assert_eq!(playlist.playlist_id, id.to_string());
+    assert_eq!(playlist.name, "Mix");
+
+    client.add_to_playlist(&id.to_string(), "1").await.unwrap();
+    client.add_to_playlist(&id.to_string(), "2").await.unwrap();
+    client.remove_from_playlist(&id.to_string(), "1").await.unwrap();
+    let library = client.get_library().await.unwrap();
+    assert_eq!(library.playlists[&id].tracks, vec![2]);
+
+    client.delete_playlist(&id.to_string()).await.unwrap();
+    assert!(server.library().playlists.is_empty());
+    let again = client.delete_playlist(&id.to_string()).await;
+    assert!(matches!(again, Err(IBroadcastError::Api(_))));
+}
+
+/// Retries quickly, so tests don't sit through the default backoff
+fn fast_retries(client: &IBroadcastClient) {
+    client.set_retry_policy(RetryPolicy {
+        base_delay: Duration::from_millis(10),
+        ..RetryPolicy::default()
+    });
+}
+
+fn count(server: &MockServer, mode: &str) -> usize {
+    server.modes().iter().filter(|sent| *sent == mode).count()
+}
+
+#[tokio::test]
+async fn failed_reads_are_retried() {
+    let (server, client) = logged_in().await;
+    fast_retries(&client);
+    server.reject_next(503, None);
+    server.reject_next(429, Some("0"));
+
+    client.get_library().await.unwrap();
+
+    assert_eq!(count(&server, "getlibrary"), 3);
+}
+
+#[tokio::test]
+async fn rate_limited_mutations_are_retried() {
+    let (server, client) = logged_in().await;
+    fast_retries(&client);
+    server.reject_next(429, Some("0"));
+
+    client.create_playlist("Mix").await.unwrap();
+
+    assert_eq!(count(&server, "createplaylist"), 2);
+    assert_eq!(server.library().playlists.len(), 1);
+}
+
+#[tokio::test]
+async fn mutations_that_may_have_taken_effect_are_sent_once() {
+    let (server, client) = logged_in().await;
+    fast_retries(&client);
+    server.fail_next_after_handling(500);
+
+    let result = client.create_playlist("Mix").await;
+
+    assert!(matches!(result, Err(IBroadcastError::Api(_))));
+    assert_eq!(count(&server, "createplaylist"), 1);
+    assert_eq!(server.library().playlists.len(), 1);
+}
+
+/// Loses the session while a request is out, returning the held request
+async fn lose_session(
+    server: &MockServer,
+    client: &IBroadcastClient,
+) -> tokio::task::JoinHandle<Result<(), IBroadcastError>> {
+    client.set_reauthenticate(true);
+    let mut events = client.subscribe_session();
+    server.revoke_tokens();
+    let held = tokio::spawn({
+        let client = client.clone();
+        async move { client.get_library().await.map(|_| ()) }
+    });
+    let event = events.recv().await.unwrap();
+    assert!(matches!(event, SessionEvent::Lost(_)));
+    held
+}
+
+#[tokio::test]
+async fn held_requests_go_ahead_after_logging_in_again() {
+    let (server, client) = logged_in().await;
+    let held = lose_session(&server, &client).await;
+
+    client.login(EMAIL, PASSWORD).await.unwrap();
+
+    held.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn held_requests_are_not_released_to_another_account() {
+    let (server, client) = logged_in().await;
+    server.add_account("other@example.com", PASSWORD);
+    let held = lose_session(&server, &client).await;
+
+    client.login("other@example.com", PASSWORD).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!held.is_finished());
+
+    client.abandon_reauthentication();
+    let result = held.await.unwrap();
+    assert!(matches!(result, Err(IBroadcastError::Authentication(_))));
+    assert_eq!(count(&server, "getlibrary"), 1);
+}
+
+#[tokio::test]
+async fn a_refresh_without_a_token_asks_for_a_new_login() {
+    let (server, client) = logged_in().await;
+    client.set_reauthenticate(true);
+    // Close enough to expiring that the next request refreshes first
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    client.restore_session(Session {
+        expires_at: Some(now + 60),
+        ..client.session().unwrap()
+    });
+    let mut events = client.subscribe_session();
+    server.withhold_refreshed_token();
+
+    let held = tokio::spawn({
+        let client = client.clone();
+        async move { client.get_library().await.map(|_| ()) }
+    });
+    let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await;
+    assert!(matches!(event, Ok(Ok(SessionEvent::Lost(_)))));
+
+    client.login(EMAIL, PASSWORD).await.unwrap();
+    held.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn logout_revokes_the_token() {
+    let (server, client) = logged_in().await;
+    let token = client.session().unwrap().token;
+
+    client.logout().await;
+
+    assert!(client.session().is_none());
+    let revoked = server.requests().into_iter().any(|request| {
+        request["mode"] == "logout" && request.get("token") == Some(&token)
+    });
+    assert!(revoked);
+}